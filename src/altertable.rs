@@ -1,27 +1,107 @@
-use crate::MigrationError;
+use crate::ddl::{
+    AlterExtensionOperation, AlterPublicationOperation, ColumnSetting, CreatePublication,
+    CreateStatistics, ExclusionConstraint, PostgresStatement, RelationKind, Storage,
+};
+use crate::{MigrationError, MigrationOptions};
 use sqlparser::ast::table_constraints::{CheckConstraint, ForeignKeyConstraint, UniqueConstraint};
 use sqlparser::ast::{
-    AlterTable, CreateExtension, CreateIndex, CreateTable, CreateView, DropBehavior,
+    AlterIndexOperation, AlterSchema, AlterSchemaOperation, AlterTable, CreateExtension,
+    CreateIndex, CreateTable, CreateView, DropBehavior, DropExtension, Ident, Owner,
+    RenameTableNameKind, ReplicaIdentity, SchemaName,
 };
 use sqlparser::ast::{AlterTableOperation, ObjectName, ObjectNamePart, Statement, TableConstraint};
 use std::fmt::Display;
 
-pub fn from_to_table(f: &CreateTable, t: &CreateTable) -> Result<Vec<Statement>, MigrationError> {
+pub fn from_to_table(f: &CreateTable, t: &CreateTable) -> Result<Vec<Step>, MigrationError> {
+    from_to_table_with_options(f, t, &MigrationOptions::default())
+}
+
+/// Like `from_to_table` with `options` for how the changes are made
+pub fn from_to_table_with_options(
+    f: &CreateTable,
+    t: &CreateTable,
+    options: &MigrationOptions,
+) -> Result<Vec<Step>, MigrationError> {
     if !object_names_equal(&f.name, &t.name) {
         return Err(MigrationError::TablesNotMatching(f.clone(), t.clone()));
     }
 
-    let mut r = Vec::new();
-    let mut column_statements = compare_columns(&f.name, &f.columns, &t.columns)?;
-    let mut constraint_statements = compare_constraints(&f.name, &f.constraints, &t.constraints)?;
+    // Constraints written on columns are read back from Postgres as table constraints
+    let f = &crate::normalize::lift_column_constraints(f);
+    let t = &crate::normalize::lift_column_constraints(t);
+    let t = &crate::normalize::serial_defaults(t);
+    let t = &crate::normalize::primary_key_not_null(f, t);
+
+    let (mut r, f) = rename_constraints(f, t);
+    let mut column_statements = compare_columns(&f.name, &f.columns, &t.columns, options)?;
+    let mut constraint_statements =
+        compare_constraints(&f.name, &f.constraints, &t.constraints, options)?;
 
     r.append(&mut column_statements);
     r.append(&mut constraint_statements);
     Ok(r)
 }
 
-pub fn from_to(froms: Vec<Wrapped>, tos: Vec<Wrapped>) -> Result<Vec<Statement>, MigrationError> {
-    let mut r: Vec<Statement> = Vec::new();
+/// A single statement of a migration plan
+#[derive(Clone, Debug, PartialEq)]
+pub enum Step {
+    Statement(Statement),
+    Postgres(PostgresStatement),
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Step::Statement(s) => s.fmt(f),
+            Step::Postgres(s) => s.fmt(f),
+        }
+    }
+}
+
+impl From<Statement> for Step {
+    fn from(s: Statement) -> Self {
+        Step::Statement(s)
+    }
+}
+
+impl From<PostgresStatement> for Step {
+    fn from(s: PostgresStatement) -> Self {
+        Step::Postgres(s)
+    }
+}
+
+impl PartialEq<Statement> for Step {
+    fn eq(&self, other: &Statement) -> bool {
+        matches!(self, Step::Statement(s) if s == other)
+    }
+}
+
+impl Step {
+    /// The index the step creates, whether or not it's created with storage settings
+    pub fn created_index(&self) -> Option<&CreateIndex> {
+        match self {
+            Step::Statement(Statement::CreateIndex(ci)) => Some(ci),
+            Step::Postgres(PostgresStatement::CreateIndex { index, .. }) => Some(index),
+            _ => None,
+        }
+    }
+}
+
+pub fn from_to(froms: Vec<Wrapped>, tos: Vec<Wrapped>) -> Result<Vec<Step>, MigrationError> {
+    from_to_with_options(froms, tos, &MigrationOptions::default())
+}
+
+/// Like `from_to` but with policies for changes that aren't made by default
+pub fn from_to_with_options(
+    froms: Vec<Wrapped>,
+    tos: Vec<Wrapped>,
+    options: &MigrationOptions,
+) -> Result<Vec<Step>, MigrationError> {
+    let tos = crate::normalize::referred_primary_keys(tos, &froms);
+    let tos = crate::naming::name_objects(tos);
+    let (mut r, froms) = move_tables(froms, &tos, &options.moved_tables);
+    let (mut renames, froms) = rename_objects(froms, &tos);
+    r.append(&mut renames);
     for wrapped_to in &tos {
         if let None = wrapped_to.name() {
             return Err(MigrationError::UnnamedObject(wrapped_to.clone()));
@@ -30,45 +110,120 @@ pub fn from_to(froms: Vec<Wrapped>, tos: Vec<Wrapped>) -> Result<Vec<Statement>,
         match wrapped_to {
             Wrapped::CreateTable(to_table) => {
                 if let Some(Wrapped::CreateTable(from)) = matched_from {
-                    let mut changes = from_to_table(&from, &to_table)?;
+                    let mut changes = from_to_table_with_options(&from, &to_table, options)?;
                     r.append(&mut changes);
                 } else {
-                    r.push(Statement::CreateTable(to_table.clone()));
+                    let mut table = to_table.clone();
+                    let mut settings = Vec::new();
+                    for column in table.columns.iter_mut() {
+                        let (without_settings, mut column_settings) =
+                            split_column_settings(&to_table.name, column);
+                        *column = without_settings;
+                        settings.append(&mut column_settings);
+                    }
+                    r.push(
+                        match new_relation_storage(&tos, RelationKind::Table, &to_table.name) {
+                            Some(storage) => PostgresStatement::CreateTable {
+                                table: Box::new(table),
+                                storage,
+                            }
+                            .into(),
+                            None => Statement::CreateTable(table).into(),
+                        },
+                    );
+                    r.append(&mut settings);
                 }
             }
             Wrapped::CreateView(to_view) => {
                 if let Some(Wrapped::CreateView(from)) = matched_from {
+                    // Only views read back from the server, such as with
+                    // `canonicalize_on_server`, are likely to have the same query
+                    if from.query == to_view.query {
+                        continue;
+                    }
                     let mut replacement_view = to_view.clone();
                     replacement_view.or_replace = true;
-                    r.push(Statement::CreateView(replacement_view));
+                    r.push(Statement::CreateView(replacement_view).into());
                     //let mut changes = from_to_table(&from, &to_table)?;
                     //r.append(&mut changes);
                 } else {
-                    r.push(Statement::CreateView(to_view.clone()));
+                    r.push(Statement::CreateView(to_view.clone()).into());
                 }
             }
             Wrapped::CreateIndex(to_index) => {
                 if let Some(Wrapped::CreateIndex(from)) = matched_from {
-                    if from != to_index {
+                    if !crate::normalize::indexes_equal(from, to_index) {
                         return Err(MigrationError::CannotModifyIndex(
                             from.clone(),
                             to_index.clone(),
                         ));
                     }
-                    eprintln!("TODO: Existing index matched, should check for changes");
                 } else {
-                    r.push(Statement::CreateIndex(to_index.clone()));
+                    let mut to_index = to_index.clone();
+                    to_index.concurrently |= options.concurrent_indexes;
+                    let storage = to_index
+                        .name
+                        .as_ref()
+                        .and_then(|name| new_relation_storage(&tos, RelationKind::Index, name));
+                    r.push(match storage {
+                        Some(storage) => PostgresStatement::CreateIndex {
+                            index: Box::new(to_index),
+                            storage,
+                        }
+                        .into(),
+                        None => Statement::CreateIndex(to_index).into(),
+                    });
                 }
             }
-            Wrapped::CreateExtension { name } => {
-                if let None = matched_from {
-                    r.push(Statement::CreateExtension(CreateExtension {
-                        name: name.to_owned(),
-                        cascade: false,
-                        if_not_exists: false,
-                        schema: None,
-                        version: None,
-                    }))
+            Wrapped::CreateExtension {
+                name,
+                schema,
+                version,
+            } => {
+                if let Some(Wrapped::CreateExtension {
+                    schema: from_schema,
+                    version: from_version,
+                    ..
+                }) = matched_from
+                {
+                    // Only compare the version and schema if the target specifies them
+                    if let Some(to_version) = version
+                        && from_version.as_ref().map(|v| &v.value) != Some(&to_version.value)
+                    {
+                        r.push(
+                            PostgresStatement::AlterExtension {
+                                name: name.to_owned(),
+                                operation: AlterExtensionOperation::UpdateTo(
+                                    to_version.value.clone(),
+                                ),
+                            }
+                            .into(),
+                        )
+                    }
+                    if let Some(to_schema) = schema
+                        && !from_schema
+                            .as_ref()
+                            .is_some_and(|s| s.value.eq_ignore_ascii_case(&to_schema.value))
+                    {
+                        r.push(
+                            PostgresStatement::AlterExtension {
+                                name: name.to_owned(),
+                                operation: AlterExtensionOperation::SetSchema(to_schema.clone()),
+                            }
+                            .into(),
+                        )
+                    }
+                } else {
+                    r.push(
+                        Statement::CreateExtension(CreateExtension {
+                            name: name.to_owned(),
+                            cascade: false,
+                            if_not_exists: false,
+                            schema: schema.to_owned(),
+                            version: version.to_owned(),
+                        })
+                        .into(),
+                    )
                 }
             }
             Wrapped::CreateSchema {
@@ -79,15 +234,130 @@ pub fn from_to(froms: Vec<Wrapped>, tos: Vec<Wrapped>) -> Result<Vec<Statement>,
                 default_collate_spec,
                 clone,
             } => {
-                if let None = matched_from {
-                    r.push(Statement::CreateSchema {
-                        schema_name: schema_name.to_owned(),
-                        if_not_exists: if_not_exists.to_owned(),
-                        with: with.to_owned(),
-                        options: options.to_owned(),
-                        default_collate_spec: default_collate_spec.to_owned(),
-                        clone: clone.to_owned(),
-                    })
+                if let Some(Wrapped::CreateSchema {
+                    schema_name: from_schema_name,
+                    ..
+                }) = matched_from
+                {
+                    // Only compare the owner if the target specifies one
+                    if let (Some(to_owner), Some(name)) =
+                        (schema_owner(schema_name), wrapped_to.name())
+                        && !schema_owner(from_schema_name)
+                            .is_some_and(|o| o.value.eq_ignore_ascii_case(&to_owner.value))
+                    {
+                        r.push(
+                            Statement::AlterSchema(AlterSchema {
+                                name: quote_object_name(&name),
+                                if_exists: false,
+                                operations: vec![AlterSchemaOperation::OwnerTo {
+                                    owner: Owner::Ident(to_owner.clone()),
+                                }],
+                            })
+                            .into(),
+                        )
+                    }
+                } else {
+                    r.push(
+                        Statement::CreateSchema {
+                            schema_name: schema_name.to_owned(),
+                            if_not_exists: if_not_exists.to_owned(),
+                            with: with.to_owned(),
+                            options: options.to_owned(),
+                            default_collate_spec: default_collate_spec.to_owned(),
+                            clone: clone.to_owned(),
+                        }
+                        .into(),
+                    )
+                }
+            }
+            Wrapped::ExclusionConstraint { table, constraint } => {
+                if let Some(Wrapped::ExclusionConstraint {
+                    constraint: from, ..
+                }) = matched_from
+                {
+                    if from != constraint {
+                        return Err(MigrationError::CannotModifyExclusionConstraint(
+                            from.clone(),
+                            constraint.clone(),
+                        ));
+                    }
+                } else {
+                    r.push(
+                        PostgresStatement::AddExclusionConstraint {
+                            table: quote_object_name(table),
+                            constraint: constraint.clone(),
+                        }
+                        .into(),
+                    );
+                }
+            }
+            Wrapped::Storage {
+                kind,
+                relation,
+                storage,
+            } => {
+                let from = match matched_from {
+                    Some(Wrapped::Storage { storage, .. }) => storage.clone(),
+                    // New relations are created with their storage
+                    _ if !relation_exists(&froms, *kind, relation) => continue,
+                    _ => Storage::default(),
+                };
+                r.append(&mut alter_storage(*kind, relation, &from, storage));
+            }
+            Wrapped::Statistics { statistics, target } => match matched_from {
+                Some(Wrapped::Statistics {
+                    statistics: from,
+                    target: from_target,
+                }) if from == statistics => {
+                    // The target is only compared when it's specified
+                    if let Some(target) = target
+                        && from_target != &Some(*target)
+                    {
+                        r.push(
+                            PostgresStatement::AlterStatistics {
+                                name: statistics.name.clone(),
+                                target: *target,
+                            }
+                            .into(),
+                        );
+                    }
+                }
+                Some(Wrapped::Statistics {
+                    statistics: from, ..
+                }) => {
+                    r.push(
+                        PostgresStatement::DropStatistics {
+                            name: from.name.clone(),
+                        }
+                        .into(),
+                    );
+                    r.append(&mut create_statistics(statistics, target));
+                }
+                _ => r.append(&mut create_statistics(statistics, target)),
+            },
+            Wrapped::Publication(publication) => match matched_from {
+                Some(Wrapped::Publication(from)) if from.all_tables == publication.all_tables => {
+                    r.append(&mut alter_publication(from, publication));
+                }
+                // Postgres can't change a publication to or from `FOR ALL TABLES`
+                Some(Wrapped::Publication(from)) => {
+                    r.push(
+                        PostgresStatement::DropPublication {
+                            name: from.name.clone(),
+                        }
+                        .into(),
+                    );
+                    r.push(PostgresStatement::CreatePublication(publication.clone()).into());
+                }
+                _ => r.push(PostgresStatement::CreatePublication(publication.clone()).into()),
+            },
+            Wrapped::ReplicaIdentity { table, identity } => {
+                let from = match matched_from {
+                    Some(Wrapped::ReplicaIdentity { identity, .. }) => identity.clone(),
+                    _ => ReplicaIdentity::Default,
+                };
+                if !replica_identities_equal(&from, identity) {
+                    r.push(alter_replica_identity(table, identity).into());
                 }
             }
         }
@@ -98,50 +368,174 @@ pub fn from_to(froms: Vec<Wrapped>, tos: Vec<Wrapped>) -> Result<Vec<Statement>,
             match from {
                 Wrapped::CreateTable(ct) => {
                     let quoted_name = quote_object_name(&ct.name);
-                    r.push(Statement::Drop {
-                        object_type: sqlparser::ast::ObjectType::Table,
-                        table: None,
-                        if_exists: false,
-                        names: vec![quoted_name],
-                        cascade: true,
-                        purge: false,
-                        restrict: false,
-                        temporary: false,
-                    })
+                    r.push(
+                        Statement::Drop {
+                            object_type: sqlparser::ast::ObjectType::Table,
+                            table: None,
+                            if_exists: false,
+                            names: vec![quoted_name],
+                            cascade: true,
+                            purge: false,
+                            restrict: false,
+                            temporary: false,
+                        }
+                        .into(),
+                    )
                 }
                 Wrapped::CreateView(cv) => {
                     let quoted_name = quote_object_name(&cv.name);
                     println!("drop {quoted_name} {}", cv.name);
-                    r.push(Statement::Drop {
-                        object_type: sqlparser::ast::ObjectType::View,
-                        table: None,
-                        if_exists: false,
-                        names: vec![quoted_name],
-                        cascade: true,
-                        purge: false,
-                        restrict: false,
-                        temporary: false,
-                    })
-                }
-
-                Wrapped::CreateIndex(ci) => {
-                    if let Some(name) = ci.name.clone() {
-                        r.push(Statement::Drop {
-                            object_type: sqlparser::ast::ObjectType::Index,
+                    r.push(
+                        Statement::Drop {
+                            object_type: sqlparser::ast::ObjectType::View,
                             table: None,
                             if_exists: false,
-                            names: vec![name],
+                            names: vec![quoted_name],
                             cascade: true,
                             purge: false,
                             restrict: false,
                             temporary: false,
-                        })
+                        }
+                        .into(),
+                    )
+                }
+
+                Wrapped::CreateIndex(ci) => {
+                    // `DROP INDEX CONCURRENTLY` doesn't support `CASCADE`
+                    if let (true, Some(name)) = (options.concurrent_indexes, ci.name.clone()) {
+                        r.push(PostgresStatement::DropIndexConcurrently { name }.into())
+                    } else if let Some(name) = ci.name.clone() {
+                        r.push(
+                            Statement::Drop {
+                                object_type: sqlparser::ast::ObjectType::Index,
+                                table: None,
+                                if_exists: false,
+                                names: vec![name],
+                                cascade: true,
+                                purge: false,
+                                restrict: false,
+                                temporary: false,
+                            }
+                            .into(),
+                        )
+                    }
+                }
+                // Extensions are only removed when asked to
+                Wrapped::CreateExtension { name, .. } => {
+                    if options.drop_extensions {
+                        r.push(
+                            Statement::DropExtension(DropExtension {
+                                names: vec![name.clone()],
+                                if_exists: false,
+                                cascade_or_restrict: None,
+                            })
+                            .into(),
+                        )
+                    }
+                }
+                // Schemas are only dropped when asked to
+                Wrapped::CreateSchema { .. } => {
+                    if let (true, Some(name)) = (options.drop_schemas, from.name()) {
+                        r.push(
+                            Statement::Drop {
+                                object_type: sqlparser::ast::ObjectType::Schema,
+                                table: None,
+                                if_exists: false,
+                                names: vec![quote_object_name(&name)],
+                                cascade: options.drop_non_empty_schemas,
+                                purge: false,
+                                restrict: !options.drop_non_empty_schemas,
+                                temporary: false,
+                            }
+                            .into(),
+                        )
+                    }
+                }
+                Wrapped::ExclusionConstraint { table, constraint } => {
+                    // Dropping the table will drop its constraints
+                    let table_remains = tos.iter().any(|t| {
+                        matches!(t, Wrapped::CreateTable(ct) if object_names_equal(&ct.name, table))
+                    });
+                    if let (true, Some(name)) = (table_remains, &constraint.name) {
+                        r.push(
+                            Statement::AlterTable(AlterTable {
+                                name: quote_object_name(table),
+                                if_exists: false,
+                                location: None,
+                                only: false,
+                                on_cluster: None,
+                                table_type: None,
+                                operations: vec![AlterTableOperation::DropConstraint {
+                                    if_exists: false,
+                                    drop_behavior: Some(DropBehavior::Cascade),
+                                    name: name.clone(),
+                                }],
+                                end_token: semicolon_token(),
+                            })
+                            .into(),
+                        );
+                    }
+                }
+                // Storage goes back to the defaults, unless the relation is dropped
+                Wrapped::Storage {
+                    kind,
+                    relation,
+                    storage,
+                } => {
+                    let relation_remains = tos.iter().any(|t| match (kind, t) {
+                        (RelationKind::Table, Wrapped::CreateTable(ct)) => {
+                            object_names_equal(&ct.name, relation)
+                        }
+                        (RelationKind::Index, Wrapped::CreateIndex(ci)) => ci
+                            .name
+                            .as_ref()
+                            .is_some_and(|name| object_names_equal(name, relation)),
+                        _ => false,
+                    });
+                    if relation_remains {
+                        r.append(&mut alter_storage(
+                            *kind,
+                            relation,
+                            storage,
+                            &Storage::default(),
+                        ));
+                    }
+                }
+                // Publications are database wide, so they're only dropped when asked to
+                Wrapped::Publication(publication) => {
+                    if options.drop_publications {
+                        r.push(
+                            PostgresStatement::DropPublication {
+                                name: publication.name.clone(),
+                            }
+                            .into(),
+                        );
+                    }
+                }
+                Wrapped::ReplicaIdentity { table, identity } => {
+                    let table_remains = tos.iter().any(|t| {
+                        matches!(t, Wrapped::CreateTable(ct) if object_names_equal(&ct.name, table))
+                    });
+                    if table_remains
+                        && !replica_identities_equal(identity, &ReplicaIdentity::Default)
+                    {
+                        r.push(alter_replica_identity(table, &ReplicaIdentity::Default).into());
+                    }
+                }
+                Wrapped::Statistics { statistics, .. } => {
+                    // Dropping the table will drop its statistics
+                    let table_remains = tos.iter().any(|t| {
+                        matches!(t, Wrapped::CreateTable(ct) if table_name_matches(&ct.name, &statistics.table))
+                    });
+                    if table_remains {
+                        r.push(
+                            PostgresStatement::DropStatistics {
+                                name: statistics.name.clone(),
+                            }
+                            .into(),
+                        );
                     }
                 }
-                // Extensions won't be removed
-                Wrapped::CreateExtension { .. } => (),
-                // Schemas wont be dropped
-                Wrapped::CreateSchema { .. } => (),
             }
         }
     }
@@ -149,7 +543,420 @@ pub fn from_to(froms: Vec<Wrapped>, tos: Vec<Wrapped>) -> Result<Vec<Statement>,
     Ok(r)
 }
 
-fn quote_object_name(name: &ObjectName) -> ObjectName {
+fn alter_publication(from: &CreatePublication, to: &CreatePublication) -> Vec<Step> {
+    let mut r = Vec::new();
+    let mut operations = Vec::new();
+    let added: Vec<ObjectName> = to
+        .tables
+        .iter()
+        .filter(|t| !from.tables.iter().any(|f| table_name_matches(f, t)))
+        .cloned()
+        .collect();
+    if !added.is_empty() {
+        operations.push(AlterPublicationOperation::AddTables(added));
+    }
+    let dropped: Vec<ObjectName> = from
+        .tables
+        .iter()
+        .filter(|f| !to.tables.iter().any(|t| table_name_matches(f, t)))
+        .cloned()
+        .collect();
+    if !dropped.is_empty() {
+        operations.push(AlterPublicationOperation::DropTables(dropped));
+    }
+    let changed = to.changed_parameters(from);
+    if !changed.is_empty() {
+        operations.push(AlterPublicationOperation::Set(changed));
+    }
+    for operation in operations {
+        r.push(
+            PostgresStatement::AlterPublication {
+                name: to.name.clone(),
+                operation,
+            }
+            .into(),
+        );
+    }
+    r
+}
+
+fn replica_identities_equal(a: &ReplicaIdentity, b: &ReplicaIdentity) -> bool {
+    match (a, b) {
+        (ReplicaIdentity::Index(a), ReplicaIdentity::Index(b)) => {
+            crate::normalize::ident(a) == crate::normalize::ident(b)
+        }
+        (a, b) => a == b,
+    }
+}
+
+fn alter_replica_identity(table: &ObjectName, identity: &ReplicaIdentity) -> Statement {
+    Statement::AlterTable(AlterTable {
+        name: table.clone(),
+        if_exists: false,
+        location: None,
+        only: false,
+        on_cluster: None,
+        table_type: None,
+        operations: vec![AlterTableOperation::ReplicaIdentity {
+            identity: identity.clone(),
+        }],
+        end_token: semicolon_token(),
+    })
+}
+
+fn create_statistics(statistics: &CreateStatistics, target: &Option<u64>) -> Vec<Step> {
+    let mut r: Vec<Step> = vec![PostgresStatement::CreateStatistics(statistics.clone()).into()];
+    if let Some(target) = target {
+        r.push(
+            PostgresStatement::AlterStatistics {
+                name: statistics.name.clone(),
+                target: *target,
+            }
+            .into(),
+        );
+    }
+    r
+}
+
+/// The storage `tos` gives the `kind` relation `name`, for creating it
+fn new_relation_storage(tos: &[Wrapped], kind: RelationKind, name: &ObjectName) -> Option<Storage> {
+    tos.iter().find_map(|o| match o {
+        Wrapped::Storage {
+            kind: k,
+            relation,
+            storage,
+        } if *k == kind && object_names_equal(relation, name) => Some(storage.clone()),
+        _ => None,
+    })
+}
+
+/// Whether the `kind` relation `name` is in `froms`
+fn relation_exists(froms: &[Wrapped], kind: RelationKind, name: &ObjectName) -> bool {
+    froms.iter().any(|o| match (o, kind) {
+        (Wrapped::CreateTable(ct), RelationKind::Table) => object_names_equal(&ct.name, name),
+        (Wrapped::CreateIndex(ci), RelationKind::Index) => ci
+            .name
+            .as_ref()
+            .is_some_and(|n| object_names_equal(n, name)),
+        _ => false,
+    })
+}
+
+fn alter_storage(
+    kind: RelationKind,
+    relation: &ObjectName,
+    from: &Storage,
+    to: &Storage,
+) -> Vec<Step> {
+    to.operations(from, kind)
+        .into_iter()
+        .map(|operation| {
+            PostgresStatement::AlterStorage {
+                kind,
+                name: relation.clone(),
+                operation,
+            }
+            .into()
+        })
+        .collect()
+}
+
+/// Tables hinted as moved, that are in `froms` under their old name and `tos` under their new
+/// one, are moved and renamed. The returned objects refer to them by their new names.
+fn move_tables(
+    mut froms: Vec<Wrapped>,
+    tos: &[Wrapped],
+    moves: &[(ObjectName, ObjectName)],
+) -> (Vec<Step>, Vec<Wrapped>) {
+    let mut r: Vec<Step> = Vec::new();
+    for (old_name, new_name) in moves {
+        let is_new = |w: &Wrapped| matches!(w, Wrapped::CreateTable(ct) if object_names_equal(&ct.name, new_name));
+        if froms.iter().any(is_new) || !tos.iter().any(is_new) {
+            continue;
+        }
+        let Some(Wrapped::CreateTable(from)) = froms.iter_mut().find(
+            |w| matches!(w, Wrapped::CreateTable(ct) if table_name_matches(&ct.name, old_name)),
+        ) else {
+            continue;
+        };
+        let (
+            Some(ObjectNamePart::Identifier(old_table)),
+            Some(ObjectNamePart::Identifier(new_table)),
+        ) = (from.name.0.last().cloned(), new_name.0.last())
+        else {
+            continue;
+        };
+
+        let mut current = from.name.clone();
+        if let [.., ObjectNamePart::Identifier(new_schema), _] = new_name.0.as_slice() {
+            let old_schema = match old_name.0.as_slice() {
+                [.., ObjectNamePart::Identifier(old_schema), _] => Some(old_schema),
+                _ => None,
+            };
+            if !old_schema.is_some_and(|s| s.value.eq_ignore_ascii_case(&new_schema.value)) {
+                r.push(
+                    PostgresStatement::AlterTableSetSchema {
+                        table: quote_object_name(&current),
+                        schema: new_schema.clone(),
+                    }
+                    .into(),
+                );
+                current = ObjectName::from(vec![new_schema.clone(), old_table.clone()]);
+            }
+        }
+        if !old_table.value.eq_ignore_ascii_case(&new_table.value) {
+            r.push(
+                Statement::AlterTable(AlterTable {
+                    name: quote_object_name(&current),
+                    if_exists: false,
+                    location: None,
+                    only: false,
+                    on_cluster: None,
+                    table_type: None,
+                    operations: vec![AlterTableOperation::RenameTable {
+                        table_name: RenameTableNameKind::To(ObjectName::from(vec![
+                            new_table.clone(),
+                        ])),
+                    }],
+                    end_token: semicolon_token(),
+                })
+                .into(),
+            );
+        }
+        let moved_from = from.name.clone();
+        from.name = new_name.clone();
+
+        // Indexes, constraints and references move with the table
+        for w in froms.iter_mut() {
+            match w {
+                Wrapped::CreateIndex(ci) if table_name_matches(&ci.table_name, &moved_from) => {
+                    ci.table_name = new_name.clone();
+                }
+                Wrapped::ExclusionConstraint { table, .. }
+                | Wrapped::ReplicaIdentity { table, .. }
+                | Wrapped::Storage {
+                    kind: RelationKind::Table,
+                    relation: table,
+                    ..
+                } if table_name_matches(table, &moved_from) => {
+                    *table = new_name.clone();
+                }
+                Wrapped::Publication(publication) => {
+                    for table in publication.tables.iter_mut() {
+                        if table_name_matches(table, &moved_from) {
+                            *table = new_name.clone();
+                        }
+                    }
+                }
+                Wrapped::CreateTable(ct) => {
+                    for c in ct.constraints.iter_mut() {
+                        if let TableConstraint::ForeignKey(fk) = c
+                            && table_name_matches(&fk.foreign_table, &moved_from)
+                        {
+                            fk.foreign_table = new_name.clone();
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    (r, froms)
+}
+
+/// Names read from the migrated schema aren't always qualified, so an unqualified name matches a
+/// qualified one by its last part
+fn table_name_matches(a: &ObjectName, b: &ObjectName) -> bool {
+    if object_names_equal(a, b) {
+        return true;
+    }
+    match (a.0.as_slice(), b.0.as_slice()) {
+        ([ObjectNamePart::Identifier(a)], [.., ObjectNamePart::Identifier(b)])
+        | ([.., ObjectNamePart::Identifier(a)], [ObjectNamePart::Identifier(b)]) => {
+            a.value.eq_ignore_ascii_case(&b.value)
+        }
+        _ => false,
+    }
+}
+
+/// Indexes and exclusion constraints that are only missing because their name changed are
+/// renamed rather than rebuilt. The returned objects have the new names.
+fn rename_objects(mut froms: Vec<Wrapped>, tos: &[Wrapped]) -> (Vec<Step>, Vec<Wrapped>) {
+    let mut r: Vec<Step> = Vec::new();
+    for to in tos {
+        if froms.iter().any(|f| f.name_and_type_equals(to)) {
+            continue;
+        }
+        let renamed = froms.iter().position(|from| {
+            if tos.iter().any(|t| t.name_and_type_equals(from)) {
+                return false;
+            }
+            match (from, to) {
+                (Wrapped::CreateIndex(from), Wrapped::CreateIndex(to)) => {
+                    let mut from = from.clone();
+                    from.name = to.name.clone();
+                    crate::normalize::indexes_equal(&from, to)
+                }
+                (
+                    Wrapped::ExclusionConstraint {
+                        table: from_table,
+                        constraint: from,
+                    },
+                    Wrapped::ExclusionConstraint { table, constraint },
+                ) => {
+                    let mut from = from.clone();
+                    from.name = constraint.name.clone();
+                    object_names_equal(from_table, table) && &from == constraint
+                }
+                _ => false,
+            }
+        });
+        let Some(i) = renamed else {
+            continue;
+        };
+
+        match (&mut froms[i], to) {
+            (Wrapped::CreateIndex(from), Wrapped::CreateIndex(to)) => {
+                if let (Some(from_name), Some(to_name)) = (&from.name, &to.name)
+                    && let Some(ObjectNamePart::Identifier(new_name)) = to_name.0.last()
+                {
+                    r.push(
+                        Statement::AlterIndex {
+                            name: from_name.clone(),
+                            operation: AlterIndexOperation::RenameIndex {
+                                index_name: ObjectName::from(vec![new_name.clone()]),
+                            },
+                        }
+                        .into(),
+                    );
+                    let old_name = from_name.clone();
+                    from.name = to.name.clone();
+                    for w in froms.iter_mut() {
+                        if let Wrapped::Storage {
+                            kind: RelationKind::Index,
+                            relation,
+                            ..
+                        } = w
+                            && object_names_equal(relation, &old_name)
+                        {
+                            *relation = to_name.clone();
+                        }
+                    }
+                }
+            }
+            (
+                Wrapped::ExclusionConstraint {
+                    table,
+                    constraint: from,
+                },
+                Wrapped::ExclusionConstraint { constraint, .. },
+            ) => {
+                if let (Some(old_name), Some(new_name)) = (&from.name, &constraint.name) {
+                    r.push(rename_constraint(table, old_name, new_name));
+                    from.name = constraint.name.clone();
+                }
+            }
+            _ => (),
+        }
+    }
+    (r, froms)
+}
+
+/// Constraints that are only missing because their name changed are renamed rather than dropped
+/// and added again. The returned table has the new names.
+fn rename_constraints(f: &CreateTable, t: &CreateTable) -> (Vec<Step>, CreateTable) {
+    let mut r = Vec::new();
+    let mut f = f.clone();
+    let same_kind = |a: &TableConstraint, b: &TableConstraint| {
+        std::mem::discriminant(a) == std::mem::discriminant(b)
+    };
+    let named = |c: &TableConstraint, name: &Option<Ident>| {
+        name.is_some() && constraint_name(c) == name.as_ref()
+    };
+
+    for t_constraint in &t.constraints {
+        // Primary keys are matched without their names
+        if matches!(t_constraint, TableConstraint::PrimaryKey(_)) {
+            continue;
+        }
+        let new_name = constraint_name(t_constraint).cloned();
+        if f.constraints
+            .iter()
+            .any(|fc| same_kind(fc, t_constraint) && named(fc, &new_name))
+        {
+            continue;
+        }
+        let renamed = f.constraints.iter().position(|fc| {
+            let old_name = constraint_name(fc).cloned();
+            same_kind(fc, t_constraint)
+                && old_name.is_some()
+                && !t
+                    .constraints
+                    .iter()
+                    .any(|tc| same_kind(tc, fc) && named(tc, &old_name))
+                && crate::normalize::constraints_equal(
+                    &with_constraint_name(fc, new_name.clone()),
+                    t_constraint,
+                )
+        });
+        if let (Some(i), Some(new_name)) = (renamed, &new_name)
+            && let Some(old_name) = constraint_name(&f.constraints[i]).cloned()
+        {
+            r.push(rename_constraint(&f.name, &old_name, new_name));
+            f.constraints[i] = with_constraint_name(&f.constraints[i], Some(new_name.clone()));
+        }
+    }
+    (r, f)
+}
+
+fn rename_constraint(table_name: &ObjectName, old_name: &Ident, new_name: &Ident) -> Step {
+    Statement::AlterTable(AlterTable {
+        name: quote_object_name(table_name),
+        if_exists: false,
+        location: None,
+        only: false,
+        on_cluster: None,
+        table_type: None,
+        operations: vec![AlterTableOperation::RenameConstraint {
+            old_name: old_name.clone(),
+            new_name: new_name.clone(),
+        }],
+        end_token: semicolon_token(),
+    })
+    .into()
+}
+
+fn constraint_name(c: &TableConstraint) -> Option<&Ident> {
+    match c {
+        TableConstraint::PrimaryKey(pk) => pk.name.as_ref(),
+        TableConstraint::Unique(unique) => unique.name.as_ref(),
+        TableConstraint::ForeignKey(fk) => fk.name.as_ref(),
+        TableConstraint::Check(check) => check.name.as_ref(),
+        _ => None,
+    }
+}
+
+fn with_constraint_name(c: &TableConstraint, name: Option<Ident>) -> TableConstraint {
+    let mut c = c.clone();
+    match &mut c {
+        TableConstraint::PrimaryKey(pk) => pk.name = name,
+        TableConstraint::Unique(unique) => unique.name = name,
+        TableConstraint::ForeignKey(fk) => fk.name = name,
+        TableConstraint::Check(check) => check.name = name,
+        _ => (),
+    }
+    c
+}
+
+fn schema_owner(schema_name: &SchemaName) -> Option<&Ident> {
+    match schema_name {
+        SchemaName::Simple(_) => None,
+        SchemaName::UnnamedAuthorization(owner) => Some(owner),
+        SchemaName::NamedAuthorization(_, owner) => Some(owner),
+    }
+}
+
+pub(crate) fn quote_object_name(name: &ObjectName) -> ObjectName {
     use sqlparser::ast::{Ident, ObjectName, ObjectNamePart};
     use sqlparser::tokenizer::Span;
     ObjectName(
@@ -167,7 +974,7 @@ fn quote_object_name(name: &ObjectName) -> ObjectName {
     )
 }
 
-fn object_names_equal(a: &ObjectName, b: &ObjectName) -> bool {
+pub(crate) fn object_names_equal(a: &ObjectName, b: &ObjectName) -> bool {
     if a.0.len() != b.0.len() {
         return false;
     }
@@ -186,7 +993,8 @@ fn compare_columns(
     table_name: &ObjectName,
     f: &Vec<sqlparser::ast::ColumnDef>,
     t: &Vec<sqlparser::ast::ColumnDef>,
-) -> Result<Vec<Statement>, MigrationError> {
+    options: &MigrationOptions,
+) -> Result<Vec<Step>, MigrationError> {
     let mut r = Vec::new();
     for f_column in f.clone() {
         eprintln!("find column {}", f_column);
@@ -194,7 +1002,7 @@ fn compare_columns(
         if let Some(t_column) = maybe_t_column {
             eprintln!("matching column {}", t_column)
         } else {
-            r.push(Statement::AlterTable(AlterTable {
+            r.push(Step::from(Statement::AlterTable(AlterTable {
                 name: table_name.clone(),
                 if_exists: false,
                 location: None,
@@ -208,7 +1016,7 @@ fn compare_columns(
                     drop_behavior: Some(DropBehavior::Cascade),
                 }],
                 end_token: semicolon_token(),
-            }));
+            })));
         }
     }
     for t_column in t {
@@ -216,10 +1024,11 @@ fn compare_columns(
         let maybe_f_column = f.iter().find(|fi| fi.name == t_column.name);
         if let Some(f_column) = maybe_f_column {
             eprintln!("matching column {}", f_column);
-            let mut column_statements = compare_column(&table_name, &f_column, &t_column)?;
+            let mut column_statements = compare_column(&table_name, &f_column, &t_column, options)?;
             r.append(&mut column_statements);
         } else {
-            r.push(Statement::AlterTable(AlterTable {
+            let (column_def, mut settings) = split_column_settings(table_name, t_column);
+            r.push(Step::from(Statement::AlterTable(AlterTable {
                 name: table_name.clone(),
                 if_exists: false,
                 location: None,
@@ -229,11 +1038,12 @@ fn compare_columns(
                 operations: vec![AlterTableOperation::AddColumn {
                     column_keyword: true,
                     if_not_exists: false,
-                    column_def: t_column.to_owned(),
+                    column_def,
                     column_position: None,
                 }],
                 end_token: semicolon_token(),
-            }));
+            })));
+            r.append(&mut settings);
         }
     }
     Ok(r)
@@ -242,7 +1052,8 @@ fn compare_column(
     table_name: &ObjectName,
     f: &sqlparser::ast::ColumnDef,
     t: &sqlparser::ast::ColumnDef,
-) -> Result<Vec<Statement>, MigrationError> {
+    options: &MigrationOptions,
+) -> Result<Vec<Step>, MigrationError> {
     let mut r = Vec::new();
     for to_opt in &t.options {
         match &to_opt.option {
@@ -252,19 +1063,7 @@ fn compare_column(
                     .iter()
                     .find(|f_opt| matches!(f_opt.option, sqlparser::ast::ColumnOption::NotNull));
                 if let None = from_not_null {
-                    r.push(Statement::AlterTable(AlterTable {
-                        name: table_name.clone(),
-                        if_exists: false,
-                        location: None,
-                        only: false,
-                        on_cluster: None,
-                        table_type: None,
-                        operations: vec![AlterTableOperation::AlterColumn {
-                            column_name: t.name.clone(),
-                            op: sqlparser::ast::AlterColumnOperation::SetNotNull,
-                        }],
-                        end_token: semicolon_token(),
-                    }));
+                    r.append(&mut set_not_null(table_name, &t.name, options));
                 }
             }
             sqlparser::ast::ColumnOption::Default(expr) => {
@@ -290,17 +1089,40 @@ fn compare_column(
                 });
                 match from_default {
                     // There is no default previously, alter the table
-                    None => r.push(alter),
+                    None => r.push(alter.into()),
                     Some(f_opt) => {
                         let to_opt_option = &to_opt.option;
                         // If the from and to options are different, alter the table
-                        if f_opt.option != to_opt_option.clone() {
-                            r.push(alter)
+                        if let (
+                            sqlparser::ast::ColumnOption::Default(f_expr),
+                            sqlparser::ast::ColumnOption::Default(to_expr),
+                        ) = (&f_opt.option, to_opt_option)
+                            && crate::normalize::expr(f_expr) != crate::normalize::expr(to_expr)
+                        {
+                            r.push(alter.into())
                         }
                     }
                 }
                 if let None = from_default {}
             }
+            sqlparser::ast::ColumnOption::DialectSpecific(_) => {
+                let Some(to_setting) = ColumnSetting::from_option(&to_opt.option) else {
+                    eprintln!("Column Option not supported yet {:?}", to_opt.option);
+                    continue;
+                };
+                let from_setting = f
+                    .options
+                    .iter()
+                    .filter_map(|f_opt| ColumnSetting::from_option(&f_opt.option))
+                    .find(|f_setting| f_setting.same_kind(&to_setting));
+                if from_setting.as_ref() != Some(&to_setting) {
+                    r.push(Step::Postgres(PostgresStatement::AlterColumnSet {
+                        table: table_name.clone(),
+                        column: t.name.clone(),
+                        setting: to_setting,
+                    }));
+                }
+            }
 
             x => eprintln!("Column Option not supported yet {:?}", x),
         }
@@ -313,7 +1135,7 @@ fn compare_column(
                     .iter()
                     .find(|to_opt| matches!(to_opt.option, sqlparser::ast::ColumnOption::NotNull));
                 if let None = to_not_null {
-                    r.push(Statement::AlterTable(AlterTable {
+                    r.push(Step::from(Statement::AlterTable(AlterTable {
                         name: table_name.clone(),
                         if_exists: false,
                         location: None,
@@ -325,7 +1147,7 @@ fn compare_column(
                             op: sqlparser::ast::AlterColumnOperation::DropNotNull,
                         }],
                         end_token: semicolon_token(),
-                    }));
+                    })));
                 }
             }
 
@@ -334,7 +1156,7 @@ fn compare_column(
                     matches!(to_opt.option, sqlparser::ast::ColumnOption::Default(_))
                 });
                 if let None = to_default {
-                    r.push(Statement::AlterTable(AlterTable {
+                    r.push(Step::from(Statement::AlterTable(AlterTable {
                         name: table_name.clone(),
                         if_exists: false,
                         location: None,
@@ -346,9 +1168,11 @@ fn compare_column(
                             op: sqlparser::ast::AlterColumnOperation::DropDefault,
                         }],
                         end_token: semicolon_token(),
-                    }));
+                    })));
                 }
             }
+            // Column settings are only compared when they're specified
+            sqlparser::ast::ColumnOption::DialectSpecific(_) => (),
 
             x => eprintln!("Column Option not supported yet {:?}", x),
         }
@@ -356,11 +1180,37 @@ fn compare_column(
     Ok(r)
 }
 
+/// Remove the settings from `column`, returning them as statements to run once the column
+/// exists. Older versions of Postgres don't support them in `CREATE TABLE`.
+fn split_column_settings(
+    table_name: &ObjectName,
+    column: &sqlparser::ast::ColumnDef,
+) -> (sqlparser::ast::ColumnDef, Vec<Step>) {
+    let mut column = column.clone();
+    let mut r = Vec::new();
+    let column_name = column.name.clone();
+    column
+        .options
+        .retain(|o| match ColumnSetting::from_option(&o.option) {
+            Some(setting) => {
+                r.push(Step::Postgres(PostgresStatement::AlterColumnSet {
+                    table: table_name.clone(),
+                    column: column_name.clone(),
+                    setting,
+                }));
+                false
+            }
+            None => true,
+        });
+    (column, r)
+}
+
 fn compare_constraints(
     table_name: &ObjectName,
     f: &Vec<sqlparser::ast::TableConstraint>,
     t: &Vec<sqlparser::ast::TableConstraint>,
-) -> Result<Vec<Statement>, MigrationError> {
+    options: &MigrationOptions,
+) -> Result<Vec<Step>, MigrationError> {
     let mut r = Vec::new();
 
     let maybe_f_pk = f
@@ -374,7 +1224,7 @@ fn compare_constraints(
                 } else {
                     eprintln!("Needs pk");
 
-                    r.push(Statement::AlterTable(AlterTable {
+                    r.push(Step::from(Statement::AlterTable(AlterTable {
                         name: table_name.clone(),
                         if_exists: false,
                         location: None,
@@ -386,7 +1236,7 @@ fn compare_constraints(
                             not_valid: false,
                         }],
                         end_token: semicolon_token(),
-                    }));
+                    })));
                 }
             }
             TableConstraint::ForeignKey(ForeignKeyConstraint { name, .. }) => {
@@ -398,27 +1248,16 @@ fn compare_constraints(
                         false
                     }
                 });
-                if let Some(fk) = maybe_fk {
-                    if fk != &t_constraint {
-                        return Err(MigrationError::CannotModifyTableConstraint(
-                            fk.clone(),
-                            t_constraint.clone(),
-                        ));
+                if let Some(TableConstraint::ForeignKey(fk)) = maybe_fk {
+                    if !crate::normalize::constraints_equal(
+                        &TableConstraint::ForeignKey(fk.clone()),
+                        &t_constraint,
+                    ) && let TableConstraint::ForeignKey(to_fk) = &t_constraint
+                    {
+                        r.append(&mut alter_foreign_key(table_name, fk, to_fk)?);
                     }
                 } else {
-                    r.push(Statement::AlterTable(AlterTable {
-                        name: table_name.clone(),
-                        if_exists: false,
-                        location: None,
-                        only: false,
-                        on_cluster: None,
-                        table_type: None,
-                        operations: vec![AlterTableOperation::AddConstraint {
-                            constraint: t_constraint.to_owned(),
-                            not_valid: false,
-                        }],
-                        end_token: semicolon_token(),
-                    }));
+                    r.append(&mut add_constraint(table_name, &t_constraint, options));
                 }
             }
             TableConstraint::Unique(UniqueConstraint { name, .. }) => {
@@ -433,7 +1272,7 @@ fn compare_constraints(
                 if let Some(_uniq) = maybe_uniq {
                     eprintln!("Has Unique already TODO: Check equal")
                 } else {
-                    r.push(Statement::AlterTable(AlterTable {
+                    r.push(Step::from(Statement::AlterTable(AlterTable {
                         name: table_name.clone(),
                         if_exists: false,
                         location: None,
@@ -445,7 +1284,7 @@ fn compare_constraints(
                             not_valid: false,
                         }],
                         end_token: semicolon_token(),
-                    }));
+                    })));
                 }
             }
             TableConstraint::Check(CheckConstraint { name, .. }) => {
@@ -458,26 +1297,14 @@ fn compare_constraints(
                     }
                 });
                 if let Some(fk) = maybe_check {
-                    if fk != &t_constraint {
+                    if !crate::normalize::constraints_equal(fk, &t_constraint) {
                         return Err(MigrationError::CannotModifyTableConstraint(
                             fk.clone(),
                             t_constraint.clone(),
                         ));
                     }
                 } else {
-                    r.push(Statement::AlterTable(AlterTable {
-                        name: table_name.clone(),
-                        if_exists: false,
-                        location: None,
-                        only: false,
-                        on_cluster: None,
-                        table_type: None,
-                        operations: vec![AlterTableOperation::AddConstraint {
-                            constraint: t_constraint.to_owned(),
-                            not_valid: false,
-                        }],
-                        end_token: semicolon_token(),
-                    }));
+                    r.append(&mut add_constraint(table_name, &t_constraint, options));
                 }
             }
             x => eprintln!("Constraints not supported {:?}", x),
@@ -496,7 +1323,7 @@ fn compare_constraints(
                 });
                 if let None = maybe_fk {
                     let quoted_name = quote_object_name(&table_name);
-                    r.push(Statement::AlterTable(AlterTable {
+                    r.push(Step::from(Statement::AlterTable(AlterTable {
                         name: quoted_name,
                         if_exists: false,
                         location: None,
@@ -509,7 +1336,7 @@ fn compare_constraints(
                             name: name.clone().unwrap(),
                         }],
                         end_token: semicolon_token(),
-                    }));
+                    })));
                 }
             }
             TableConstraint::Unique(UniqueConstraint { name, .. }) => {
@@ -523,7 +1350,7 @@ fn compare_constraints(
                 });
                 if let None = maybe_uniq {
                     let quoted_name = quote_object_name(&table_name);
-                    r.push(Statement::AlterTable(AlterTable {
+                    r.push(Step::from(Statement::AlterTable(AlterTable {
                         name: quoted_name,
                         if_exists: false,
                         location: None,
@@ -536,7 +1363,7 @@ fn compare_constraints(
                             name: name.clone().unwrap(),
                         }],
                         end_token: semicolon_token(),
-                    }));
+                    })));
                 }
             }
             TableConstraint::Check(CheckConstraint { name, .. }) => {
@@ -550,7 +1377,7 @@ fn compare_constraints(
                 });
                 if let None = maybe_check {
                     let quoted_name = quote_object_name(&table_name);
-                    r.push(Statement::AlterTable(AlterTable {
+                    r.push(Step::from(Statement::AlterTable(AlterTable {
                         name: quoted_name,
                         if_exists: false,
                         location: None,
@@ -563,7 +1390,7 @@ fn compare_constraints(
                             name: name.clone().unwrap(),
                         }],
                         end_token: semicolon_token(),
-                    }));
+                    })));
                 }
             }
             TableConstraint::PrimaryKey { .. } => {}
@@ -573,6 +1400,161 @@ fn compare_constraints(
     Ok(r)
 }
 
+/// Postgres can only change whether a foreign key is deferrable in place. Changes to its
+/// referential actions replace the constraint, adding it `NOT VALID` and validating it
+/// separately so the table isn't locked against writes while existing rows are checked.
+fn alter_foreign_key(
+    table_name: &ObjectName,
+    from: &ForeignKeyConstraint,
+    to: &ForeignKeyConstraint,
+) -> Result<Vec<Step>, MigrationError> {
+    let fk_equal = |a: ForeignKeyConstraint, b: &ForeignKeyConstraint| {
+        crate::normalize::constraints_equal(
+            &TableConstraint::ForeignKey(a),
+            &TableConstraint::ForeignKey(b.clone()),
+        )
+    };
+    let name = to.name.clone().expect("Named by naming::name_objects");
+
+    let mut deferrable_changed = from.clone();
+    deferrable_changed.characteristics = to.characteristics;
+    if fk_equal(deferrable_changed.clone(), to) {
+        let mut characteristics = crate::normalize::characteristics(&to.characteristics);
+        characteristics.enforced = None;
+        return Ok(vec![
+            PostgresStatement::AlterConstraint {
+                table: table_name.clone(),
+                name,
+                characteristics,
+            }
+            .into(),
+        ]);
+    }
+
+    let mut actions_changed = deferrable_changed;
+    actions_changed.on_delete = to.on_delete;
+    actions_changed.on_update = to.on_update;
+    actions_changed.match_kind = to.match_kind;
+    if !fk_equal(actions_changed, to) {
+        return Err(MigrationError::CannotModifyTableConstraint(
+            TableConstraint::ForeignKey(from.clone()),
+            TableConstraint::ForeignKey(to.clone()),
+        ));
+    }
+
+    let alter_table = |operation| {
+        Statement::AlterTable(AlterTable {
+            name: table_name.clone(),
+            if_exists: false,
+            location: None,
+            only: false,
+            on_cluster: None,
+            table_type: None,
+            operations: vec![operation],
+            end_token: semicolon_token(),
+        })
+        .into()
+    };
+    Ok(vec![
+        alter_table(AlterTableOperation::DropConstraint {
+            if_exists: false,
+            drop_behavior: None,
+            name: name.clone(),
+        }),
+        alter_table(AlterTableOperation::AddConstraint {
+            constraint: TableConstraint::ForeignKey(to.clone()),
+            not_valid: true,
+        }),
+        alter_table(AlterTableOperation::ValidateConstraint { name }),
+    ])
+}
+
+/// Add a foreign key or `CHECK` constraint to an existing table. Online, it's added `NOT VALID`
+/// and validated by a separate statement, which checks the rows without blocking writes.
+fn add_constraint(
+    table_name: &ObjectName,
+    constraint: &TableConstraint,
+    options: &MigrationOptions,
+) -> Vec<Step> {
+    let alter_table = |operation| {
+        Statement::AlterTable(AlterTable {
+            name: table_name.clone(),
+            if_exists: false,
+            location: None,
+            only: false,
+            on_cluster: None,
+            table_type: None,
+            operations: vec![operation],
+            end_token: semicolon_token(),
+        })
+        .into()
+    };
+    let name = match constraint {
+        TableConstraint::ForeignKey(ForeignKeyConstraint { name, .. })
+        | TableConstraint::Check(CheckConstraint { name, .. }) => name.clone(),
+        _ => None,
+    };
+    match (options.online, name) {
+        (true, Some(name)) => vec![
+            alter_table(AlterTableOperation::AddConstraint {
+                constraint: constraint.clone(),
+                not_valid: true,
+            }),
+            alter_table(AlterTableOperation::ValidateConstraint { name }),
+        ],
+        _ => vec![alter_table(AlterTableOperation::AddConstraint {
+            constraint: constraint.clone(),
+            not_valid: false,
+        })],
+    }
+}
+
+/// `SET NOT NULL` on an existing column. Online, the column is first proven not null with a
+/// `CHECK` constraint added `NOT VALID` and validated without blocking writes, which lets Postgres
+/// skip the scan while `SET NOT NULL` holds its exclusive lock.
+fn set_not_null(table_name: &ObjectName, column: &Ident, options: &MigrationOptions) -> Vec<Step> {
+    let alter_table = |operation| {
+        Statement::AlterTable(AlterTable {
+            name: table_name.clone(),
+            if_exists: false,
+            location: None,
+            only: false,
+            on_cluster: None,
+            table_type: None,
+            operations: vec![operation],
+            end_token: semicolon_token(),
+        })
+        .into()
+    };
+    let set_not_null = alter_table(AlterTableOperation::AlterColumn {
+        column_name: column.clone(),
+        op: sqlparser::ast::AlterColumnOperation::SetNotNull,
+    });
+    if !options.online {
+        return vec![set_not_null];
+    }
+    let name = crate::naming::not_null_check_name(table_name, column);
+    vec![
+        alter_table(AlterTableOperation::AddConstraint {
+            constraint: TableConstraint::Check(CheckConstraint {
+                name: Some(name.clone()),
+                expr: Box::new(sqlparser::ast::Expr::IsNotNull(Box::new(
+                    sqlparser::ast::Expr::Identifier(column.clone()),
+                ))),
+                enforced: None,
+            }),
+            not_valid: true,
+        }),
+        alter_table(AlterTableOperation::ValidateConstraint { name: name.clone() }),
+        set_not_null,
+        alter_table(AlterTableOperation::DropConstraint {
+            if_exists: false,
+            drop_behavior: None,
+            name,
+        }),
+    ]
+}
+
 #[derive(Clone, Debug)]
 pub enum Wrapped {
     CreateTable(CreateTable),
@@ -580,6 +1562,8 @@ pub enum Wrapped {
     CreateView(CreateView),
     CreateExtension {
         name: sqlparser::ast::Ident,
+        schema: Option<sqlparser::ast::Ident>,
+        version: Option<sqlparser::ast::Ident>,
     },
     CreateSchema {
         schema_name: sqlparser::ast::SchemaName,
@@ -589,6 +1573,27 @@ pub enum Wrapped {
         default_collate_spec: Option<sqlparser::ast::Expr>,
         clone: Option<ObjectName>,
     },
+    ExclusionConstraint {
+        table: ObjectName,
+        constraint: ExclusionConstraint,
+    },
+    /// How a table or index is stored, which sqlparser doesn't keep on the table or index
+    Storage {
+        kind: RelationKind,
+        relation: ObjectName,
+        storage: Storage,
+    },
+    /// Extended statistics, with the target set by `ALTER STATISTICS`
+    Statistics {
+        statistics: CreateStatistics,
+        target: Option<u64>,
+    },
+    Publication(CreatePublication),
+    /// `ALTER TABLE <table> REPLICA IDENTITY ...`, tables without one use `DEFAULT`
+    ReplicaIdentity {
+        table: ObjectName,
+        identity: ReplicaIdentity,
+    },
 }
 
 impl Display for Wrapped {
@@ -599,16 +1604,18 @@ impl Display for Wrapped {
             Wrapped::CreateIndex(wci) => {
                 sqlparser::ast::Statement::CreateIndex(wci.to_owned()).fmt(f)
             }
-            Wrapped::CreateExtension { name } => {
-                sqlparser::ast::Statement::CreateExtension(CreateExtension {
-                    name: name.to_owned(),
-                    if_not_exists: false,
-                    cascade: false,
-                    schema: None,
-                    version: None,
-                })
-                .fmt(f)
-            }
+            Wrapped::CreateExtension {
+                name,
+                schema,
+                version,
+            } => sqlparser::ast::Statement::CreateExtension(CreateExtension {
+                name: name.to_owned(),
+                if_not_exists: false,
+                cascade: false,
+                schema: schema.to_owned(),
+                version: version.to_owned(),
+            })
+            .fmt(f),
             Wrapped::CreateSchema {
                 schema_name,
                 if_not_exists,
@@ -625,6 +1632,36 @@ impl Display for Wrapped {
                 clone: clone.to_owned(),
             }
             .fmt(f),
+            Wrapped::ExclusionConstraint { table, constraint } => {
+                PostgresStatement::AddExclusionConstraint {
+                    table: table.to_owned(),
+                    constraint: constraint.to_owned(),
+                }
+                .fmt(f)
+            }
+            Wrapped::Storage {
+                kind,
+                relation,
+                storage,
+            } => {
+                let statements: Vec<String> =
+                    alter_storage(*kind, relation, &Storage::default(), storage)
+                        .iter()
+                        .map(|s| s.to_string())
+                        .collect();
+                write!(f, "{}", statements.join(";\n"))
+            }
+            Wrapped::Statistics { statistics, target } => {
+                let statements: Vec<String> = create_statistics(statistics, target)
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect();
+                write!(f, "{}", statements.join(";\n"))
+            }
+            Wrapped::Publication(publication) => publication.fmt(f),
+            Wrapped::ReplicaIdentity { table, identity } => {
+                alter_replica_identity(table, identity).fmt(f)
+            }
         }
     }
 }
@@ -655,34 +1692,80 @@ impl Wrapped {
                     }
                 }
             }
-            Self::CreateExtension { name } => {
+            Self::CreateExtension { name, .. } => {
                 let name1 = name;
-                if let Self::CreateExtension { name } = other {
+                if let Self::CreateExtension { name, .. } = other {
                     return name1 == name;
                 }
             }
-            Self::CreateSchema { schema_name, .. } => {
-                let name1 = schema_name;
-                if let Self::CreateSchema { schema_name, .. } = other {
-                    return name1 == schema_name;
+            Self::CreateSchema { .. } => {
+                if let Self::CreateSchema { .. } = other {
+                    return matches!((self.name(), other.name()), (Some(a), Some(b)) if object_names_equal(&a, &b));
+                }
+            }
+            Self::Storage { kind, relation, .. } => {
+                let (kind1, relation1) = (kind, relation);
+                if let Self::Storage { kind, relation, .. } = other {
+                    return kind1 == kind && object_names_equal(relation1, relation);
+                }
+            }
+            Self::Publication(_) => {
+                if let Self::Publication(_) = other {
+                    return matches!((self.name(), other.name()), (Some(a), Some(b)) if object_names_equal(&a, &b));
+                }
+            }
+            Self::ReplicaIdentity { table, .. } => {
+                let table1 = table;
+                if let Self::ReplicaIdentity { table, .. } = other {
+                    return object_names_equal(table1, table);
+                }
+            }
+            Self::Statistics { .. } => {
+                if let Self::Statistics { .. } = other {
+                    return matches!((self.name(), other.name()), (Some(a), Some(b)) if object_names_equal(&a, &b));
+                }
+            }
+            Self::ExclusionConstraint { table, .. } => {
+                let table1 = table;
+                if let Self::ExclusionConstraint { table, .. } = other {
+                    return object_names_equal(table1, table)
+                        && matches!((self.name(), other.name()), (Some(a), Some(b)) if object_names_equal(&a, &b));
                 }
             }
         }
         return false;
     }
 
-    fn name(&self) -> Option<ObjectName> {
+    pub(crate) fn name(&self) -> Option<ObjectName> {
         match self {
             Wrapped::CreateTable(wct) => Some(wct.name.clone()),
             Wrapped::CreateView(wcv) => Some(wcv.name.clone()),
             Wrapped::CreateIndex(wci) => wci.name.clone(),
-            Wrapped::CreateExtension { name } => {
+            Wrapped::CreateExtension { name, .. } => {
                 Some(ObjectName(vec![ObjectNamePart::Identifier(name.clone())]))
             }
             Wrapped::CreateSchema { schema_name, .. } => match schema_name {
                 sqlparser::ast::SchemaName::Simple(obj_name) => Some(obj_name.clone().into()),
-                _ => None,
+                sqlparser::ast::SchemaName::NamedAuthorization(obj_name, _) => {
+                    Some(obj_name.clone())
+                }
+                // Without a name the schema is named after the owner
+                sqlparser::ast::SchemaName::UnnamedAuthorization(owner) => {
+                    Some(ObjectName(vec![ObjectNamePart::Identifier(owner.clone())]))
+                }
             },
+            Wrapped::ExclusionConstraint { constraint, .. } => constraint
+                .name
+                .as_ref()
+                .map(|name| ObjectName(vec![ObjectNamePart::Identifier(name.clone())])),
+            Wrapped::Storage { relation, .. } => (!relation.0.is_empty()).then(|| relation.clone()),
+            Wrapped::Statistics { statistics, .. } => Some(statistics.name.clone()),
+            Wrapped::Publication(publication) => {
+                Some(ObjectName(vec![ObjectNamePart::Identifier(
+                    publication.name.clone(),
+                )]))
+            }
+            Wrapped::ReplicaIdentity { table, .. } => Some(table.clone()),
         }
     }
 
@@ -691,9 +1774,16 @@ impl Wrapped {
             Statement::CreateTable(ct) => Ok(Wrapped::CreateTable(ct)),
             Statement::CreateView(cv) => Ok(Wrapped::CreateView(cv)),
             Statement::CreateIndex(ci) => Ok(Wrapped::CreateIndex(ci)),
-            Statement::CreateExtension(CreateExtension { name, .. }) => {
-                Ok(Wrapped::CreateExtension { name })
-            }
+            Statement::CreateExtension(CreateExtension {
+                name,
+                schema,
+                version,
+                ..
+            }) => Ok(Wrapped::CreateExtension {
+                name,
+                schema,
+                version,
+            }),
             Statement::CreateSchema {
                 schema_name,
                 if_not_exists,
@@ -710,6 +1800,18 @@ impl Wrapped {
                 clone,
             }),
 
+            Statement::AlterTable(at) => match at.operations.as_slice() {
+                [AlterTableOperation::ReplicaIdentity { identity }] => {
+                    Ok(Wrapped::ReplicaIdentity {
+                        table: at.name.clone(),
+                        identity: identity.clone(),
+                    })
+                }
+                _ => Err(MigrationError::UnsupportedStatementType(
+                    Statement::AlterTable(at),
+                )),
+            },
+
             statement => Err(MigrationError::UnsupportedStatementType(statement)),
         }
     }
@@ -802,6 +1904,46 @@ mod test_str_to_str {
         assert_eq!(r, alter);
     }
 
+    #[test]
+    fn test_unchanged_default_normalized() {
+        let from = str_to_create_table(
+            r#"CREATE TABLE "test" (name varchar DEFAULT 'foo'::character varying, n int DEFAULT '-1'::integer, data jsonb DEFAULT '{}'::jsonb)"#,
+        );
+        let target = str_to_create_table(
+            r#"CREATE TABLE "test" (name varchar DEFAULT 'foo', n int DEFAULT -1, data jsonb DEFAULT '{}')"#,
+        );
+
+        let r = from_to_table(&from, &target).expect("works");
+
+        assert_eq!(r, Vec::<Statement>::new());
+    }
+
+    #[test]
+    fn test_unchanged_check_constraint_normalized() {
+        let from = str_to_create_table(
+            r#"CREATE TABLE test (
+                status varchar,
+                n int,
+                CONSTRAINT check_status CHECK (((status)::text = ANY ((ARRAY['a'::character varying, 'b'::character varying])::text[]))),
+                CONSTRAINT check_n CHECK (((n >= 1) AND (n <= 5))),
+                CONSTRAINT check_like CHECK (((status)::text ~~ 'a%'::text))
+            )"#,
+        );
+        let target = str_to_create_table(
+            r#"CREATE TABLE test (
+                status varchar,
+                n int,
+                CONSTRAINT check_status CHECK (status IN ('a', 'b')),
+                CONSTRAINT check_n CHECK (n BETWEEN 1 AND 5),
+                CONSTRAINT check_like CHECK (status LIKE 'a%')
+            )"#,
+        );
+
+        let r = from_to_table(&from, &target).expect("works");
+
+        assert_eq!(r, Vec::<Statement>::new());
+    }
+
     #[test]
     fn test_alter_column_drop_default() {
         let empty_table =
@@ -886,6 +2028,45 @@ mod test_str_to_str {
 
         assert_eq!(r, alter);
     }
+    #[test]
+    fn test_change_foreign_key_action() {
+        let start = str_to_create_table(
+            r#"CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY (id) REFERENCES items(id) ON DELETE CASCADE)"#,
+        );
+        let target = str_to_create_table(
+            r#"CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY (id) REFERENCES items(id) ON DELETE SET NULL)"#,
+        );
+
+        let r = from_to_table(&start, &target).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter = vec![
+            r#"ALTER TABLE test DROP CONSTRAINT fk_id"#,
+            r#"ALTER TABLE test ADD CONSTRAINT fk_id FOREIGN KEY (id) REFERENCES items(id) ON DELETE SET NULL NOT VALID"#,
+            r#"ALTER TABLE test VALIDATE CONSTRAINT fk_id"#,
+        ];
+
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_change_foreign_key_deferrable() {
+        let start = str_to_create_table(
+            r#"CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY (id) REFERENCES items(id) ON DELETE NO ACTION)"#,
+        );
+        let target = str_to_create_table(
+            r#"CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY (id) REFERENCES items(id) DEFERRABLE INITIALLY DEFERRED)"#,
+        );
+
+        let r = from_to_table(&start, &target).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter =
+            vec![r#"ALTER TABLE test ALTER CONSTRAINT fk_id DEFERRABLE INITIALLY DEFERRED"#];
+
+        assert_eq!(r, alter);
+    }
+
     #[test]
     fn test_drop_foreign_key_constraint() {
         let start = str_to_create_table(
@@ -993,419 +2174,2463 @@ mod test_str_to_str {
     }
 
     #[test]
-    fn test_add_index_compare() {
-        let named_index = str_to_wrapped(r#"CREATE INDEX idx_id on test (id)"#);
-        let unnamed_index = str_to_wrapped(r#"CREATE INDEX on test (id)"#);
-
-        // One name and one missing name shouldn't match
-        let matched = Wrapped::name_and_type_equals(&named_index, &unnamed_index);
-        assert!(!matched);
+    fn test_add_unnamed_index() {
+        let target = vec![
+            str_to_wrapped(r#"CREATE INDEX on test (id)"#),
+            str_to_wrapped(r#"CREATE INDEX on test (lower(email), id) INCLUDE (name)"#),
+            str_to_wrapped(r#"CREATE INDEX on test (id)"#),
+        ];
+
+        let r = from_to(vec![], target).expect("works");
+
+        let alter = vec![
+            str_to_statement(r#"CREATE INDEX test_id_idx on test (id)"#),
+            str_to_statement(
+                r#"CREATE INDEX test_lower_id_name_idx on test (lower(email), id) INCLUDE (name)"#,
+            ),
+            str_to_statement(r#"CREATE INDEX test_id_idx1 on test (id)"#),
+        ];
 
-        // Unnamed items shouldn't match
-        let matched = Wrapped::name_and_type_equals(&unnamed_index, &unnamed_index);
-        assert!(!matched);
+        assert_eq!(r, alter);
     }
 
     #[test]
-    fn test_create_extension() {
-        let start = vec![];
-        let target = vec![str_to_wrapped(r#"CREATE EXTENSION ltree"#)];
+    fn test_add_unnamed_constraints() {
+        let start = str_to_wrapped(r#"CREATE TABLE test (id int, parent_id int, a int, b int)"#);
+        let target = str_to_wrapped(
+            r#"CREATE TABLE test (
+                id int, parent_id int, a int, b int,
+                PRIMARY KEY (id),
+                UNIQUE (a, b),
+                CHECK (a > 0),
+                CHECK (a > b),
+                FOREIGN KEY (parent_id) REFERENCES test (id)
+            )"#,
+        );
 
-        let r = from_to(start, target).expect("works");
+        let r = from_to(vec![start], vec![target]).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
 
-        let alter = vec![str_to_statement(r#"CREATE EXTENSION ltree"#)];
+        let alter = vec![
+            r#"ALTER TABLE test ADD PRIMARY KEY (id)"#,
+            r#"ALTER TABLE test ADD CONSTRAINT test_a_b_key UNIQUE (a, b)"#,
+            r#"ALTER TABLE test ADD CONSTRAINT test_a_check CHECK (a > 0)"#,
+            r#"ALTER TABLE test ADD CONSTRAINT test_check CHECK (a > b)"#,
+            r#"ALTER TABLE test ADD CONSTRAINT test_parent_id_fkey FOREIGN KEY (parent_id) REFERENCES test(id)"#,
+        ];
 
         assert_eq!(r, alter);
     }
 
-    fn str_to_wrapped(s: &str) -> Wrapped {
-        let ast = str_to_statement(s);
-        match ast {
-            Statement::CreateTable(ct) => Wrapped::CreateTable(ct),
-            Statement::CreateView(cv) => Wrapped::CreateView(cv),
-            Statement::CreateIndex(ci) => Wrapped::CreateIndex(ci),
-            Statement::CreateExtension(CreateExtension { name, .. }) => {
-                Wrapped::CreateExtension { name }
-            }
-            _ => panic!("Unhandled relation type"),
-        }
-    }
+    #[test]
+    fn test_unchanged_inline_constraints() {
+        let start = str_to_wrapped(
+            r#"CREATE TABLE test (
+                id int NOT NULL, parent_id int, email text, n int,
+                CONSTRAINT test_pkey PRIMARY KEY (id),
+                CONSTRAINT test_email_key UNIQUE (email),
+                CONSTRAINT test_parent_id_fkey FOREIGN KEY (parent_id) REFERENCES test(id),
+                CONSTRAINT test_n_check CHECK (n > 0)
+            )"#,
+        );
+        let target = str_to_wrapped(
+            r#"CREATE TABLE test (
+                id int PRIMARY KEY,
+                parent_id int REFERENCES test (id),
+                email text UNIQUE,
+                n int CHECK (n > 0)
+            )"#,
+        );
 
-    fn str_to_create_table(s: &str) -> CreateTable {
-        let ast = str_to_statement(s);
-        match ast {
-            Statement::CreateTable(ct) => ct,
-            _ => panic!("Expected a CREATE TABLE statement"),
-        }
-    }
+        let r = from_to(vec![start], vec![target]).expect("works");
 
-    fn str_to_statement(s: &str) -> Statement {
-        let dialect = sqlparser::dialect::PostgreSqlDialect {};
-        let parser = sqlparser::parser::Parser::new(&dialect);
-        let mut parser = parser.try_with_sql(s).expect("SQL");
-        parser.parse_statement().expect("Not valid sql")
+        assert_eq!(r, Vec::<Statement>::new());
     }
-}
 
-fn semicolon_token() -> sqlparser::ast::helpers::attached_token::AttachedToken {
-    use sqlparser::ast::helpers::attached_token::AttachedToken;
-    use sqlparser::tokenizer::{Location, Span, Token, TokenWithLocation};
-    AttachedToken(TokenWithLocation::new(
-        Token::SemiColon,
-        Span::new(Location::new(1, 10), Location::new(1, 11)),
-    ))
-}
+    #[test]
+    fn test_add_inline_constraint() {
+        let start = str_to_wrapped(r#"CREATE TABLE test (id int, email text)"#);
+        let target = str_to_wrapped(r#"CREATE TABLE test (id int PRIMARY KEY, email text UNIQUE)"#);
 
-#[cfg(test)]
-mod test_str_to_pg {
-    use super::*;
-    use sqlx::postgres::PgPool;
+        let r = from_to(vec![start], vec![target]).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
 
-    #[sqlx::test]
-    fn test_add_column(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE test ()"#, &pool)
-            .await
-            .expect("Setup");
-        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
-            .await
-            .expect("Migrate");
+        let alter = vec![
+            r#"ALTER TABLE test ADD PRIMARY KEY (id)"#,
+            r#"ALTER TABLE test ADD CONSTRAINT test_email_key UNIQUE (email)"#,
+        ];
 
-        let alter = vec![r#"ALTER TABLE test ADD COLUMN id UUID"#];
+        assert_eq!(r, alter);
+    }
 
-        assert_eq!(m, alter);
+    #[test]
+    fn test_rename_constraints() {
+        let start = str_to_create_table(
+            r#"CREATE TABLE test (
+                id int, email text,
+                CONSTRAINT uniq_email UNIQUE (email),
+                CONSTRAINT fk_id FOREIGN KEY (id) REFERENCES items(id),
+                CONSTRAINT positive CHECK (id > 0)
+            )"#,
+        );
+        let target = str_to_create_table(
+            r#"CREATE TABLE test (
+                id int, email text,
+                CONSTRAINT test_email_key UNIQUE (email),
+                CONSTRAINT test_id_fkey FOREIGN KEY (id) REFERENCES items(id),
+                CONSTRAINT id_big CHECK (id > 10)
+            )"#,
+        );
+
+        let r = from_to_table(&start, &target).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter = vec![
+            r#"ALTER TABLE "test" RENAME CONSTRAINT uniq_email TO test_email_key"#,
+            r#"ALTER TABLE "test" RENAME CONSTRAINT fk_id TO test_id_fkey"#,
+            r#"ALTER TABLE test ADD CONSTRAINT id_big CHECK (id > 10)"#,
+            r#"ALTER TABLE "test" DROP CONSTRAINT positive CASCADE"#,
+        ];
+
+        assert_eq!(r, alter);
     }
 
-    #[sqlx::test]
-    fn test_add_column_to_separate_schemas(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE SCHEMA schema1 "#, &pool)
-            .await
-            .expect("Setup schema");
-        crate::migrate_from_string(r#"CREATE SCHEMA schema2 "#, &pool)
-            .await
-            .expect("Setup schema");
-        crate::migrate_from_string(r#"CREATE TABLE schema1.test ()"#, &pool)
-            .await
-            .expect("Setup");
-        let m = crate::generate_migrations_from_string(r#"CREATE TABLE schema2.test ()"#, &pool)
-            .await
-            .expect("Migrate");
+    #[test]
+    fn test_rename_index() {
+        let table = r#"CREATE TABLE test (id int, name text)"#;
+        let start = vec![
+            str_to_wrapped(table),
+            str_to_wrapped(r#"CREATE INDEX idx_id ON test (id)"#),
+            str_to_wrapped(r#"CREATE INDEX idx_name ON test (name)"#),
+        ];
+        let target = vec![
+            str_to_wrapped(table),
+            str_to_wrapped(r#"CREATE INDEX test_id ON test (id)"#),
+            str_to_wrapped(r#"CREATE INDEX test_name ON test (lower(name))"#),
+        ];
 
-        let alter = vec![r#"CREATE TABLE schema2.test ()"#];
-        assert_eq!(m, alter);
+        let r = from_to(start, target).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter = vec![
+            r#"ALTER INDEX idx_id RENAME TO test_id"#,
+            r#"CREATE INDEX test_name ON test(lower(name))"#,
+            r#"DROP INDEX idx_name CASCADE"#,
+        ];
+
+        assert_eq!(r, alter);
     }
-    #[sqlx::test]
-    fn test_add_column_to_default_schemas(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE public.test ()"#, &pool)
-            .await
-            .expect("Setup");
-        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
-            .await
-            .expect("Migrate");
 
-        let alter = vec![r#"ALTER TABLE test ADD COLUMN id UUID"#];
+    #[test]
+    fn test_move_table() {
+        let start = vec![
+            str_to_wrapped(
+                r#"CREATE TABLE orders (id int, CONSTRAINT orders_pkey PRIMARY KEY (id))"#,
+            ),
+            str_to_wrapped(r#"CREATE INDEX orders_id_idx ON public.orders (id)"#),
+            str_to_wrapped(
+                r#"CREATE TABLE items (order_id int, CONSTRAINT items_order_id_fkey FOREIGN KEY (order_id) REFERENCES orders(id))"#,
+            ),
+        ];
+        let target = vec![
+            str_to_wrapped(r#"CREATE TABLE billing.invoices (id int PRIMARY KEY)"#),
+            str_to_wrapped(r#"CREATE INDEX orders_id_idx ON billing.invoices (id)"#),
+            str_to_wrapped(r#"CREATE TABLE items (order_id int REFERENCES billing.invoices (id))"#),
+        ];
+        let options = MigrationOptions {
+            moved_tables: vec![(
+                ObjectName::from(vec![Ident::new("public"), Ident::new("orders")]),
+                ObjectName::from(vec![Ident::new("billing"), Ident::new("invoices")]),
+            )],
+            ..Default::default()
+        };
+
+        let r = from_to_with_options(start, target, &options).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter = vec![
+            r#"ALTER TABLE "orders" SET SCHEMA billing"#,
+            r#"ALTER TABLE "billing"."orders" RENAME TO invoices"#,
+        ];
 
-        assert_eq!(m, alter);
+        assert_eq!(r, alter);
     }
-    #[sqlx::test]
-    fn test_no_add_column_to_named_schema_table(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE SCHEMA schema1 "#, &pool)
-            .await
-            .expect("Setup schema");
-        crate::migrate_from_string(r#"CREATE TABLE schema1.test ()"#, &pool)
-            .await
-            .expect("Setup");
-        let m = crate::generate_migrations_from_string_for_schema(
-            "schema1",
-            r#"CREATE TABLE test ()"#,
-            &pool,
+
+    #[test]
+    fn test_alter_storage() {
+        let start = crate::schema::app_objects(
+            r#"
+            CREATE UNLOGGED TABLE test (id int) WITH (fillfactor = 70, autovacuum_enabled = false);
+            CREATE INDEX test_id_idx ON test (id) WITH (fillfactor = 70) TABLESPACE fast;
+            "#,
         )
-        .await
-        .expect("Migrate");
+        .expect("parses");
+        let target = crate::schema::app_objects(
+            r#"
+            CREATE TABLE test (id int) WITH (fillfactor = 50, toast.autovacuum_enabled) TABLESPACE slow;
+            CREATE INDEX ON test (id);
+            "#,
+        )
+        .expect("parses");
 
-        let alter: Vec<String> = vec![];
-        assert_eq!(m, alter);
+        let r = from_to(start, target).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter = vec![
+            r#"ALTER TABLE test SET LOGGED"#,
+            r#"ALTER TABLE test SET (fillfactor = 50, toast.autovacuum_enabled = true)"#,
+            r#"ALTER TABLE test RESET (autovacuum_enabled)"#,
+            r#"ALTER TABLE test SET TABLESPACE slow"#,
+            r#"ALTER INDEX test_id_idx RESET (fillfactor)"#,
+            r#"ALTER INDEX test_id_idx SET TABLESPACE pg_default"#,
+        ];
+
+        assert_eq!(r, alter);
     }
 
-    #[sqlx::test]
-    fn test_noop_for_schema_table(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE SCHEMA schema1 "#, &pool)
-            .await
-            .expect("Setup schema");
-        crate::migrate_schema_from_string("schema1", r#"CREATE TABLE test ()"#, &pool)
-            .await
-            .expect("Setup");
-        let m = crate::generate_migrations_from_string_for_schema(
-            "schema1",
-            r#"CREATE TABLE test ()"#,
-            &pool,
+    #[test]
+    fn test_create_with_storage() {
+        let target = crate::schema::app_objects(
+            r#"
+            CREATE UNLOGGED TABLE test (id int) WITH (fillfactor = 70) TABLESPACE fast;
+            CREATE INDEX test_id_idx ON test (id) WITH (fillfactor = 70) TABLESPACE fast WHERE id > 0;
+            "#,
         )
-        .await
-        .expect("Migrate");
+        .expect("parses");
+        let options = MigrationOptions {
+            concurrent_indexes: true,
+            ..Default::default()
+        };
 
-        let alter: Vec<String> = vec![];
-        assert_eq!(m, alter);
-    }
+        let r = from_to_with_options(vec![], target, &options).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
 
-    #[sqlx::test]
+        let alter = vec![
+            r#"CREATE UNLOGGED TABLE test (id INT) WITH (fillfactor = 70) TABLESPACE fast"#,
+            r#"CREATE INDEX CONCURRENTLY test_id_idx ON test(id) WITH (fillfactor = 70) TABLESPACE fast WHERE id > 0"#,
+        ];
+
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_alter_column_settings() {
+        let start = crate::schema::app_objects(
+            r#"
+            CREATE TABLE test (id int, body text STORAGE EXTERNAL, tag text);
+            ALTER TABLE ONLY test ALTER COLUMN tag SET STATISTICS 100;
+            "#,
+        )
+        .expect("parses");
+        let target = crate::schema::app_objects(
+            r#"
+            CREATE TABLE test (id int, body text STORAGE MAIN COMPRESSION lz4, tag text, note text COMPRESSION lz4);
+            ALTER TABLE test ALTER tag SET STATISTICS 500;
+            "#,
+        )
+        .expect("parses");
+
+        let r = from_to(start, target).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter = vec![
+            r#"ALTER TABLE test ALTER COLUMN body SET STORAGE MAIN"#,
+            r#"ALTER TABLE test ALTER COLUMN body SET COMPRESSION lz4"#,
+            r#"ALTER TABLE test ALTER COLUMN tag SET STATISTICS 500"#,
+            r#"ALTER TABLE test ADD COLUMN note TEXT"#,
+            r#"ALTER TABLE test ALTER COLUMN note SET COMPRESSION lz4"#,
+        ];
+
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_statistics() {
+        let start = crate::schema::app_objects(
+            r#"
+            CREATE TABLE test (a int, b int, c text);
+            CREATE STATISTICS test_ab ON a, b FROM test;
+            CREATE STATISTICS test_ac (ndistinct) ON a, c FROM test;
+            CREATE STATISTICS test_old ON a, c FROM test;
+            "#,
+        )
+        .expect("parses");
+        let target = crate::schema::app_objects(
+            r#"
+            CREATE TABLE test (a int, b int, c text);
+            CREATE STATISTICS test_ab (ndistinct, dependencies, mcv) ON a, b FROM test;
+            ALTER STATISTICS test_ab SET STATISTICS 500;
+            CREATE STATISTICS test_ac (mcv) ON a, c FROM test;
+            CREATE STATISTICS test_lower ON (lower(c)) FROM test;
+            "#,
+        )
+        .expect("parses");
+
+        let r = from_to(start, target).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter = vec![
+            r#"ALTER STATISTICS test_ab SET STATISTICS 500"#,
+            r#"DROP STATISTICS test_ac"#,
+            r#"CREATE STATISTICS test_ac (mcv) ON a, c FROM test"#,
+            r#"CREATE STATISTICS test_lower ON (lower(c)) FROM test"#,
+            r#"DROP STATISTICS test_old"#,
+        ];
+
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_publications_and_replica_identity() {
+        let start = crate::schema::app_objects(
+            r#"
+            CREATE TABLE a (id int PRIMARY KEY);
+            CREATE TABLE b (id int PRIMARY KEY);
+            CREATE TABLE c (id int NOT NULL);
+            CREATE UNIQUE INDEX c_id_idx ON c (id);
+            ALTER TABLE b REPLICA IDENTITY FULL;
+            CREATE PUBLICATION cdc FOR TABLE a, b;
+            CREATE PUBLICATION everything FOR ALL TABLES;
+            CREATE PUBLICATION old;
+            "#,
+        )
+        .expect("parses");
+        let target = crate::schema::app_objects(
+            r#"
+            CREATE TABLE a (id int PRIMARY KEY);
+            CREATE TABLE b (id int PRIMARY KEY);
+            CREATE TABLE c (id int NOT NULL);
+            CREATE UNIQUE INDEX c_id_idx ON c (id);
+            ALTER TABLE c REPLICA IDENTITY USING INDEX c_id_idx;
+            CREATE PUBLICATION cdc FOR TABLE a WITH (publish = 'insert, update');
+            ALTER PUBLICATION cdc ADD TABLE ONLY c;
+            CREATE PUBLICATION everything FOR TABLE a;
+            "#,
+        )
+        .expect("parses");
+
+        let r = from_to(start, target).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter = vec![
+            r#"ALTER TABLE c REPLICA IDENTITY USING INDEX c_id_idx"#,
+            r#"ALTER PUBLICATION cdc ADD TABLE c"#,
+            r#"ALTER PUBLICATION cdc DROP TABLE b"#,
+            r#"ALTER PUBLICATION cdc SET (publish = 'insert, update')"#,
+            r#"DROP PUBLICATION everything"#,
+            r#"CREATE PUBLICATION everything FOR TABLE a"#,
+            r#"ALTER TABLE b REPLICA IDENTITY DEFAULT"#,
+        ];
+
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_classify_steps() {
+        use crate::safety::Change;
+        let start = vec![
+            str_to_wrapped("CREATE TABLE test (id int, name text, email text)"),
+            str_to_wrapped("CREATE TABLE old (id int)"),
+        ];
+        let target = vec![
+            str_to_wrapped("CREATE TABLE test (id int NOT NULL, email text, age int)"),
+            str_to_wrapped("CREATE INDEX test_id_idx ON test (id)"),
+        ];
+
+        let r = from_to(start, target).expect("works");
+        let r: Vec<(String, crate::safety::Change)> =
+            r.iter().map(|s| (s.to_string(), s.change())).collect();
+
+        let alter = vec![
+            (
+                "ALTER TABLE test DROP COLUMN name CASCADE".to_string(),
+                Change::DataLoss,
+            ),
+            (
+                "ALTER TABLE test ALTER COLUMN id SET NOT NULL".to_string(),
+                Change::Blocking,
+            ),
+            (
+                "ALTER TABLE test ADD COLUMN age INT".to_string(),
+                Change::Safe,
+            ),
+            (
+                "CREATE INDEX test_id_idx ON test(id)".to_string(),
+                Change::Blocking,
+            ),
+            (r#"DROP TABLE "old" CASCADE"#.to_string(), Change::DataLoss),
+        ];
+
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_step_locks() {
+        let start = vec![
+            str_to_wrapped("CREATE TABLE parent (id int PRIMARY KEY)"),
+            str_to_wrapped("CREATE TABLE test (id int, parent_id int)"),
+        ];
+        let target = vec![
+            str_to_wrapped("CREATE TABLE parent (id int PRIMARY KEY)"),
+            str_to_wrapped(
+                "CREATE TABLE test (id int NOT NULL, parent_id int REFERENCES parent (id), age int)",
+            ),
+            str_to_wrapped("CREATE INDEX CONCURRENTLY test_id_idx ON test (id)"),
+            str_to_wrapped("CREATE TABLE other (id int)"),
+        ];
+
+        let r = from_to(start, target).expect("works");
+        let r: Vec<(String, Option<String>, bool)> = r
+            .iter()
+            .map(|s| {
+                let locking = s.locking();
+                (
+                    s.to_string(),
+                    locking.as_ref().map(|l| l.to_string()),
+                    locking.is_some_and(|l| l.is_long()),
+                )
+            })
+            .collect();
+
+        let alter = vec![
+            (
+                "ALTER TABLE test ALTER COLUMN id SET NOT NULL".to_string(),
+                Some("ACCESS EXCLUSIVE on test, scans the table".to_string()),
+                true,
+            ),
+            (
+                "ALTER TABLE test ADD COLUMN age INT".to_string(),
+                Some("ACCESS EXCLUSIVE on test, catalog only".to_string()),
+                false,
+            ),
+            (
+                "ALTER TABLE test ADD CONSTRAINT test_parent_id_fkey FOREIGN KEY (parent_id) REFERENCES parent(id)".to_string(),
+                Some("SHARE ROW EXCLUSIVE on test, parent, scans the table".to_string()),
+                true,
+            ),
+            (
+                "CREATE INDEX CONCURRENTLY test_id_idx ON test(id)".to_string(),
+                Some("SHARE UPDATE EXCLUSIVE on test, scans the table".to_string()),
+                false,
+            ),
+            ("CREATE TABLE other (id INT)".to_string(), None, false),
+        ];
+
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_online_set_not_null() {
+        let start = vec![str_to_wrapped("CREATE TABLE test (id int)")];
+        let target = vec![str_to_wrapped("CREATE TABLE test (id int NOT NULL)")];
+        let options = MigrationOptions {
+            online: true,
+            ..Default::default()
+        };
+
+        let r = from_to_with_options(start, target, &options).expect("works");
+        let locking: Vec<Option<String>> = crate::safety::plan_locking(&r)
+            .iter()
+            .map(|l| l.as_ref().map(|l| l.to_string()))
+            .collect();
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter = vec![
+            "ALTER TABLE test ADD CONSTRAINT test_id_not_null CHECK (id IS NOT NULL) NOT VALID",
+            "ALTER TABLE test VALIDATE CONSTRAINT test_id_not_null",
+            "ALTER TABLE test ALTER COLUMN id SET NOT NULL",
+            "ALTER TABLE test DROP CONSTRAINT test_id_not_null",
+        ];
+        assert_eq!(r, alter);
+        let locks = vec![
+            Some("ACCESS EXCLUSIVE on test, catalog only".to_string()),
+            Some("SHARE UPDATE EXCLUSIVE on test, scans the table".to_string()),
+            Some("ACCESS EXCLUSIVE on test, catalog only".to_string()),
+            Some("ACCESS EXCLUSIVE on test, catalog only".to_string()),
+        ];
+        assert_eq!(locking, locks);
+    }
+
+    #[test]
+    fn test_online_add_constraints() {
+        let start = vec![
+            str_to_wrapped("CREATE TABLE parent (id int PRIMARY KEY)"),
+            str_to_wrapped("CREATE TABLE test (id int, parent_id int)"),
+        ];
+        let target = vec![
+            str_to_wrapped("CREATE TABLE parent (id int PRIMARY KEY)"),
+            str_to_wrapped(
+                "CREATE TABLE test (id int CHECK (id > 0), parent_id int REFERENCES parent (id))",
+            ),
+        ];
+        let options = MigrationOptions {
+            online: true,
+            ..Default::default()
+        };
+
+        let r = from_to_with_options(start, target, &options).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter = vec![
+            "ALTER TABLE test ADD CONSTRAINT test_id_check CHECK (id > 0) NOT VALID",
+            "ALTER TABLE test VALIDATE CONSTRAINT test_id_check",
+            "ALTER TABLE test ADD CONSTRAINT test_parent_id_fkey FOREIGN KEY (parent_id) REFERENCES parent(id) NOT VALID",
+            "ALTER TABLE test VALIDATE CONSTRAINT test_parent_id_fkey",
+        ];
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_online_phases() {
+        let start = vec![
+            str_to_wrapped("CREATE TABLE parent (id int PRIMARY KEY)"),
+            str_to_wrapped("CREATE TABLE test (id int, parent_id int)"),
+        ];
+        let target = vec![
+            str_to_wrapped("CREATE TABLE parent (id int PRIMARY KEY)"),
+            str_to_wrapped(
+                "CREATE TABLE test (id int NOT NULL, parent_id int REFERENCES parent (id))",
+            ),
+        ];
+        let options = MigrationOptions {
+            online: true,
+            ..Default::default()
+        };
+
+        let r = from_to_with_options(start, target, &options).expect("works");
+        let r: Vec<Vec<String>> = crate::apply::phases(r)
+            .into_iter()
+            .map(|p| p.steps.iter().map(|s| s.to_string()).collect())
+            .collect();
+
+        // Each validation scans the table without the locks taken before and after it
+        let alter = vec![
+            vec![
+                "ALTER TABLE test ADD CONSTRAINT test_id_not_null CHECK (id IS NOT NULL) NOT VALID"
+                    .to_string(),
+            ],
+            vec!["ALTER TABLE test VALIDATE CONSTRAINT test_id_not_null".to_string()],
+            vec![
+                "ALTER TABLE test ALTER COLUMN id SET NOT NULL".to_string(),
+                "ALTER TABLE test DROP CONSTRAINT test_id_not_null".to_string(),
+                "ALTER TABLE test ADD CONSTRAINT test_parent_id_fkey FOREIGN KEY (parent_id) REFERENCES parent(id) NOT VALID".to_string(),
+            ],
+            vec!["ALTER TABLE test VALIDATE CONSTRAINT test_parent_id_fkey".to_string()],
+        ];
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_concurrent_indexes() {
+        let start = vec![
+            str_to_wrapped("CREATE TABLE test (id int, name text)"),
+            str_to_wrapped("CREATE INDEX test_name_idx ON test (name)"),
+        ];
+        let target = vec![
+            str_to_wrapped("CREATE TABLE test (id int, name text)"),
+            str_to_wrapped("CREATE INDEX test_id_idx ON test (id)"),
+        ];
+        let options = MigrationOptions {
+            concurrent_indexes: true,
+            ..Default::default()
+        };
+
+        let r = from_to_with_options(start, target, &options).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter = vec![
+            "CREATE INDEX CONCURRENTLY test_id_idx ON test(id)",
+            "DROP INDEX CONCURRENTLY test_name_idx",
+        ];
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_phases() {
+        let start = vec![
+            str_to_wrapped("CREATE TABLE test (id int)"),
+            str_to_wrapped("CREATE INDEX test_id_idx ON test (id)"),
+        ];
+        let target = vec![
+            str_to_wrapped("CREATE TABLE test (id int, name text, email text)"),
+            str_to_wrapped("CREATE INDEX test_name_idx ON test (name)"),
+            str_to_wrapped("CREATE INDEX test_email_idx ON test (email)"),
+        ];
+        let options = MigrationOptions {
+            concurrent_indexes: true,
+            ..Default::default()
+        };
+
+        let r = from_to_with_options(start, target, &options).expect("works");
+        let r: Vec<(bool, Vec<String>)> = crate::apply::phases(r)
+            .into_iter()
+            .map(|p| {
+                (
+                    p.transactional,
+                    p.steps.iter().map(|s| s.to_string()).collect(),
+                )
+            })
+            .collect();
+
+        let alter = vec![
+            (
+                true,
+                vec![
+                    "ALTER TABLE test ADD COLUMN name TEXT".to_string(),
+                    "ALTER TABLE test ADD COLUMN email TEXT".to_string(),
+                ],
+            ),
+            (
+                false,
+                vec!["CREATE INDEX CONCURRENTLY test_name_idx ON test(name)".to_string()],
+            ),
+            (
+                false,
+                vec!["CREATE INDEX CONCURRENTLY test_email_idx ON test(email)".to_string()],
+            ),
+            (
+                false,
+                vec!["DROP INDEX CONCURRENTLY test_id_idx".to_string()],
+            ),
+        ];
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_unnamed_index_name_truncated() {
+        let table = "t".repeat(60);
+        let target = vec![str_to_wrapped(&format!(
+            "CREATE INDEX on {table} (a_fairly_long_column_name)"
+        ))];
+
+        let r = from_to(vec![], target).expect("works");
+
+        // The longer of the table and column names is shortened to fit in 63 bytes
+        let name = format!("{}_{}_idx", "t".repeat(33), "a_fairly_long_column_name");
+        assert_eq!(name.len(), 63);
+        let alter = vec![str_to_statement(&format!(
+            "CREATE INDEX {name} on {table} (a_fairly_long_column_name)"
+        ))];
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_add_index_compare() {
+        let named_index = str_to_wrapped(r#"CREATE INDEX idx_id on test (id)"#);
+        let unnamed_index = str_to_wrapped(r#"CREATE INDEX on test (id)"#);
+
+        // One name and one missing name shouldn't match
+        let matched = Wrapped::name_and_type_equals(&named_index, &unnamed_index);
+        assert!(!matched);
+
+        // Unnamed items shouldn't match
+        let matched = Wrapped::name_and_type_equals(&unnamed_index, &unnamed_index);
+        assert!(!matched);
+    }
+
+    #[test]
+    fn test_unchanged_index_normalized() {
+        let start = vec![str_to_wrapped(
+            r#"CREATE INDEX idx_active ON public.test USING btree (lower(email) DESC, id) INCLUDE (name) WHERE (deleted_at IS NULL)"#,
+        )];
+        let target = vec![str_to_wrapped(
+            r#"CREATE INDEX idx_active ON test (LOWER("email") DESC NULLS FIRST, id ASC) INCLUDE (name) WHERE deleted_at IS NULL"#,
+        )];
+
+        let r = from_to(start, target).expect("works");
+        assert_eq!(r, Vec::<Statement>::new());
+    }
+
+    #[test]
+    fn test_changed_index_predicate() {
+        let start = vec![str_to_wrapped(
+            r#"CREATE INDEX idx_active ON public.test USING btree (id) WHERE (deleted_at IS NULL)"#,
+        )];
+        let target = vec![str_to_wrapped(
+            r#"CREATE INDEX idx_active ON test (id) WHERE deleted_at IS NOT NULL"#,
+        )];
+
+        let maybe_err = from_to(start, target);
+        match maybe_err {
+            Err(MigrationError::CannotModifyIndex(_, _)) => (),
+            _ => panic!("Not the right error {maybe_err:?}"),
+        }
+    }
+
+    #[test]
+    fn test_trailing_tokens() {
+        let r = crate::schema::app_objects(r#"CREATE TABLE test (a int) garbage here"#);
+        assert!(matches!(r, Err(MigrationError::SqlParseError(_))), "{r:?}");
+    }
+
+    #[test]
+    fn test_create_extension() {
+        let start = vec![];
+        let target = vec![str_to_wrapped(r#"CREATE EXTENSION ltree"#)];
+
+        let r = from_to(start, target).expect("works");
+
+        let alter = vec![str_to_statement(r#"CREATE EXTENSION ltree"#)];
+
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_update_extension_version() {
+        let start = vec![str_to_wrapped(
+            r#"CREATE EXTENSION ltree WITH VERSION '1.1'"#,
+        )];
+        let target = vec![str_to_wrapped(
+            r#"CREATE EXTENSION ltree WITH VERSION '1.2'"#,
+        )];
+
+        let r = from_to(start, target).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        assert_eq!(r, vec![r#"ALTER EXTENSION ltree UPDATE TO '1.2'"#]);
+    }
+
+    #[test]
+    fn test_extension_options_without_with() {
+        let start = vec![str_to_wrapped(
+            r#"CREATE EXTENSION ltree WITH SCHEMA public VERSION '1.1'"#,
+        )];
+        let target =
+            crate::schema::app_objects(r#"CREATE EXTENSION ltree SCHEMA ext VERSION '1.2'"#)
+                .expect("parses");
+
+        let r = from_to(start, target).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        assert_eq!(
+            r,
+            vec![
+                r#"ALTER EXTENSION ltree UPDATE TO '1.2'"#,
+                r#"ALTER EXTENSION ltree SET SCHEMA ext"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_extension_schema() {
+        let start = vec![str_to_wrapped(
+            r#"CREATE EXTENSION ltree WITH SCHEMA public"#,
+        )];
+        let target = vec![str_to_wrapped(r#"CREATE EXTENSION ltree WITH SCHEMA ext"#)];
+
+        let r = from_to(start, target).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        assert_eq!(r, vec![r#"ALTER EXTENSION ltree SET SCHEMA ext"#]);
+    }
+
+    #[test]
+    fn test_unspecified_extension_version_and_schema() {
+        let start = vec![str_to_wrapped(
+            r#"CREATE EXTENSION ltree WITH SCHEMA public VERSION '1.1'"#,
+        )];
+        let target = vec![str_to_wrapped(r#"CREATE EXTENSION ltree"#)];
+
+        let r = from_to(start, target).expect("works");
+
+        assert_eq!(r, Vec::<Statement>::new());
+    }
+
+    #[test]
+    fn test_drop_extension() {
+        let start = vec![str_to_wrapped(r#"CREATE EXTENSION ltree"#)];
+
+        let r = from_to(start.clone(), vec![]).expect("works");
+        assert_eq!(r, Vec::<Statement>::new());
+
+        let options = MigrationOptions {
+            drop_extensions: true,
+            ..Default::default()
+        };
+        let r = from_to_with_options(start, vec![], &options).expect("works");
+        assert_eq!(r, vec![str_to_statement(r#"DROP EXTENSION ltree"#)]);
+    }
+
+    #[test]
+    fn test_create_schema() {
+        let r = from_to(vec![], vec![str_to_wrapped(r#"CREATE SCHEMA billing"#)]).expect("works");
+        assert_eq!(r, vec![str_to_statement(r#"CREATE SCHEMA billing"#)]);
+    }
+
+    #[test]
+    fn test_alter_schema_owner() {
+        let start = vec![str_to_wrapped(
+            r#"CREATE SCHEMA billing AUTHORIZATION postgres"#,
+        )];
+        let target = vec![str_to_wrapped(
+            r#"CREATE SCHEMA billing AUTHORIZATION billing_owner"#,
+        )];
+
+        let r = from_to(start, target).expect("works");
+        assert_eq!(
+            r,
+            vec![str_to_statement(
+                r#"ALTER SCHEMA "billing" OWNER TO billing_owner"#
+            )]
+        );
+    }
+
+    #[test]
+    fn test_unspecified_schema_owner() {
+        let start = vec![str_to_wrapped(
+            r#"CREATE SCHEMA billing AUTHORIZATION postgres"#,
+        )];
+        let target = vec![str_to_wrapped(r#"CREATE SCHEMA billing"#)];
+
+        let r = from_to(start, target).expect("works");
+        assert_eq!(r, Vec::<Statement>::new());
+    }
+
+    #[test]
+    fn test_drop_schema() {
+        let start = vec![str_to_wrapped(r#"CREATE SCHEMA billing"#)];
+
+        let r = from_to(start.clone(), vec![]).expect("works");
+        assert_eq!(r, Vec::<Statement>::new());
+
+        let options = MigrationOptions {
+            drop_schemas: true,
+            ..Default::default()
+        };
+        let r = from_to_with_options(start.clone(), vec![], &options).expect("works");
+        assert_eq!(
+            r,
+            vec![str_to_statement(r#"DROP SCHEMA "billing" RESTRICT"#)]
+        );
+
+        let options = MigrationOptions {
+            drop_schemas: true,
+            drop_non_empty_schemas: true,
+            ..Default::default()
+        };
+        let r = from_to_with_options(start, vec![], &options).expect("works");
+        assert_eq!(
+            r,
+            vec![str_to_statement(r#"DROP SCHEMA "billing" CASCADE"#)]
+        );
+    }
+
+    #[test]
+    fn test_add_exclusion_constraint() {
+        let start =
+            crate::schema::app_objects(r#"CREATE TABLE booking (during tsrange)"#).expect("Parse");
+        let target = crate::schema::app_objects(
+            r#"CREATE TABLE booking (
+                during tsrange,
+                CONSTRAINT no_overlap EXCLUDE USING gist (during WITH &&)
+            )"#,
+        )
+        .expect("Parse");
+
+        let r = from_to(start, target).expect("works");
+        let r: Vec<String> = r.iter().map(|s| s.to_string()).collect();
+
+        let alter = vec![
+            r#"ALTER TABLE "booking" ADD CONSTRAINT no_overlap EXCLUDE USING gist (during WITH &&)"#,
+        ];
+
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_drop_exclusion_constraint() {
+        let start = crate::schema::app_objects(
+            r#"CREATE TABLE booking (
+                room int,
+                during tsrange,
+                CONSTRAINT no_overlap EXCLUDE USING gist (room WITH =, during WITH &&),
+                PRIMARY KEY (room)
+            )"#,
+        )
+        .expect("Parse");
+        let target = crate::schema::app_objects(
+            r#"CREATE TABLE booking (room int, during tsrange, PRIMARY KEY (room))"#,
+        )
+        .expect("Parse");
+
+        let r = from_to(start, target).expect("works");
+
+        let alter = vec![str_to_statement(
+            r#"ALTER TABLE "booking" DROP CONSTRAINT no_overlap CASCADE"#,
+        )];
+
+        assert_eq!(r, alter);
+    }
+
+    #[test]
+    fn test_modify_exclusion_constraint() {
+        let start = crate::schema::app_objects(
+            r#"CREATE TABLE booking (
+                room int,
+                during tsrange,
+                CONSTRAINT no_overlap EXCLUDE USING gist (during WITH &&)
+            )"#,
+        )
+        .expect("Parse");
+        let target = crate::schema::app_objects(
+            r#"CREATE TABLE booking (
+                room int,
+                during tsrange,
+                CONSTRAINT no_overlap EXCLUDE USING gist (room WITH =, during WITH &&)
+            )"#,
+        )
+        .expect("Parse");
+
+        let maybe_err = from_to(start, target);
+
+        match maybe_err {
+            Err(MigrationError::CannotModifyExclusionConstraint(_, _)) => (),
+            _ => panic!("Not the right error {maybe_err:?}"),
+        }
+    }
+
+    fn str_to_wrapped(s: &str) -> Wrapped {
+        let ast = str_to_statement(s);
+        match ast {
+            Statement::CreateTable(ct) => Wrapped::CreateTable(ct),
+            Statement::CreateView(cv) => Wrapped::CreateView(cv),
+            Statement::CreateIndex(ci) => Wrapped::CreateIndex(ci),
+            Statement::CreateExtension(ce) => {
+                Wrapped::try_from(Statement::CreateExtension(ce)).expect("Extension")
+            }
+            s @ Statement::CreateSchema { .. } => Wrapped::try_from(s).expect("Schema"),
+            _ => panic!("Unhandled relation type"),
+        }
+    }
+
+    fn str_to_create_table(s: &str) -> CreateTable {
+        let ast = str_to_statement(s);
+        match ast {
+            Statement::CreateTable(ct) => ct,
+            _ => panic!("Expected a CREATE TABLE statement"),
+        }
+    }
+
+    fn str_to_statement(s: &str) -> Statement {
+        let dialect = sqlparser::dialect::PostgreSqlDialect {};
+        let parser = sqlparser::parser::Parser::new(&dialect);
+        let mut parser = parser.try_with_sql(s).expect("SQL");
+        parser.parse_statement().expect("Not valid sql")
+    }
+}
+
+fn semicolon_token() -> sqlparser::ast::helpers::attached_token::AttachedToken {
+    use sqlparser::ast::helpers::attached_token::AttachedToken;
+    use sqlparser::tokenizer::{Location, Span, Token, TokenWithLocation};
+    AttachedToken(TokenWithLocation::new(
+        Token::SemiColon,
+        Span::new(Location::new(1, 10), Location::new(1, 11)),
+    ))
+}
+
+#[cfg(test)]
+mod test_str_to_pg {
+    use super::*;
+    use sqlx::postgres::PgPool;
+
+    #[sqlx::test]
+    fn test_add_column(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test ()"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE test ADD COLUMN id UUID"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_add_column_to_separate_schemas(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE SCHEMA schema1 "#, &pool)
+            .await
+            .expect("Setup schema");
+        crate::migrate_from_string(r#"CREATE SCHEMA schema2 "#, &pool)
+            .await
+            .expect("Setup schema");
+        crate::migrate_from_string(r#"CREATE TABLE schema1.test ()"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE schema2.test ()"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"CREATE TABLE schema2.test ()"#];
+        assert_eq!(m, alter);
+    }
+    #[sqlx::test]
+    fn test_add_column_to_default_schemas(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE public.test ()"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE test ADD COLUMN id UUID"#];
+
+        assert_eq!(m, alter);
+    }
+    #[sqlx::test]
+    fn test_no_add_column_to_named_schema_table(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE SCHEMA schema1 "#, &pool)
+            .await
+            .expect("Setup schema");
+        crate::migrate_from_string(r#"CREATE TABLE schema1.test ()"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string_for_schema(
+            "schema1",
+            r#"CREATE TABLE test ()"#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_noop_for_schema_table(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE SCHEMA schema1 "#, &pool)
+            .await
+            .expect("Setup schema");
+        crate::migrate_schema_from_string("schema1", r#"CREATE TABLE test ()"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string_for_schema(
+            "schema1",
+            r#"CREATE TABLE test ()"#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
     fn test_remove_column(pool: PgPool) {
         crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
             .await
             .expect("Setup");
-        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test ()"#, &pool)
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test ()"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE test DROP COLUMN id CASCADE"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_alter_column_not_null(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(
+            r#"CREATE TABLE test (id uuid NOT NULL)"#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE test ALTER COLUMN id SET NOT NULL"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_alter_column_drop_not_null(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id uuid NOT NULL)"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE test ALTER COLUMN id DROP NOT NULL"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_alter_column_set_default(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (name varchar)"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(
+            r#"CREATE TABLE test (name varchar DEFAULT 'foo')"#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE test ALTER COLUMN name SET DEFAULT 'foo'"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_alter_column_set_new_default(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (name varchar DEFAULT 'foo')"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(
+            r#"CREATE TABLE test (name varchar DEFAULT 'bar')"#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE test ALTER COLUMN name SET DEFAULT 'bar'"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_alter_column_drop_default(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (name varchar DEFAULT 'foo')"#, &pool)
+            .await
+            .expect("Setup");
+        let m =
+            crate::generate_migrations_from_string(r#"CREATE TABLE test (name varchar )"#, &pool)
+                .await
+                .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE test ALTER COLUMN name DROP DEFAULT"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_add_table(pool: PgPool) {
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"CREATE TABLE test (id UUID)"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_add_table_with_quotes(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE "test" (id uuid)"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE "test" (id uuid)"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_add_table_removing_quotes(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE "test" (id uuid)"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_add_table_adding_quotes(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE "test" (id uuid)"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_add_table_reserved_word(pool: PgPool) {
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE "user" (id uuid)"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"CREATE TABLE "user" (id UUID)"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_drop_table(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(r#""#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"DROP TABLE "test" CASCADE"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_drop_table_reserved_word(pool: PgPool) {
+        let m = crate::migrate_from_string(r#"CREATE TABLE "user" ()"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let m = crate::generate_migrations_from_string(r#""#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"DROP TABLE "user" CASCADE"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_add_primary_key_constraint(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(
+            r#"CREATE TABLE test (id uuid, PRIMARY KEY(id))"#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE test ADD PRIMARY KEY (id)"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_add_foreign_key_constraint(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(
+            r#"CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY(id) REFERENCES items(id))"#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter =
+            vec![r#"ALTER TABLE test ADD CONSTRAINT fk_id FOREIGN KEY (id) REFERENCES items(id)"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_drop_foreign_key_constraint(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE TABLE items (id uuid NOT NULL, PRIMARY KEY(id));
+                CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY(id) REFERENCES items(id))"#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let m = crate::generate_migrations_from_string(
+            r#"
+               CREATE TABLE items (id uuid NOT NULL, PRIMARY KEY(id));
+               CREATE TABLE test (id uuid)"#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE "test" DROP CONSTRAINT fk_id CASCADE"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_same_foreign_key_constraint(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE TABLE items (id uuid NOT NULL, PRIMARY KEY(id));
+                CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY(id) REFERENCES items(id))"#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let m = crate::generate_migrations_from_string(
+            r#"
+                CREATE TABLE items (id uuid NOT NULL, PRIMARY KEY(id));
+                CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY(id) REFERENCES items(id))"#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_modify_foreign_key_constraint(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE TABLE items (
+                    id uuid NOT NULL,
+                    id2 uuid NOT NULL,
+                    PRIMARY KEY(id)
+                );
+                CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY(id) REFERENCES items(id))"#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let maybe_err = crate::generate_migrations_from_string(
+            r#"
+                CREATE TABLE items (
+                    id uuid NOT NULL,
+                    id2 uuid NOT NULL,
+                    PRIMARY KEY(id)
+                );
+                CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY(id) REFERENCES items(id2))"#,
+            &pool,
+        )
+        .await;
+
+        match maybe_err {
+            Err(MigrationError::CannotModifyTableConstraint(_, _)) => (),
+            _ => panic!("Not the right error {maybe_err:?}"),
+        }
+    }
+
+    #[sqlx::test]
+    fn test_change_foreign_key_action_and_deferrable(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE TABLE items (id uuid PRIMARY KEY);
+                CREATE TABLE test (
+                    id uuid CONSTRAINT fk_id REFERENCES items(id) ON DELETE CASCADE,
+                    id2 uuid CONSTRAINT fk_id2 REFERENCES items(id)
+                )"#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let schema = r#"
+                CREATE TABLE items (id uuid PRIMARY KEY);
+                CREATE TABLE test (
+                    id uuid CONSTRAINT fk_id REFERENCES items(id) ON DELETE SET NULL,
+                    id2 uuid CONSTRAINT fk_id2 REFERENCES items(id) DEFERRABLE INITIALLY DEFERRED
+                )"#;
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![
+            r#"ALTER TABLE test DROP CONSTRAINT fk_id"#,
+            r#"ALTER TABLE test ADD CONSTRAINT fk_id FOREIGN KEY (id) REFERENCES items(id) ON DELETE SET NULL NOT VALID"#,
+            r#"ALTER TABLE test VALIDATE CONSTRAINT fk_id"#,
+            r#"ALTER TABLE test ALTER CONSTRAINT fk_id2 DEFERRABLE INITIALLY DEFERRED"#,
+        ];
+        assert_eq!(m, alter);
+
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_add_check_constraint(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(
+            r#"CREATE TABLE test (id uuid, CONSTRAINT check_id CHECK (id = 1))"#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE test ADD CONSTRAINT check_id CHECK (id = 1)"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_drop_check_constraint(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"CREATE TABLE test (id int, CONSTRAINT check_id CHECK (id = 1))"#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id int)"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE "test" DROP CONSTRAINT check_id CASCADE"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_same_check_constraint(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"CREATE TABLE test (id int, CONSTRAINT check_id CHECK (id = 1))"#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let m = crate::generate_migrations_from_string(
+            r#"CREATE TABLE test (id int, CONSTRAINT check_id CHECK (id = 1))"#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter: Vec<&str> = vec![];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_same_normalized_defaults_and_check_constraints(pool: PgPool) {
+        let schema = r#"
+            CREATE TABLE test (
+                name varchar DEFAULT 'foo',
+                n int DEFAULT -1,
+                data jsonb DEFAULT '{}',
+                created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+                updated_at timestamptz DEFAULT now(),
+                status varchar,
+                CONSTRAINT check_status CHECK (status IN ('a', 'b')),
+                CONSTRAINT check_status_not CHECK (status NOT IN ('c')),
+                CONSTRAINT check_n CHECK (n BETWEEN -5 AND 5),
+                CONSTRAINT check_name CHECK ("name" LIKE 'f%' AND length(name) > 0)
+            )
+        "#;
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<&str> = vec![];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_modify_check_constraint(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"CREATE TABLE test (id int, CONSTRAINT check_id CHECK (id = 1))"#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let maybe_err = crate::generate_migrations_from_string(
+            r#"CREATE TABLE test (id int, CONSTRAINT check_id CHECK (id = 2))"#,
+            &pool,
+        )
+        .await;
+
+        match maybe_err {
+            Err(MigrationError::CannotModifyTableConstraint(_, _)) => (),
+            _ => panic!("Not the right error {maybe_err:?}"),
+        }
+    }
+
+    #[sqlx::test]
+    fn test_add_unique_constraint(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(
+            r#"CREATE TABLE test (id uuid, CONSTRAINT id_u UNIQUE (id))"#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE test ADD CONSTRAINT id_u UNIQUE (id)"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_drop_unique_constraint(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"CREATE TABLE test (id uuid, CONSTRAINT id_u UNIQUE (id))"#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
             .await
             .expect("Migrate");
 
-        let alter = vec![r#"ALTER TABLE test DROP COLUMN id CASCADE"#];
+        let alter = vec![r#"ALTER TABLE "test" DROP CONSTRAINT id_u CASCADE"#];
 
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_alter_column_not_null(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+    fn test_add_view(pool: PgPool) {
+        let m =
+            crate::generate_migrations_from_string(r#"CREATE VIEW "test_view" AS SELECT 1"#, &pool)
+                .await
+                .expect("Migrate");
+
+        let alter = vec![r#"CREATE VIEW "test_view" AS SELECT 1"#];
+
+        assert_eq!(m, alter);
+    }
+    #[sqlx::test]
+    fn test_drop_view(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE VIEW "test_view" AS SELECT 1 AS a"#, &pool)
+            .await
+            .expect("Setup");
+        println!("generate_migrattions_from_string");
+        let m = crate::generate_migrations_from_string(r#""#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"DROP VIEW "test_view" CASCADE"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_alter_view(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE VIEW "test_view" AS SELECT 1 AS a"#, &pool)
             .await
             .expect("Setup");
+        println!("generate_migrattions_from_string");
         let m = crate::generate_migrations_from_string(
-            r#"CREATE TABLE test (id uuid NOT NULL)"#,
+            r#"CREATE VIEW "test_view" AS SELECT 1 AS a, 2 as b"#,
             &pool,
         )
         .await
         .expect("Migrate");
 
-        let alter = vec![r#"ALTER TABLE test ALTER COLUMN id SET NOT NULL"#];
+        let alter = vec![r#"CREATE OR REPLACE VIEW "test_view" AS SELECT 1 AS a, 2 AS b"#];
 
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_alter_column_drop_not_null(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE test (id uuid NOT NULL)"#, &pool)
+    fn test_add_index(pool: PgPool) {
+        let m =
+            crate::generate_migrations_from_string(r#"CREATE INDEX idx_id on test (id)"#, &pool)
+                .await
+                .expect("Migrate");
+
+        let alter = vec![r#"CREATE INDEX idx_id ON test(id)"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_add_index_without_name(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE TABLE test (id uuid NOT NULL);
+                CREATE INDEX idx_id on test (id);
+
+            "#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let m = crate::generate_migrations_from_string(
+            r#"
+                    CREATE TABLE test (id uuid NOT NULL);
+                    CREATE INDEX on public.test (id)
+            "#,
+            &pool,
+        )
+        .await
+        .expect("Migrate");
+
+        let alter = vec![r#"ALTER INDEX idx_id RENAME TO test_id_idx"#];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_rename_constraints_and_indexes(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE TABLE test (
+                    id int CONSTRAINT pk PRIMARY KEY,
+                    parent_id int CONSTRAINT fk_parent REFERENCES test (id) ON DELETE CASCADE,
+                    email text CONSTRAINT uniq_email UNIQUE,
+                    n int CONSTRAINT positive CHECK (n > 0)
+                );
+                CREATE INDEX idx_parent ON test (parent_id) WHERE n > 1;
+            "#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let schema = r#"
+                CREATE TABLE test (
+                    id int PRIMARY KEY,
+                    parent_id int REFERENCES test (id) ON DELETE CASCADE,
+                    email text UNIQUE,
+                    n int CHECK (n > 0)
+                );
+                CREATE INDEX ON test (parent_id) WHERE n > 1;
+            "#;
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![
+            r#"ALTER INDEX idx_parent RENAME TO test_parent_id_idx"#,
+            r#"ALTER TABLE "test" RENAME CONSTRAINT fk_parent TO test_parent_id_fkey"#,
+            r#"ALTER TABLE "test" RENAME CONSTRAINT uniq_email TO test_email_key"#,
+            r#"ALTER TABLE "test" RENAME CONSTRAINT positive TO test_n_check"#,
+        ];
+        assert_eq!(m, alter);
+
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_move_table(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE SCHEMA billing;
+                CREATE TABLE orders (id int PRIMARY KEY, customer text);
+                CREATE INDEX ON orders (customer);
+                CREATE TABLE items (order_id int REFERENCES orders (id));
+            "#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let schema = r#"
+                CREATE SCHEMA billing;
+                CREATE TABLE billing.orders (id int PRIMARY KEY, customer text);
+                CREATE INDEX ON billing.orders (customer);
+                CREATE TABLE items (order_id int REFERENCES billing.orders (id));
+            "#;
+        let options = crate::MigrationOptions {
+            moved_tables: vec![(
+                ObjectName::from(vec![Ident::new("orders")]),
+                ObjectName::from(vec![Ident::new("billing"), Ident::new("orders")]),
+            )],
+            ..Default::default()
+        };
+        let m = crate::generate_migrations_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"ALTER TABLE "orders" SET SCHEMA billing"#];
+        assert_eq!(m, alter);
+
+        crate::migrate_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_alter_storage(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE UNLOGGED TABLE test (id int) WITH (fillfactor = 70);
+                CREATE INDEX ON test (id) WITH (fillfactor = 70);
+            "#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let schema = r#"
+                CREATE TABLE test (id int) WITH (fillfactor = 50, autovacuum_vacuum_scale_factor = 0.01);
+                CREATE INDEX ON test (id) WITH (fillfactor = 70);
+            "#;
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![
+            r#"ALTER TABLE test SET LOGGED"#,
+            r#"ALTER TABLE test SET (fillfactor = 50, autovacuum_vacuum_scale_factor = 0.01)"#,
+        ];
+        assert_eq!(m, alter);
+
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_create_with_storage(pool: PgPool) {
+        let schema = r#"
+                CREATE UNLOGGED TABLE test (id int) WITH (fillfactor = 70);
+                CREATE INDEX test_id_idx ON test (id) WITH (fillfactor = 70);
+            "#;
+        let options = crate::MigrationOptions {
+            concurrent_indexes: true,
+            ..Default::default()
+        };
+
+        crate::migrate_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_alter_column_settings(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id int, body text)"#, &pool)
             .await
             .expect("Setup");
-        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+        let schema = r#"
+                CREATE TABLE test (id int, body text STORAGE EXTERNAL COMPRESSION lz4);
+                ALTER TABLE test ALTER COLUMN id SET STATISTICS 500;
+                CREATE TABLE other (id int STORAGE PLAIN, body text STORAGE MAIN);
+            "#;
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![
+            r#"ALTER TABLE test ALTER COLUMN id SET STATISTICS 500"#,
+            r#"ALTER TABLE test ALTER COLUMN body SET STORAGE EXTERNAL"#,
+            r#"ALTER TABLE test ALTER COLUMN body SET COMPRESSION lz4"#,
+            r#"CREATE TABLE other (id INT, body TEXT)"#,
+            r#"ALTER TABLE other ALTER COLUMN id SET STORAGE PLAIN"#,
+            r#"ALTER TABLE other ALTER COLUMN body SET STORAGE MAIN"#,
+        ];
+        assert_eq!(m, alter);
+
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_statistics(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE TABLE test (a int, b int, c text);
+                CREATE STATISTICS test_ab ON a, b FROM test;
+                CREATE STATISTICS test_ac (ndistinct) ON a, c FROM test;
+            "#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let schema = r#"
+                CREATE TABLE test (a int, b int, c text);
+                CREATE STATISTICS test_ab ON a, b FROM test;
+                ALTER STATISTICS test_ab SET STATISTICS 500;
+                CREATE STATISTICS test_ac (mcv) ON a, (b + 1) FROM test;
+                CREATE STATISTICS test_lower ON (lower(c)) FROM test;
+            "#;
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![
+            r#"ALTER STATISTICS test_ab SET STATISTICS 500"#,
+            r#"DROP STATISTICS test_ac"#,
+            r#"CREATE STATISTICS test_ac (mcv) ON a, (b + 1) FROM test"#,
+            r#"CREATE STATISTICS test_lower ON (lower(c)) FROM test"#,
+        ];
+        assert_eq!(m, alter);
+
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_statistics_in_schema(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE SCHEMA schema1"#, &pool)
+            .await
+            .expect("Setup schema");
+        // schema1 isn't in the search path, so Postgres qualifies the table in the definition
+        let schema = r#"
+                CREATE TABLE test (a int, b int);
+                CREATE STATISTICS test_ab ON a, b FROM schema1.test;
+            "#;
+        crate::migrate_schema_from_string("schema1", schema, &pool)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string_for_schema("schema1", schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_publications_and_replica_identity(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE TABLE a (id int PRIMARY KEY);
+                CREATE TABLE b (id int PRIMARY KEY);
+                CREATE PUBLICATION cdc FOR TABLE a;
+                CREATE PUBLICATION old;
+            "#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        let options = crate::MigrationOptions {
+            drop_publications: true,
+            ..Default::default()
+        };
+        let schema = r#"
+                CREATE TABLE a (id int PRIMARY KEY);
+                CREATE TABLE b (id int PRIMARY KEY);
+                ALTER TABLE b REPLICA IDENTITY FULL;
+                CREATE PUBLICATION cdc FOR TABLE a, b WITH (publish = 'update, insert');
+            "#;
+        let m = crate::generate_migrations_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![
+            r#"ALTER TABLE b REPLICA IDENTITY FULL"#,
+            r#"ALTER PUBLICATION cdc ADD TABLE b"#,
+            r#"ALTER PUBLICATION cdc SET (publish = 'update, insert')"#,
+            r#"DROP PUBLICATION old"#,
+        ];
+        assert_eq!(m, alter);
+
+        crate::migrate_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string_with_options(schema, &pool, &options)
             .await
             .expect("Migrate");
 
-        let alter = vec![r#"ALTER TABLE test ALTER COLUMN id DROP NOT NULL"#];
-
+        let alter: Vec<String> = vec![];
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_alter_column_set_default(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE test (name varchar)"#, &pool)
-            .await
-            .expect("Setup");
-        let m = crate::generate_migrations_from_string(
-            r#"CREATE TABLE test (name varchar DEFAULT 'foo')"#,
+    fn test_refuse_data_loss(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE TABLE test (id int, name text);
+                CREATE TABLE old (id int);
+            "#,
             &pool,
         )
         .await
-        .expect("Migrate");
+        .expect("Setup");
+        let schema = r#"CREATE TABLE test (id int)"#;
 
-        let alter = vec![r#"ALTER TABLE test ALTER COLUMN name SET DEFAULT 'foo'"#];
+        let r = crate::migrate_from_string(schema, &pool).await;
+        match r {
+            Err(crate::MigrationError::DataLoss(lost)) => {
+                assert_eq!(lost, vec![r#"column test.name"#, r#"table "old""#])
+            }
+            r => panic!("Expected DataLoss, got {r:?}"),
+        }
+
+        let options = crate::MigrationOptions {
+            allow_data_loss: true,
+            ..Default::default()
+        };
+        crate::migrate_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
 
+        let alter: Vec<String> = vec![];
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_alter_column_set_new_default(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE test (name varchar DEFAULT 'foo')"#, &pool)
-            .await
-            .expect("Setup");
-        let m = crate::generate_migrations_from_string(
-            r#"CREATE TABLE test (name varchar DEFAULT 'bar')"#,
+    fn test_refuse_long_locks_on_large_tables(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE TABLE test (id int);
+                CREATE TABLE small (id int);
+            "#,
             &pool,
         )
         .await
-        .expect("Migrate");
+        .expect("Setup");
+        sqlx::query("INSERT INTO test SELECT generate_series(1, 1000)")
+            .execute(&pool)
+            .await
+            .expect("Insert");
+        let schema = r#"
+            CREATE TABLE test (id int NOT NULL, name text);
+            CREATE TABLE small (id int NOT NULL);
+        "#;
+        let options = crate::MigrationOptions {
+            max_locked_table_size: Some(8192),
+            ..Default::default()
+        };
+
+        let r = crate::migrate_from_string_with_options(schema, &pool, &options).await;
+        match r {
+            Err(crate::MigrationError::LockedTablesTooLarge(tables)) => {
+                assert_eq!(tables.len(), 1);
+                assert!(
+                    tables[0].starts_with(
+                        "ALTER TABLE test ALTER COLUMN id SET NOT NULL (ACCESS EXCLUSIVE on test, scans the table, test is "
+                    ),
+                    "{tables:?}"
+                );
+            }
+            r => panic!("Expected LockedTablesTooLarge, got {r:?}"),
+        }
 
-        let alter = vec![r#"ALTER TABLE test ALTER COLUMN name SET DEFAULT 'bar'"#];
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
 
+        let alter: Vec<String> = vec![];
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_alter_column_drop_default(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE test (name varchar DEFAULT 'foo')"#, &pool)
+    fn test_online_set_not_null(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id int)"#, &pool)
             .await
             .expect("Setup");
-        let m =
-            crate::generate_migrations_from_string(r#"CREATE TABLE test (name varchar )"#, &pool)
-                .await
-                .expect("Migrate");
-
-        let alter = vec![r#"ALTER TABLE test ALTER COLUMN name DROP DEFAULT"#];
+        sqlx::query("INSERT INTO test SELECT generate_series(1, 1000)")
+            .execute(&pool)
+            .await
+            .expect("Insert");
+        let schema = r#"CREATE TABLE test (id int NOT NULL)"#;
+        let options = crate::MigrationOptions {
+            online: true,
+            max_locked_table_size: Some(8192),
+            ..Default::default()
+        };
+
+        crate::migrate_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
 
+        let alter: Vec<String> = vec![];
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_add_table(pool: PgPool) {
-        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+    fn test_online_add_constraints(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE TABLE parent (id int PRIMARY KEY);
+                CREATE TABLE test (id int, parent_id int);
+            "#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        sqlx::query("INSERT INTO parent SELECT generate_series(1, 1000)")
+            .execute(&pool)
+            .await
+            .expect("Insert");
+        sqlx::query("INSERT INTO test SELECT id, id FROM parent")
+            .execute(&pool)
+            .await
+            .expect("Insert");
+        let schema = r#"
+            CREATE TABLE parent (id int PRIMARY KEY);
+            CREATE TABLE test (id int CHECK (id > 0), parent_id int REFERENCES parent (id));
+        "#;
+        let options = crate::MigrationOptions {
+            online: true,
+            max_locked_table_size: Some(8192),
+            ..Default::default()
+        };
+
+        crate::migrate_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
             .await
             .expect("Migrate");
 
-        let alter = vec![r#"CREATE TABLE test (id UUID)"#];
-
+        let alter: Vec<String> = vec![];
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_add_table_with_quotes(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE "test" (id uuid)"#, &pool)
+    fn test_concurrent_indexes(pool: PgPool) {
+        crate::migrate_from_string(
+            r#"
+                CREATE TABLE test (id int, name text);
+                CREATE INDEX test_name_idx ON test (name);
+            "#,
+            &pool,
+        )
+        .await
+        .expect("Setup");
+        sqlx::query("INSERT INTO test VALUES (1, 'a'), (1, 'b')")
+            .execute(&pool)
             .await
-            .expect("Setup");
-        let m = crate::generate_migrations_from_string(r#"CREATE TABLE "test" (id uuid)"#, &pool)
+            .expect("Insert");
+        let schema = r#"
+            CREATE TABLE test (id int, name text);
+            CREATE UNIQUE INDEX test_id_idx ON test (id);
+        "#;
+        let options = crate::MigrationOptions {
+            concurrent_indexes: true,
+            ..Default::default()
+        };
+
+        // The duplicate ids fail the build, and the invalid index it leaves is dropped
+        let r = crate::migrate_from_string_with_options(schema, &pool, &options).await;
+        assert!(
+            matches!(r, Err(crate::MigrationError::ExecSqlError(_))),
+            "{r:?}"
+        );
+        let invalid: i64 =
+            sqlx::query_scalar("SELECT count(*) FROM pg_catalog.pg_index WHERE NOT indisvalid")
+                .fetch_one(&pool)
+                .await
+                .expect("Count");
+        assert_eq!(invalid, 0);
+
+        sqlx::query("DELETE FROM test WHERE name = 'b'")
+            .execute(&pool)
+            .await
+            .expect("Delete");
+        crate::migrate_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
             .await
             .expect("Migrate");
 
         let alter: Vec<String> = vec![];
-
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_add_table_removing_quotes(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE "test" (id uuid)"#, &pool)
+    fn test_failed_migration_rolls_back(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id int)"#, &pool)
             .await
             .expect("Setup");
-        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+        sqlx::query("INSERT INTO test VALUES (1), (1)")
+            .execute(&pool)
+            .await
+            .expect("Insert");
+        let schema = r#"
+            CREATE TABLE test (id int, name text);
+            CREATE UNIQUE INDEX test_id_idx ON test (id);
+        "#;
+
+        let r = crate::migrate_from_string(schema, &pool).await;
+        assert!(
+            matches!(r, Err(crate::MigrationError::ExecSqlError(_))),
+            "{r:?}"
+        );
+        let m = crate::generate_migrations_from_string(schema, &pool)
             .await
             .expect("Migrate");
 
-        let alter: Vec<String> = vec![];
-
+        let alter = vec![
+            "ALTER TABLE test ADD COLUMN name TEXT",
+            "CREATE UNIQUE INDEX test_id_idx ON test(id)",
+        ];
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_add_table_adding_quotes(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
-            .await
-            .expect("Setup");
-        let m = crate::generate_migrations_from_string(r#"CREATE TABLE "test" (id uuid)"#, &pool)
+    fn test_concurrent_migrators_wait(pool: PgPool) {
+        let schema = r#"
+            CREATE TABLE test (id int);
+            CREATE INDEX test_id_idx ON test (id);
+        "#;
+
+        // Each waits for the other's advisory lock, so the second finds nothing to do
+        let migrators: Vec<_> = (0..2)
+            .map(|_| {
+                let pool = pool.clone();
+                tokio::spawn(async move { crate::migrate_from_string(schema, &pool).await })
+            })
+            .collect();
+        for migrator in migrators {
+            migrator.await.expect("Join").expect("Migrate");
+        }
+        let m = crate::generate_migrations_from_string(schema, &pool)
             .await
             .expect("Migrate");
 
         let alter: Vec<String> = vec![];
-
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_add_table_reserved_word(pool: PgPool) {
-        let m = crate::generate_migrations_from_string(r#"CREATE TABLE "user" (id uuid)"#, &pool)
-            .await
-            .expect("Migrate");
+    fn test_plan_holds_advisory_lock(pool: PgPool) {
+        let schema = r#"CREATE TABLE test (id int)"#;
+        let options = crate::MigrationOptions::default();
+        let try_lock = |pool: PgPool| async move {
+            let mut conn = pool.acquire().await.expect("Acquire");
+            let locked: bool = sqlx::query_scalar("SELECT pg_catalog.pg_try_advisory_lock($1)")
+                .bind(crate::apply::DEFAULT_ADVISORY_LOCK_KEY)
+                .fetch_one(&mut *conn)
+                .await
+                .expect("Try lock");
+            if locked {
+                crate::apply::advisory_unlock(&mut conn, crate::apply::DEFAULT_ADVISORY_LOCK_KEY)
+                    .await
+                    .expect("Unlock");
+            }
+            locked
+        };
 
-        let alter = vec![r#"CREATE TABLE "user" (id UUID)"#];
+        // Held from before the database is read until the plan is applied
+        let migration = Box::pin(crate::plan_migration("public", schema, &pool, &options))
+            .await
+            .expect("Plan");
+        assert!(!try_lock(pool.clone()).await);
+        Box::pin(migration.apply()).await.expect("Apply");
+        assert!(try_lock(pool.clone()).await);
 
-        assert_eq!(m, alter);
+        // Released when a plan is dropped without applying it
+        let migration = Box::pin(crate::plan_migration("public", schema, &pool, &options))
+            .await
+            .expect("Plan");
+        assert!(migration.steps.is_empty());
+        drop(migration);
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
     }
 
     #[sqlx::test]
-    fn test_drop_table(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+    fn test_retry_lock_timeout(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id int)"#, &pool)
             .await
             .expect("Setup");
-        let m = crate::generate_migrations_from_string(r#""#, &pool)
+        let schema = r#"CREATE TABLE test (id int, name text)"#;
+        let options = crate::MigrationOptions {
+            lock_timeout: Some(std::time::Duration::from_millis(50)),
+            lock_retry_backoff: Some(std::time::Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        let mut locker = pool.begin().await.expect("Begin");
+        sqlx::query("LOCK TABLE test")
+            .execute(&mut *locker)
             .await
-            .expect("Migrate");
+            .expect("Lock");
+        let r = crate::migrate_from_string_with_options(schema, &pool, &options).await;
+        match r {
+            Err(crate::MigrationError::ExecSqlError(sqlx::Error::Database(e))) => {
+                assert_eq!(e.code().as_deref(), Some("55P03"))
+            }
+            r => panic!("Expected lock_not_available, got {r:?}"),
+        }
+        let retrying = crate::MigrationOptions {
+            lock_retries: 1,
+            ..options.clone()
+        };
+        let r = crate::migrate_from_string_with_options(schema, &pool, &retrying).await;
+        match r {
+            Err(crate::MigrationError::LockRetriesExhausted { retries: 1, .. }) => (),
+            r => panic!("Expected the retries to run out, got {r:?}"),
+        }
 
-        let alter = vec![r#"DROP TABLE "test" CASCADE"#];
+        // Released while the migration backs off
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            locker.commit().await.expect("Commit");
+        });
+        let options = crate::MigrationOptions {
+            lock_retries: 5,
+            ..options
+        };
+        crate::migrate_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+        release.await.expect("Release");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
 
+        let alter: Vec<String> = vec![];
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_drop_table_reserved_word(pool: PgPool) {
-        let m = crate::migrate_from_string(r#"CREATE TABLE "user" ()"#, &pool)
+    fn test_record_history(pool: PgPool) {
+        let schema = r#"CREATE TABLE test (id int)"#;
+        let options = crate::MigrationOptions {
+            record_history: true,
+            application_version: Some("1.2.3".to_string()),
+            ..Default::default()
+        };
+
+        crate::migrate_from_string_with_options(schema, &pool, &options)
             .await
             .expect("Migrate");
+        // Nothing to do, so nothing is recorded
+        crate::migrate_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+        sqlx::query("INSERT INTO test VALUES (1), (1)")
+            .execute(&pool)
+            .await
+            .expect("Insert");
+        let failing = r#"
+            CREATE TABLE test (id int);
+            CREATE UNIQUE INDEX test_id_idx ON test (id);
+        "#;
+        crate::migrate_from_string_with_options(failing, &pool, &options)
+            .await
+            .expect_err("Duplicate ids");
+
+        type HistoryRow = (
+            String,
+            Option<String>,
+            Vec<String>,
+            i64,
+            String,
+            Option<String>,
+        );
+        let history: Vec<HistoryRow> = sqlx::query_as(
+            "SELECT schema_hash, application_version, statements,
+                    cardinality(durations_ms)::bigint, outcome, error
+                FROM declare_schema_history ORDER BY id",
+        )
+        .fetch_all(&pool)
+        .await
+        .expect("History");
+        let hash: String =
+            sqlx::query_scalar("SELECT encode(sha256(convert_to($1, 'UTF8')), 'hex')")
+                .bind(schema)
+                .fetch_one(&pool)
+                .await
+                .expect("Hash");
+        assert_eq!(history.len(), 2);
+        assert_eq!(
+            history[0],
+            (
+                hash,
+                Some("1.2.3".to_string()),
+                vec!["CREATE TABLE test (id INT)".to_string()],
+                1,
+                "applied".to_string(),
+                None
+            )
+        );
+        assert_eq!(
+            history[1].2,
+            vec!["CREATE UNIQUE INDEX test_id_idx ON test(id)".to_string()]
+        );
+        assert_eq!(history[1].3, 0);
+        assert_eq!(history[1].4, "failed");
+        let error = history[1].5.as_deref().expect("Error");
+        assert_eq!(
+            error,
+            r#"Problems while connecting/executing SQL: could not create unique index "test_id_idx""#
+        );
 
-        let m = crate::generate_migrations_from_string(r#""#, &pool)
+        // The history table isn't part of the schema
+        let m = crate::generate_migrations_from_string(schema, &pool)
             .await
             .expect("Migrate");
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
+    }
 
-        let alter = vec![r#"DROP TABLE "user" CASCADE"#];
+    #[sqlx::test]
+    fn test_not_converged(pool: PgPool) {
+        // Postgres stores the default as `(now() + '1 day'::interval)`
+        let schema = r#"CREATE TABLE test (a timestamptz DEFAULT now() + interval '1 day')"#;
+
+        let r = crate::migrate_from_string(schema, &pool).await;
+        match r {
+            Err(crate::MigrationError::NotConverged(residual)) => assert_eq!(
+                *residual,
+                vec!["ALTER TABLE test ALTER COLUMN a SET DEFAULT now() + INTERVAL '1 day'"]
+            ),
+            r => panic!("Expected NotConverged, got {r:?}"),
+        }
 
-        assert_eq!(m, alter);
+        // Qualified with the migrated schema, which is read back unqualified
+        sqlx::query("DROP TABLE test")
+            .execute(&pool)
+            .await
+            .expect("Drop");
+        crate::migrate_from_string(r#"CREATE TABLE public.other (id int)"#, &pool)
+            .await
+            .expect("Migrate");
     }
 
     #[sqlx::test]
-    fn test_add_primary_key_constraint(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+    fn test_unchanged_inline_constraints(pool: PgPool) {
+        let schema = r#"
+            CREATE TABLE parent (id int PRIMARY KEY);
+            CREATE TABLE test (
+                id int PRIMARY KEY,
+                parent_id int REFERENCES parent (id),
+                email text CONSTRAINT unique_email UNIQUE,
+                n int CHECK (n > 0)
+            );
+        "#;
+        crate::migrate_from_string(schema, &pool)
             .await
             .expect("Setup");
-        let m = crate::generate_migrations_from_string(
-            r#"CREATE TABLE test (id uuid, PRIMARY KEY(id))"#,
-            &pool,
-        )
-        .await
-        .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
 
-        let alter = vec![r#"ALTER TABLE test ADD PRIMARY KEY (id)"#];
+        let alter: Vec<String> = vec![];
 
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_add_foreign_key_constraint(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+    fn test_unchanged_unnamed_objects(pool: PgPool) {
+        let schema = r#"
+            CREATE TABLE parent (id int NOT NULL, PRIMARY KEY (id));
+            CREATE TABLE test (
+                id int NOT NULL,
+                parent_id int,
+                a int,
+                b int,
+                email text,
+                PRIMARY KEY (id),
+                UNIQUE (a, b),
+                CHECK (a > 0),
+                CHECK (a > b),
+                FOREIGN KEY (parent_id) REFERENCES parent (id)
+            );
+            CREATE INDEX ON test (lower(email));
+            CREATE INDEX ON test (a, b);
+        "#;
+        crate::migrate_from_string(schema, &pool)
             .await
             .expect("Setup");
-        let m = crate::generate_migrations_from_string(
-            r#"CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY(id) REFERENCES items(id))"#,
-            &pool,
-        )
-        .await
-        .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
 
-        let alter =
-            vec![r#"ALTER TABLE test ADD CONSTRAINT fk_id FOREIGN KEY (id) REFERENCES items(id)"#];
+        let alter: Vec<String> = vec![];
 
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_drop_foreign_key_constraint(pool: PgPool) {
+    fn test_unchanged_index(pool: PgPool) {
         crate::migrate_from_string(
             r#"
-                CREATE TABLE items (id uuid NOT NULL, PRIMARY KEY(id));
-                CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY(id) REFERENCES items(id))"#,
+                CREATE TABLE test (id uuid);
+                CREATE INDEX idx_id on test (id);
+
+            "#,
             &pool,
         )
         .await
         .expect("Setup");
         let m = crate::generate_migrations_from_string(
             r#"
-               CREATE TABLE items (id uuid NOT NULL, PRIMARY KEY(id));
-               CREATE TABLE test (id uuid)"#,
+                CREATE TABLE test (id uuid);
+                CREATE INDEX idx_id on public.test USING btree (id);
+            "#,
             &pool,
         )
         .await
         .expect("Migrate");
 
-        let alter = vec![r#"ALTER TABLE "test" DROP CONSTRAINT fk_id CASCADE"#];
+        let alter: Vec<String> = vec![];
 
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_same_foreign_key_constraint(pool: PgPool) {
+    fn test_unchanged_unique_index(pool: PgPool) {
         crate::migrate_from_string(
             r#"
-                CREATE TABLE items (id uuid NOT NULL, PRIMARY KEY(id));
-                CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY(id) REFERENCES items(id))"#,
+                CREATE TABLE test (id uuid, name text);
+                CREATE UNIQUE INDEX idx_id on test (id, name);
+
+            "#,
             &pool,
         )
         .await
         .expect("Setup");
         let m = crate::generate_migrations_from_string(
             r#"
-                CREATE TABLE items (id uuid NOT NULL, PRIMARY KEY(id));
-                CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY(id) REFERENCES items(id))"#,
+                CREATE TABLE test (id uuid, name text);
+                CREATE UNIQUE INDEX idx_id on public.test USING btree (id, name);
+            "#,
             &pool,
         )
         .await
@@ -1417,317 +4642,478 @@ mod test_str_to_pg {
     }
 
     #[sqlx::test]
-    fn test_modify_foreign_key_constraint(pool: PgPool) {
+    fn test_unchanged_partial_expression_index(pool: PgPool) {
+        let schema = r#"
+            CREATE TABLE test (id uuid, email text, name text, deleted_at timestamptz);
+            CREATE INDEX idx_email on test (lower(email) DESC) WHERE deleted_at IS NULL;
+            CREATE UNIQUE INDEX idx_id on test (id) INCLUDE (name);
+        "#;
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_changed_index(pool: PgPool) {
         crate::migrate_from_string(
             r#"
-                CREATE TABLE items (
-                    id uuid NOT NULL,
-                    id2 uuid NOT NULL,
-                    PRIMARY KEY(id)
-                );
-                CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY(id) REFERENCES items(id))"#,
+                CREATE TABLE test (id uuid);
+                CREATE INDEX idx_id on public.test USING BTREE (id ASC);
+
+            "#,
             &pool,
         )
         .await
         .expect("Setup");
         let maybe_err = crate::generate_migrations_from_string(
             r#"
-                CREATE TABLE items (
-                    id uuid NOT NULL,
-                    id2 uuid NOT NULL,
-                    PRIMARY KEY(id)
-                );
-                CREATE TABLE test (id uuid, CONSTRAINT fk_id FOREIGN KEY(id) REFERENCES items(id2))"#,
+                CREATE TABLE test (id uuid);
+                CREATE INDEX idx_id on public.test USING BTREE (id DESC);
+            "#,
             &pool,
         )
         .await;
 
         match maybe_err {
-            Err(MigrationError::CannotModifyTableConstraint(_, _)) => (),
+            Err(MigrationError::CannotModifyIndex(_, _)) => (),
             _ => panic!("Not the right error {maybe_err:?}"),
         }
     }
 
-    #[sqlx::test]
-    fn test_add_check_constraint(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
-            .await
-            .expect("Setup");
-        let m = crate::generate_migrations_from_string(
-            r#"CREATE TABLE test (id uuid, CONSTRAINT check_id CHECK (id = 1))"#,
-            &pool,
-        )
-        .await
-        .expect("Migrate");
+    #[sqlx::test]
+    fn test_create_extension(pool: PgPool) {
+        let m = crate::generate_migrations_from_string(r#"CREATE EXTENSION ltree;"#, &pool)
+            .await
+            .expect("Migrate");
 
-        let alter = vec![r#"ALTER TABLE test ADD CONSTRAINT check_id CHECK (id = 1)"#];
+        let alter = vec![r#"CREATE EXTENSION ltree"#];
 
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_drop_check_constraint(pool: PgPool) {
-        crate::migrate_from_string(
-            r#"CREATE TABLE test (id int, CONSTRAINT check_id CHECK (id = 1))"#,
-            &pool,
-        )
-        .await
-        .expect("Setup");
-        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id int)"#, &pool)
+    fn test_unchanged_extension(pool: PgPool) {
+        let schema = r#"CREATE EXTENSION ltree WITH SCHEMA public VERSION '1.1';"#;
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(schema, &pool)
             .await
             .expect("Migrate");
 
-        let alter = vec![r#"ALTER TABLE "test" DROP CONSTRAINT check_id CASCADE"#];
+        let alter: Vec<String> = vec![];
 
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_same_check_constraint(pool: PgPool) {
-        crate::migrate_from_string(
-            r#"CREATE TABLE test (id int, CONSTRAINT check_id CHECK (id = 1))"#,
-            &pool,
-        )
-        .await
-        .expect("Setup");
+    fn test_extension_version_without_with(pool: PgPool) {
+        let schema = r#"CREATE EXTENSION ltree VERSION '1.1';"#;
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Setup");
         let m = crate::generate_migrations_from_string(
-            r#"CREATE TABLE test (id int, CONSTRAINT check_id CHECK (id = 1))"#,
+            r#"CREATE EXTENSION ltree VERSION '1.2';"#,
             &pool,
         )
         .await
         .expect("Migrate");
 
-        let alter: Vec<&str> = vec![];
+        let alter = vec![r#"ALTER EXTENSION ltree UPDATE TO '1.2'"#];
+        assert_eq!(m, alter);
+    }
 
+    #[sqlx::test]
+    fn test_references_without_columns(pool: PgPool) {
+        let schema = r#"
+            CREATE TABLE parent (id uuid PRIMARY KEY);
+            CREATE TABLE child (id uuid PRIMARY KEY, p uuid REFERENCES parent ON DELETE CASCADE);
+        "#;
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_modify_check_constraint(pool: PgPool) {
-        crate::migrate_from_string(
-            r#"CREATE TABLE test (id int, CONSTRAINT check_id CHECK (id = 1))"#,
-            &pool,
-        )
-        .await
-        .expect("Setup");
-        let maybe_err = crate::generate_migrations_from_string(
-            r#"CREATE TABLE test (id int, CONSTRAINT check_id CHECK (id = 2))"#,
-            &pool,
-        )
-        .await;
+    fn test_serial_primary_key(pool: PgPool) {
+        let schema = r#"
+            CREATE TABLE orders (id serial PRIMARY KEY);
+            CREATE TABLE lines (id bigserial PRIMARY KEY, n smallserial);
+        "#;
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Setup");
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Migrate again");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
 
-        match maybe_err {
-            Err(MigrationError::CannotModifyTableConstraint(_, _)) => (),
-            _ => panic!("Not the right error {maybe_err:?}"),
-        }
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_add_unique_constraint(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+    fn test_update_extension_version(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE EXTENSION ltree WITH VERSION '1.1';"#, &pool)
             .await
             .expect("Setup");
         let m = crate::generate_migrations_from_string(
-            r#"CREATE TABLE test (id uuid, CONSTRAINT id_u UNIQUE (id))"#,
+            r#"CREATE EXTENSION ltree WITH VERSION '1.2';"#,
             &pool,
         )
         .await
         .expect("Migrate");
 
-        let alter = vec![r#"ALTER TABLE test ADD CONSTRAINT id_u UNIQUE (id)"#];
+        let alter = vec![r#"ALTER EXTENSION ltree UPDATE TO '1.2'"#];
 
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_drop_unique_constraint(pool: PgPool) {
-        crate::migrate_from_string(
-            r#"CREATE TABLE test (id uuid, CONSTRAINT id_u UNIQUE (id))"#,
-            &pool,
-        )
-        .await
-        .expect("Setup");
-        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id uuid)"#, &pool)
+    fn test_drop_extension(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE EXTENSION "uuid-ossp";"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(r#""#, &pool)
             .await
             .expect("Migrate");
 
-        let alter = vec![r#"ALTER TABLE "test" DROP CONSTRAINT id_u CASCADE"#];
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
 
+        let options = crate::MigrationOptions {
+            drop_extensions: true,
+            ..Default::default()
+        };
+        let m = crate::generate_migrations_from_string_with_options(r#""#, &pool, &options)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"DROP EXTENSION "uuid-ossp""#];
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_add_view(pool: PgPool) {
-        let m =
-            crate::generate_migrations_from_string(r#"CREATE VIEW "test_view" AS SELECT 1"#, &pool)
-                .await
-                .expect("Migrate");
+    fn test_extension_in_other_schema(pool: PgPool) {
+        sqlx::raw_sql("CREATE SCHEMA ext; CREATE EXTENSION ltree WITH SCHEMA ext;")
+            .execute(&pool)
+            .await
+            .expect("Setup");
+        let options = crate::MigrationOptions {
+            drop_extensions: true,
+            ..Default::default()
+        };
 
-        let alter = vec![r#"CREATE VIEW "test_view" AS SELECT 1"#];
+        // Only extensions in the migrated schema are dropped
+        let m = crate::generate_migrations_from_string_with_options(r#""#, &pool, &options)
+            .await
+            .expect("Migrate");
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
 
+        // Declared extensions are found in other schemas
+        let m = crate::generate_migrations_from_string_with_options(
+            r#"CREATE EXTENSION ltree WITH SCHEMA ext;"#,
+            &pool,
+            &options,
+        )
+        .await
+        .expect("Migrate");
         assert_eq!(m, alter);
+
+        let m = crate::generate_migrations_from_string_with_options(
+            r#"CREATE EXTENSION ltree WITH SCHEMA public;"#,
+            &pool,
+            &options,
+        )
+        .await
+        .expect("Migrate");
+        assert_eq!(m, vec![r#"ALTER EXTENSION ltree SET SCHEMA public"#]);
     }
+
     #[sqlx::test]
-    fn test_drop_view(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE VIEW "test_view" AS SELECT 1 AS a"#, &pool)
+    fn test_unchanged_schema(pool: PgPool) {
+        let schema = r#"CREATE SCHEMA billing;"#;
+        crate::migrate_from_string(schema, &pool)
             .await
             .expect("Setup");
-        println!("generate_migrattions_from_string");
-        let m = crate::generate_migrations_from_string(r#""#, &pool)
+        let m = crate::generate_migrations_from_string(schema, &pool)
             .await
             .expect("Migrate");
 
-        let alter = vec![r#"DROP VIEW "test_view" CASCADE"#];
-
+        let alter: Vec<String> = vec![];
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_alter_view(pool: PgPool) {
-        crate::migrate_from_string(r#"CREATE VIEW "test_view" AS SELECT 1 AS a"#, &pool)
+    fn test_alter_schema_owner(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE SCHEMA billing;"#, &pool)
             .await
             .expect("Setup");
-        println!("generate_migrattions_from_string");
-        let m = crate::generate_migrations_from_string(
-            r#"CREATE VIEW "test_view" AS SELECT 1 AS a, 2 as b"#,
-            &pool,
-        )
-        .await
-        .expect("Migrate");
+        let schema = r#"CREATE SCHEMA billing AUTHORIZATION pg_database_owner;"#;
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
 
-        let alter = vec![r#"CREATE OR REPLACE VIEW "test_view" AS SELECT 1 AS a, 2 AS b"#];
+        let alter = vec![r#"ALTER SCHEMA "billing" OWNER TO pg_database_owner"#];
+        assert_eq!(m, alter);
+
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
 
+        let alter: Vec<String> = vec![];
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_add_index(pool: PgPool) {
-        let m =
-            crate::generate_migrations_from_string(r#"CREATE INDEX idx_id on test (id)"#, &pool)
-                .await
-                .expect("Migrate");
+    fn test_drop_schema(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE SCHEMA billing;"#, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(r#""#, &pool)
+            .await
+            .expect("Migrate");
 
-        let alter = vec![r#"CREATE INDEX idx_id ON test(id)"#];
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
 
+        let options = crate::MigrationOptions {
+            drop_schemas: true,
+            ..Default::default()
+        };
+        let m = crate::generate_migrations_from_string_with_options(r#""#, &pool, &options)
+            .await
+            .expect("Migrate");
+
+        let alter = vec![r#"DROP SCHEMA "billing" RESTRICT"#];
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_add_index_without_name(pool: PgPool) {
+    fn test_drop_non_empty_schema(pool: PgPool) {
         crate::migrate_from_string(
-            r#"
-                CREATE TABLE test (id uuid NOT NULL);
-                CREATE INDEX idx_id on test (id);
-
-            "#,
+            r#"CREATE SCHEMA billing; CREATE TABLE billing.invoices (id int);"#,
             &pool,
         )
         .await
         .expect("Setup");
-        let maybe_err = crate::generate_migrations_from_string(
-            r#"
-                    CREATE TABLE test (id uuid);
-                    CREATE INDEX on public.test (id)
-            "#,
+
+        let options = crate::MigrationOptions {
+            drop_schemas: true,
+            ..Default::default()
+        };
+        let r = crate::generate_migrations_from_string_with_options(
+            r#"CREATE TABLE test (id int);"#,
             &pool,
+            &options,
         )
         .await;
-
-        match maybe_err {
-            Err(MigrationError::UnnamedObject(_w)) => (),
-            _ => panic!("Not the right error {maybe_err:?}"),
+        match r {
+            Err(crate::MigrationError::NonEmptySchemas(schemas)) => {
+                assert_eq!(
+                    schemas,
+                    vec![r#"schema "billing" (table billing.invoices)"#]
+                );
+            }
+            r => panic!("Expected NonEmptySchemas, got {r:?}"),
         }
+
+        let options = crate::MigrationOptions {
+            drop_schemas: true,
+            drop_non_empty_schemas: true,
+            allow_data_loss: true,
+            ..Default::default()
+        };
+        crate::migrate_from_string_with_options(r#"CREATE TABLE test (id int);"#, &pool, &options)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string(r#"CREATE TABLE test (id int);"#, &pool)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_unchanged_index(pool: PgPool) {
-        crate::migrate_from_string(
-            r#"
-                CREATE TABLE test (id uuid);
-                CREATE INDEX idx_id on test (id);
-
-            "#,
+    fn test_keep_schemas_in_use(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE SCHEMA billing;"#, &pool)
+            .await
+            .expect("Setup");
+        let options = crate::MigrationOptions {
+            drop_schemas: true,
+            drop_non_empty_schemas: true,
+            allow_data_loss: true,
+            ..Default::default()
+        };
+
+        // The migrated schema and schemas the target qualifies names with aren't dropped
+        let m = crate::generate_migrations_from_string_with_options(
+            r#"CREATE TABLE billing.invoices (id int);"#,
             &pool,
+            &options,
         )
         .await
-        .expect("Setup");
+        .expect("Migrate");
+
+        let alter = vec![r#"CREATE TABLE billing.invoices (id INT)"#];
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_add_exclusion_constraint(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE booking (during tsrange)"#, &pool)
+            .await
+            .expect("Setup");
         let m = crate::generate_migrations_from_string(
-            r#"
-                CREATE TABLE test (id uuid);
-                CREATE INDEX idx_id on public.test USING btree (id);
-            "#,
+            r#"CREATE TABLE booking (
+                during tsrange,
+                CONSTRAINT no_overlap EXCLUDE USING gist (during WITH &&)
+            )"#,
             &pool,
         )
         .await
         .expect("Migrate");
 
+        let alter = vec![
+            r#"ALTER TABLE "booking" ADD CONSTRAINT no_overlap EXCLUDE USING gist (during WITH &&)"#,
+        ];
+
+        assert_eq!(m, alter);
+    }
+
+    #[sqlx::test]
+    fn test_same_exclusion_constraint(pool: PgPool) {
+        let schema = r#"
+            CREATE EXTENSION btree_gist;
+            CREATE TABLE booking (
+                room int,
+                during tsrange,
+                CONSTRAINT no_overlap EXCLUDE USING gist (room WITH =, during WITH &&) WHERE (room > 0)
+            )"#;
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Setup");
+        let m = crate::generate_migrations_from_string(schema, &pool)
+            .await
+            .expect("Migrate");
+
         let alter: Vec<String> = vec![];
 
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_unchanged_unique_index(pool: PgPool) {
+    fn test_drop_exclusion_constraint(pool: PgPool) {
         crate::migrate_from_string(
-            r#"
-                CREATE TABLE test (id uuid, name text);
-                CREATE UNIQUE INDEX idx_id on test (id, name);
-
-            "#,
+            r#"CREATE TABLE booking (
+                during tsrange,
+                CONSTRAINT no_overlap EXCLUDE USING gist (during WITH &&)
+            )"#,
             &pool,
         )
         .await
         .expect("Setup");
         let m = crate::generate_migrations_from_string(
-            r#"
-                CREATE TABLE test (id uuid, name text);
-                CREATE UNIQUE INDEX idx_id on public.test USING btree (id, name);
-            "#,
+            r#"CREATE TABLE booking (during tsrange)"#,
             &pool,
         )
         .await
         .expect("Migrate");
 
-        let alter: Vec<String> = vec![];
+        let alter = vec![r#"ALTER TABLE "booking" DROP CONSTRAINT no_overlap CASCADE"#];
 
         assert_eq!(m, alter);
     }
 
     #[sqlx::test]
-    fn test_changed_index(pool: PgPool) {
-        crate::migrate_from_string(
-            r#"
-                CREATE TABLE test (id uuid);
-                CREATE INDEX idx_id on public.test USING BTREE (id ASC);
+    fn test_canonicalize_on_server_unchanged(pool: PgPool) {
+        let schema = r#"
+            CREATE TABLE parent (id int NOT NULL, PRIMARY KEY (id));
+            CREATE TABLE test (
+                id int NOT NULL,
+                parent_id int REFERENCES parent (id),
+                name varchar(20) DEFAULT 'foo' CHECK (upper(name) <> ''),
+                PRIMARY KEY (id)
+            );
+            CREATE INDEX ON public.test (lower(name)) WHERE name IS NOT NULL;
+            CREATE VIEW test_view AS SELECT * FROM test;
+        "#;
+        crate::migrate_from_string(schema, &pool)
+            .await
+            .expect("Setup");
 
-            "#,
-            &pool,
+        let options = crate::MigrationOptions {
+            canonicalize_on_server: true,
+            ..Default::default()
+        };
+        let m = crate::generate_migrations_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+
+        let alter: Vec<String> = vec![];
+        assert_eq!(m, alter);
+
+        let scratch_schemas: i64 = sqlx::query_scalar(
+            "SELECT count(*) FROM pg_catalog.pg_namespace WHERE nspname LIKE 'declare_schema_scratch_%'",
         )
+        .fetch_one(&pool)
         .await
-        .expect("Setup");
-        let maybe_err = crate::generate_migrations_from_string(
-            r#"
-                CREATE TABLE test (id uuid);
-                CREATE INDEX idx_id on public.test USING BTREE (id DESC);
-            "#,
-            &pool,
-        )
-        .await;
-
-        match maybe_err {
-            Err(MigrationError::CannotModifyIndex(_, _)) => (),
-            _ => panic!("Not the right error {maybe_err:?}"),
-        }
+        .expect("Count");
+        assert_eq!(scratch_schemas, 0);
     }
 
     #[sqlx::test]
-    fn test_create_extension(pool: PgPool) {
-        let m = crate::generate_migrations_from_string(r#"CREATE EXTENSION ltree;"#, &pool)
+    fn test_canonicalize_on_server_changes(pool: PgPool) {
+        crate::migrate_from_string(r#"CREATE TABLE test (id int)"#, &pool)
+            .await
+            .expect("Setup");
+
+        let options = crate::MigrationOptions {
+            canonicalize_on_server: true,
+            ..Default::default()
+        };
+        let schema = r#"
+            CREATE TABLE test (id int, name varchar(20) DEFAULT 'foo');
+            CREATE INDEX ON test (id);
+        "#;
+        let m = crate::generate_migrations_from_string_with_options(schema, &pool, &options)
             .await
             .expect("Migrate");
 
-        let alter = vec![r#"CREATE EXTENSION ltree"#];
+        let alter = vec![
+            r#"ALTER TABLE test ADD COLUMN name VARCHAR(20) DEFAULT 'foo'::CHARACTER VARYING"#,
+            r#"CREATE INDEX test_id_idx ON public.test USING BTREE (id)"#,
+        ];
+        assert_eq!(m, alter);
+
+        crate::migrate_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
+        let m = crate::generate_migrations_from_string_with_options(schema, &pool, &options)
+            .await
+            .expect("Migrate");
 
+        let alter: Vec<String> = vec![];
         assert_eq!(m, alter);
     }
 
@@ -1736,8 +5122,8 @@ mod test_str_to_pg {
         match ast {
             Statement::CreateTable(ct) => Wrapped::CreateTable(ct),
             Statement::CreateIndex(ci) => Wrapped::CreateIndex(ci),
-            Statement::CreateExtension(CreateExtension { name, .. }) => {
-                Wrapped::CreateExtension { name }
+            Statement::CreateExtension(ce) => {
+                Wrapped::try_from(Statement::CreateExtension(ce)).expect("Extension")
             }
             _ => panic!("Expected a CREATE TABLE statement"),
         }
@@ -0,0 +1,238 @@
+use crate::altertable::Step;
+use crate::ddl::PostgresStatement;
+use crate::{MigrationError, MigrationOptions};
+use sqlparser::ast::{AlterTableOperation, ObjectName, Statement};
+use sqlx::Connection;
+use std::time::{Duration, Instant};
+
+/// The advisory lock key used unless `MigrationOptions::advisory_lock_key` is set, the bytes of
+/// `"declsche"`
+pub const DEFAULT_ADVISORY_LOCK_KEY: i64 = 0x6465_636c_7363_6865;
+
+/// Wait for the session level advisory lock `key`
+pub async fn advisory_lock(c: &mut sqlx::PgConnection, key: i64) -> Result<(), MigrationError> {
+    sqlx::query!("SELECT pg_catalog.pg_advisory_lock($1)", key)
+        .execute(&mut *c)
+        .await?;
+    Ok(())
+}
+
+/// Release the advisory lock `key` taken by `advisory_lock`
+pub async fn advisory_unlock(c: &mut sqlx::PgConnection, key: i64) -> Result<(), MigrationError> {
+    sqlx::query!("SELECT pg_catalog.pg_advisory_unlock($1)", key)
+        .fetch_one(&mut *c)
+        .await?;
+    Ok(())
+}
+
+/// The `lock_timeout` unless `MigrationOptions::lock_timeout` is set
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The wait before retrying a phase that timed out on a lock, unless
+/// `MigrationOptions::lock_retry_backoff` is set. It's doubled for each retry after the first.
+pub const DEFAULT_LOCK_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Set the session's `lock_timeout`, and `statement_timeout` when it's given, from `options`
+pub async fn set_timeouts(
+    c: &mut sqlx::PgConnection,
+    options: &MigrationOptions,
+) -> Result<(), MigrationError> {
+    let lock_timeout = options.lock_timeout.unwrap_or(DEFAULT_LOCK_TIMEOUT);
+    let q = format!("SET lock_timeout TO {}", lock_timeout.as_millis());
+    sqlx::query(sqlx::AssertSqlSafe(q)).execute(&mut *c).await?;
+    if let Some(statement_timeout) = options.statement_timeout {
+        let q = format!("SET statement_timeout TO {}", statement_timeout.as_millis());
+        sqlx::query(sqlx::AssertSqlSafe(q)).execute(&mut *c).await?;
+    }
+    Ok(())
+}
+
+/// Consecutive steps of a plan that run together, in a transaction when they can
+#[derive(Clone, Debug, PartialEq)]
+pub struct Phase {
+    pub transactional: bool,
+    pub steps: Vec<Step>,
+}
+
+impl Step {
+    /// Whether Postgres can run the step in a transaction block. `CONCURRENTLY` steps can't.
+    pub fn transactional(&self) -> bool {
+        !(self.created_index().is_some_and(|ci| ci.concurrently)
+            || matches!(
+                self,
+                Step::Postgres(PostgresStatement::DropIndexConcurrently { .. })
+            ))
+    }
+
+    /// Whether the step validates a constraint, scanning the table
+    fn validates(&self) -> bool {
+        matches!(
+            self,
+            Step::Statement(Statement::AlterTable(alter_table))
+                if alter_table
+                    .operations
+                    .iter()
+                    .any(|o| matches!(o, AlterTableOperation::ValidateConstraint { .. }))
+        )
+    }
+}
+
+/// Split `steps` into phases, keeping their order. Each run of steps that can be in a transaction
+/// is one phase, and each step that can't is a phase of its own. `VALIDATE CONSTRAINT` is a phase
+/// of its own too, so the locks taken by the steps around it aren't held while it scans the table.
+pub fn phases(steps: Vec<Step>) -> Vec<Phase> {
+    let mut r: Vec<Phase> = Vec::new();
+    let mut after_validate = false;
+    for step in steps {
+        let transactional = step.transactional();
+        let validates = step.validates();
+        match r.last_mut() {
+            Some(phase)
+                if transactional && phase.transactional && !validates && !after_validate =>
+            {
+                phase.steps.push(step)
+            }
+            _ => r.push(Phase {
+                transactional,
+                steps: vec![step],
+            }),
+        }
+        after_validate = validates;
+    }
+    r
+}
+
+/// Run `steps` on `c` phase by phase. A failure rolls back the phase it happened in, while
+/// earlier phases stay committed. How long each step took is added to `durations`.
+pub async fn apply(
+    c: &mut sqlx::PgConnection,
+    steps: Vec<Step>,
+    options: &MigrationOptions,
+    durations: &mut Vec<Duration>,
+) -> Result<(), MigrationError> {
+    for phase in phases(steps) {
+        apply_phase(c, &phase, options, durations).await?;
+    }
+    Ok(())
+}
+
+/// Run `phase` on `c`, in a transaction when it can be. When a step times out waiting for a lock
+/// the phase is rolled back and run again, up to `options.lock_retries` times, waiting
+/// `options.lock_retry_backoff` before the first retry and twice as long before each one after.
+/// `durations` gets how long each step took in the last attempt. When the retries run out the
+/// error is `MigrationError::LockRetriesExhausted`.
+pub async fn apply_phase(
+    c: &mut sqlx::PgConnection,
+    phase: &Phase,
+    options: &MigrationOptions,
+    durations: &mut Vec<Duration>,
+) -> Result<(), MigrationError> {
+    let mut backoff = options
+        .lock_retry_backoff
+        .unwrap_or(DEFAULT_LOCK_RETRY_BACKOFF);
+    let mut retries = 0;
+    let earlier = durations.len();
+    loop {
+        durations.truncate(earlier);
+        match run_phase(c, phase, durations).await {
+            Err(e) if retries < options.lock_retries && lock_not_available(&e) => {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                retries += 1;
+            }
+            Err(e) if retries > 0 && lock_not_available(&e) => {
+                return Err(MigrationError::LockRetriesExhausted {
+                    retries,
+                    error: Box::new(e),
+                });
+            }
+            r => return r,
+        }
+    }
+}
+
+async fn run_phase(
+    c: &mut sqlx::PgConnection,
+    phase: &Phase,
+    durations: &mut Vec<Duration>,
+) -> Result<(), MigrationError> {
+    if phase.transactional {
+        let mut tx = c.begin().await?;
+        for s in &phase.steps {
+            #[cfg(test)]
+            println!("{:?}", s.to_string());
+            let start = Instant::now();
+            execute_step(&mut tx, s).await?;
+            durations.push(start.elapsed());
+        }
+        tx.commit().await?;
+    } else {
+        for s in &phase.steps {
+            #[cfg(test)]
+            println!("{:?}", s.to_string());
+            let start = Instant::now();
+            execute_step(c, s).await?;
+            durations.push(start.elapsed());
+        }
+    }
+    Ok(())
+}
+
+/// Whether `e` is Postgres' `lock_not_available`, raised when `lock_timeout` runs out
+fn lock_not_available(e: &MigrationError) -> bool {
+    matches!(
+        e,
+        MigrationError::ExecSqlError(sqlx::Error::Database(e)) if e.code().as_deref() == Some("55P03")
+    )
+}
+
+/// Run a single step of a migration. `CREATE INDEX CONCURRENTLY` first drops an invalid index of
+/// the same name left by an earlier failed build, and drops the index it leaves invalid when it
+/// fails.
+pub async fn execute_step(c: &mut sqlx::PgConnection, step: &Step) -> Result<(), MigrationError> {
+    let concurrent_index = match step.created_index() {
+        Some(ci) if ci.concurrently => ci
+            .name
+            .as_ref()
+            .map(|name| index_name(name, &ci.table_name)),
+        _ => None,
+    };
+    if let Some(name) = &concurrent_index {
+        drop_invalid_index(c, name).await?;
+    }
+    let safe = sqlx::AssertSqlSafe(step.to_string());
+    let r = sqlx::query(safe).execute(&mut *c).await;
+    if let (Err(_), Some(name)) = (&r, &concurrent_index) {
+        drop_invalid_index(c, name).await?;
+    }
+    r?;
+    Ok(())
+}
+
+/// The index `name` qualified with the schema of its `table`, where Postgres creates it
+fn index_name(name: &ObjectName, table: &ObjectName) -> String {
+    match (name.0.len(), table.0.split_last()) {
+        (1, Some((_, schema))) if !schema.is_empty() => {
+            format!("{}.{name}", ObjectName(schema.to_vec()))
+        }
+        _ => name.to_string(),
+    }
+}
+
+async fn drop_invalid_index(c: &mut sqlx::PgConnection, name: &str) -> Result<(), MigrationError> {
+    let invalid = sqlx::query_scalar!(
+        "SELECT i.indexrelid::regclass::text
+        FROM pg_catalog.pg_index i
+        WHERE i.indexrelid = pg_catalog.to_regclass($1)
+        AND NOT i.indisvalid",
+        name
+    )
+    .fetch_optional(&mut *c)
+    .await?
+    .flatten();
+    if let Some(invalid) = invalid {
+        let q = format!("DROP INDEX CONCURRENTLY IF EXISTS {invalid}");
+        sqlx::query(sqlx::AssertSqlSafe(q)).execute(&mut *c).await?;
+    }
+    Ok(())
+}
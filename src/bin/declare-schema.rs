@@ -1,8 +1,12 @@
 use clap::{Parser, Subcommand};
-use declare_schema::altertable::{Wrapped, from_to};
-use declare_schema::schema::app_schema;
+use declare_schema::apply::phases;
+use declare_schema::plan_migration;
+use declare_schema::safety::{Change, plan_changes, plan_locking};
+use sqlparser::ast::ObjectName;
+use sqlparser::dialect::PostgreSqlDialect;
 use std::fs;
 use std::io::{Write, stdin, stdout};
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 pub struct Args {
@@ -21,12 +25,83 @@ enum Commands {
         execute: bool,
         #[arg(long, action, default_value = "false")]
         apply_execute: bool,
+        /// Run steps that lose data, such as dropping tables and columns
+        #[arg(long, action)]
+        allow_data_loss: bool,
+        /// Refuse steps that block writes while they scan or rewrite a table larger than this many
+        /// bytes
+        #[arg(long)]
+        max_locked_table_size: Option<u64>,
+        /// Change existing tables without scanning them while writes are blocked, by adding
+        /// foreign keys and CHECK constraints NOT VALID then validating them
+        #[arg(long, action)]
+        online: bool,
+        /// Create and drop indexes CONCURRENTLY, without blocking writes
+        #[arg(long, action)]
+        concurrent_indexes: bool,
+        /// The key of the advisory lock held while executing, so other migrators wait
+        #[arg(long)]
+        advisory_lock_key: Option<i64>,
+        /// Execute without taking the advisory lock
+        #[arg(long, action)]
+        no_advisory_lock: bool,
+        /// How long a step waits for its lock, in milliseconds
+        #[arg(long)]
+        lock_timeout_ms: Option<u64>,
+        /// How long a step may run, in milliseconds
+        #[arg(long)]
+        statement_timeout_ms: Option<u64>,
+        /// How many times to retry a phase that timed out waiting for a lock
+        #[arg(long, default_value = "0")]
+        lock_retries: u32,
+        /// The wait before retrying a phase, in milliseconds, doubled for each retry
+        #[arg(long)]
+        lock_retry_backoff_ms: Option<u64>,
+        /// Record the executed plan in the declare_schema_history table
+        #[arg(long, action)]
+        record_history: bool,
+        /// The application version recorded with the plan
+        #[arg(long)]
+        application_version: Option<String>,
+        /// Drop extensions that aren't in the target schema
+        #[arg(long, action)]
+        drop_extensions: bool,
+        /// Drop publications that aren't in the target schema
+        #[arg(long, action)]
+        drop_publications: bool,
+        /// Drop schemas that aren't in the target schema, if they're empty
+        #[arg(long, action)]
+        drop_schemas: bool,
+        /// Drop schemas that aren't in the target schema along with everything in them
+        #[arg(long, action)]
+        drop_non_empty_schemas: bool,
+        /// Compare with the definitions Postgres stores for the target, by creating it in a
+        /// scratch schema that's rolled back
+        #[arg(long, action)]
+        canonicalize_on_server: bool,
+        /// A table that was renamed or moved to another schema, as `old_name=new_name`, such as
+        /// `public.orders=billing.orders`. Can be repeated.
+        #[arg(long = "moved-table", value_parser = parse_moved_table)]
+        moved_tables: Vec<(ObjectName, ObjectName)>,
         #[arg(short, long)]
         to: String,
     },
     Dump,
 }
 
+fn parse_moved_table(s: &str) -> Result<(ObjectName, ObjectName), String> {
+    let parse = |name: &str| {
+        sqlparser::parser::Parser::new(&PostgreSqlDialect {})
+            .try_with_sql(name)
+            .and_then(|mut p| p.parse_object_name(false))
+            .map_err(|e| e.to_string())
+    };
+    let (old_name, new_name) = s
+        .split_once('=')
+        .ok_or("Expected old_name=new_name".to_string())?;
+    Ok((parse(old_name)?, parse(new_name)?))
+}
+
 pub fn read_file(path: &String) -> String {
     let file_error_msg = format!("Could not read file {}", path);
     let file_contents = fs::read_to_string(path).expect(&file_error_msg);
@@ -41,10 +116,9 @@ async fn main() -> anyhow::Result<()> {
     let pg_connect_opts = sqlx::postgres::PgConnectOptions::new();
     let pool = sqlx::PgPool::connect_with(pg_connect_opts).await?;
 
-    let start_from_db = declare_schema::source_postgres::from_pool(&pool).await?;
-
     match &args.command {
         Commands::Dump => {
+            let start_from_db = declare_schema::source_postgres::from_pool(&pool).await?;
             for s in start_from_db {
                 println!("{};", s.to_string())
             }
@@ -52,21 +126,75 @@ async fn main() -> anyhow::Result<()> {
         Commands::Migrate {
             execute,
             apply_execute,
+            allow_data_loss,
+            max_locked_table_size,
+            online,
+            concurrent_indexes,
+            advisory_lock_key,
+            no_advisory_lock,
+            lock_timeout_ms,
+            statement_timeout_ms,
+            lock_retries,
+            lock_retry_backoff_ms,
+            record_history,
+            application_version,
+            drop_extensions,
+            drop_publications,
+            drop_schemas,
+            drop_non_empty_schemas,
+            canonicalize_on_server,
+            moved_tables,
             to,
         } => {
             let to_file = read_file(to);
-            let end_state = app_schema(&to_file)?;
-            let end_tables: Result<Vec<Wrapped>, declare_schema::MigrationError> = end_state
-                .clone()
-                .into_iter()
-                .map(|s| Wrapped::try_from(s))
-                .collect();
-            let end_tables = end_tables.unwrap();
-            let steps = from_to(start_from_db, end_tables)?;
-            for s in steps.clone() {
-                println!("{};", s.to_string());
+            let options = declare_schema::MigrationOptions {
+                allow_data_loss: *allow_data_loss,
+                max_locked_table_size: *max_locked_table_size,
+                online: *online,
+                concurrent_indexes: *concurrent_indexes,
+                advisory_lock_key: *advisory_lock_key,
+                no_advisory_lock: *no_advisory_lock,
+                lock_timeout: lock_timeout_ms.map(Duration::from_millis),
+                statement_timeout: statement_timeout_ms.map(Duration::from_millis),
+                lock_retries: *lock_retries,
+                lock_retry_backoff: lock_retry_backoff_ms.map(Duration::from_millis),
+                record_history: *record_history,
+                application_version: application_version.clone(),
+                drop_extensions: *drop_extensions,
+                drop_publications: *drop_publications,
+                drop_schemas: *drop_schemas || *drop_non_empty_schemas,
+                drop_non_empty_schemas: *drop_non_empty_schemas,
+                canonicalize_on_server: *canonicalize_on_server,
+                moved_tables: moved_tables.clone(),
+            };
+            let schema: String = sqlx::query_scalar("SELECT current_schema()::text")
+                .fetch_one(&pool)
+                .await?;
+            // Planned while holding the advisory lock, which dropping the migration releases
+            let migration = plan_migration(&schema, &to_file, &pool, &options).await?;
+            let steps = &migration.steps;
+            let mut annotations = plan_changes(steps).into_iter().zip(plan_locking(steps));
+            for phase in phases(steps.clone()) {
+                match phase.transactional {
+                    true => println!("-- In a transaction:"),
+                    false => println!("-- Outside a transaction:"),
+                }
+                for (s, (change, locking)) in phase.steps.iter().zip(&mut annotations) {
+                    match change {
+                        Change::Safe => (),
+                        Change::DataLoss => {
+                            println!("-- data loss: {}", s.data_lost().join(", "))
+                        }
+                        change => println!("-- {change}"),
+                    }
+                    if let Some(locking) = locking {
+                        println!("-- {locking}");
+                    }
+                    println!("{};", s.to_string());
+                }
             }
             if execute.to_owned() {
+                declare_schema::safety::check_data_loss(steps, &options)?;
                 if !apply_execute.to_owned() {
                     println!("Apply? (y/N)");
                     let mut input = String::new();
@@ -81,16 +209,8 @@ async fn main() -> anyhow::Result<()> {
                 }
                 println!("Executing!");
 
-                let mut conn = pool.acquire().await?;
-                sqlx::query("SET lock_timeout TO 5000")
-                    .execute(&mut *conn)
-                    .await?;
-                for s in steps {
-                    println!("Executing statement: {}", s);
-                    let safe = sqlx::AssertSqlSafe(s.to_string());
-                    sqlx::query(safe).execute(&mut *conn).await?;
-                    println!("Executed.");
-                }
+                migration.apply().await?;
+                println!("Executed.");
             }
         }
     }
@@ -0,0 +1,799 @@
+use sqlparser::ast::{
+    ColumnOption, ConstraintCharacteristics, CreateIndex, CreateTable, DeferrableInitial, Expr,
+    Ident, ObjectName, Statement, Value,
+};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::keywords::Keyword;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::Token;
+use std::fmt::Display;
+
+/// Postgres statements that sqlparser can't represent
+#[derive(Clone, Debug, PartialEq)]
+pub enum PostgresStatement {
+    /// `ALTER TABLE <table> ADD CONSTRAINT <name> EXCLUDE ...`
+    AddExclusionConstraint {
+        table: ObjectName,
+        constraint: ExclusionConstraint,
+    },
+    /// `ALTER TABLE <table> ALTER CONSTRAINT <name> <characteristics>`
+    AlterConstraint {
+        table: ObjectName,
+        name: Ident,
+        characteristics: ConstraintCharacteristics,
+    },
+    /// `ALTER TABLE <table> ALTER COLUMN <column> SET <setting>`
+    AlterColumnSet {
+        table: ObjectName,
+        column: Ident,
+        setting: ColumnSetting,
+    },
+    /// `CREATE STATISTICS ...`
+    CreateStatistics(CreateStatistics),
+    /// `ALTER STATISTICS <name> SET STATISTICS <target>`
+    AlterStatistics { name: ObjectName, target: u64 },
+    /// `DROP STATISTICS <name>`
+    DropStatistics { name: ObjectName },
+    /// `DROP INDEX CONCURRENTLY <name>`, which sqlparser can't display
+    DropIndexConcurrently { name: ObjectName },
+    /// `CREATE PUBLICATION ...`
+    CreatePublication(CreatePublication),
+    /// `ALTER PUBLICATION <name> <operation>`
+    AlterPublication {
+        name: Ident,
+        operation: AlterPublicationOperation,
+    },
+    /// `DROP PUBLICATION <name>`
+    DropPublication { name: Ident },
+    /// `ALTER TABLE <table> SET SCHEMA <schema>`
+    AlterTableSetSchema { table: ObjectName, schema: Ident },
+    /// `ALTER { TABLE | INDEX } <name> <operation>`
+    AlterStorage {
+        kind: RelationKind,
+        name: ObjectName,
+        operation: StorageOperation,
+    },
+    /// `ALTER EXTENSION <name> <operation>`
+    AlterExtension {
+        name: Ident,
+        operation: AlterExtensionOperation,
+    },
+    /// `CREATE [ UNLOGGED ] TABLE ... [ WITH ( ... ) ] [ TABLESPACE <name> ]`
+    CreateTable {
+        table: Box<CreateTable>,
+        storage: Storage,
+    },
+    /// `CREATE INDEX ... [ WITH ( ... ) ] [ TABLESPACE <name> ] [ WHERE <predicate> ]`
+    CreateIndex {
+        index: Box<CreateIndex>,
+        storage: Storage,
+    },
+}
+
+impl Display for PostgresStatement {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PostgresStatement::AddExclusionConstraint { table, constraint } => {
+                write!(f, "ALTER TABLE {table} ADD {constraint}")
+            }
+            PostgresStatement::AlterConstraint {
+                table,
+                name,
+                characteristics,
+            } => {
+                write!(
+                    f,
+                    "ALTER TABLE {table} ALTER CONSTRAINT {name} {characteristics}"
+                )
+            }
+            PostgresStatement::AlterColumnSet {
+                table,
+                column,
+                setting,
+            } => write!(f, "ALTER TABLE {table} ALTER COLUMN {column} SET {setting}"),
+            PostgresStatement::CreateStatistics(statistics) => write!(f, "{statistics}"),
+            PostgresStatement::AlterStatistics { name, target } => {
+                write!(f, "ALTER STATISTICS {name} SET STATISTICS {target}")
+            }
+            PostgresStatement::DropStatistics { name } => write!(f, "DROP STATISTICS {name}"),
+            PostgresStatement::DropIndexConcurrently { name } => {
+                write!(f, "DROP INDEX CONCURRENTLY {name}")
+            }
+            PostgresStatement::CreatePublication(publication) => write!(f, "{publication}"),
+            PostgresStatement::AlterPublication { name, operation } => {
+                write!(f, "ALTER PUBLICATION {name} {operation}")
+            }
+            PostgresStatement::DropPublication { name } => write!(f, "DROP PUBLICATION {name}"),
+            PostgresStatement::AlterTableSetSchema { table, schema } => {
+                write!(f, "ALTER TABLE {table} SET SCHEMA {schema}")
+            }
+            PostgresStatement::AlterStorage {
+                kind,
+                name,
+                operation,
+            } => write!(f, "ALTER {kind} {name} {operation}"),
+            PostgresStatement::AlterExtension { name, operation } => {
+                write!(f, "ALTER EXTENSION {name} {operation}")
+            }
+            PostgresStatement::CreateTable { table, storage } => {
+                let create = Statement::CreateTable(*table.clone()).to_string();
+                let create = match storage.unlogged {
+                    true => create.replacen("CREATE TABLE", "CREATE UNLOGGED TABLE", 1),
+                    false => create,
+                };
+                write!(f, "{create}{}", storage.clauses())
+            }
+            PostgresStatement::CreateIndex { index, storage } => {
+                // Storage comes before the predicate
+                let mut without_predicate = *index.clone();
+                let predicate = without_predicate.predicate.take();
+                write!(
+                    f,
+                    "{}{}",
+                    Statement::CreateIndex(without_predicate),
+                    storage.clauses()
+                )?;
+                if let Some(predicate) = predicate {
+                    write!(f, " WHERE {predicate}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AlterExtensionOperation {
+    /// `UPDATE TO '<version>'`
+    UpdateTo(String),
+    /// `SET SCHEMA <schema>`
+    SetSchema(Ident),
+}
+
+impl Display for AlterExtensionOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AlterExtensionOperation::UpdateTo(version) => {
+                write!(
+                    f,
+                    "UPDATE TO {}",
+                    Value::SingleQuotedString(version.clone())
+                )
+            }
+            AlterExtensionOperation::SetSchema(schema) => write!(f, "SET SCHEMA {schema}"),
+        }
+    }
+}
+
+/// Column settings Postgres keeps in `pg_attribute`. sqlparser has no column option for them, so
+/// they're kept on the column as a `ColumnOption::DialectSpecific`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ColumnSetting {
+    /// `STORAGE { PLAIN | EXTERNAL | EXTENDED | MAIN }`
+    Storage(String),
+    /// `COMPRESSION <method>`
+    Compression(String),
+    /// `STATISTICS <target>`
+    Statistics(u64),
+}
+
+impl ColumnSetting {
+    pub fn parse(parser: &mut Parser) -> Result<Self, ParserError> {
+        if parser.parse_keyword(Keyword::STORAGE) {
+            let storage = parser.expect_one_of_keywords(&[
+                Keyword::PLAIN,
+                Keyword::EXTERNAL,
+                Keyword::EXTENDED,
+                Keyword::MAIN,
+            ])?;
+            Ok(ColumnSetting::Storage(format!("{storage:?}")))
+        } else if parser.parse_keyword(Keyword::COMPRESSION) {
+            let method = parser.parse_identifier()?;
+            Ok(ColumnSetting::Compression(method.value.to_lowercase()))
+        } else if parser.parse_keyword(Keyword::STATISTICS) {
+            Ok(ColumnSetting::Statistics(parser.parse_literal_uint()?))
+        } else {
+            parser.expected("STORAGE, COMPRESSION or STATISTICS", parser.peek_token())
+        }
+    }
+
+    /// The setting kept in `option`, if it is one
+    pub fn from_option(option: &ColumnOption) -> Option<Self> {
+        let ColumnOption::DialectSpecific(tokens) = option else {
+            return None;
+        };
+        let dialect = PostgreSqlDialect {};
+        let mut parser = Parser::new(&dialect).with_tokens(tokens.clone());
+        ColumnSetting::parse(&mut parser).ok()
+    }
+
+    pub fn option(&self) -> ColumnOption {
+        let value = match self {
+            ColumnSetting::Storage(storage) => Token::make_keyword(storage),
+            ColumnSetting::Compression(method) => Token::make_word(method, None),
+            ColumnSetting::Statistics(target) => Token::Number(target.to_string(), false),
+        };
+        ColumnOption::DialectSpecific(vec![Token::make_keyword(self.keyword()), value])
+    }
+
+    pub fn same_kind(&self, other: &ColumnSetting) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    fn keyword(&self) -> &'static str {
+        match self {
+            ColumnSetting::Storage(_) => "STORAGE",
+            ColumnSetting::Compression(_) => "COMPRESSION",
+            ColumnSetting::Statistics(_) => "STATISTICS",
+        }
+    }
+}
+
+impl Display for ColumnSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ColumnSetting::Storage(storage) => write!(f, "STORAGE {storage}"),
+            ColumnSetting::Compression(method) => write!(f, "COMPRESSION {method}"),
+            ColumnSetting::Statistics(target) => write!(f, "STATISTICS {target}"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RelationKind {
+    Table,
+    Index,
+}
+
+impl Display for RelationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RelationKind::Table => write!(f, "TABLE"),
+            RelationKind::Index => write!(f, "INDEX"),
+        }
+    }
+}
+
+/// How a table or index is stored: `UNLOGGED`, `WITH ( <parameter> = <value> [, ...] )` and
+/// `TABLESPACE <name>`. Parameter values are kept as the text Postgres stores in `reloptions`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Storage {
+    pub unlogged: bool,
+    pub parameters: Vec<(String, String)>,
+    pub tablespace: Option<Ident>,
+}
+
+impl Storage {
+    /// Parse `( <parameter> [ = <value> ] [, ...] )`, a parameter without a value is `true`
+    pub fn parse_parameters(parser: &mut Parser) -> Result<Vec<(String, String)>, ParserError> {
+        parser.expect_token(&Token::LParen)?;
+        let parameters = parser.parse_comma_separated(|p| {
+            // Parameters can be namespaced, such as `toast.autovacuum_enabled`
+            let mut name = p.parse_identifier()?.value.to_lowercase();
+            while p.consume_token(&Token::Period) {
+                name.push('.');
+                name.push_str(&p.parse_identifier()?.value.to_lowercase());
+            }
+            if !p.consume_token(&Token::Eq) {
+                return Ok((name, "true".to_string()));
+            }
+            let value = match p.next_token().token {
+                Token::Minus => match p.next_token().token {
+                    Token::Number(n, _) => format!("-{n}"),
+                    _ => return p.expected("a number", p.peek_token()),
+                },
+                Token::Number(n, _) => n,
+                Token::SingleQuotedString(s) => s,
+                Token::Word(w) => w.value,
+                _ => return p.expected("a parameter value", p.peek_token()),
+            };
+            Ok((name, value))
+        })?;
+        parser.expect_token(&Token::RParen)?;
+        Ok(parameters)
+    }
+
+    /// ` WITH ( ... )` and ` TABLESPACE <name>`, as they're written when creating a relation
+    fn clauses(&self) -> String {
+        let mut r = String::new();
+        if !self.parameters.is_empty() {
+            r.push_str(&format!(" WITH ({})", parameter_list(&self.parameters)));
+        }
+        if let Some(tablespace) = &self.tablespace {
+            r.push_str(&format!(" TABLESPACE {tablespace}"));
+        }
+        r
+    }
+
+    /// The operations to change `from` to `self`
+    pub fn operations(&self, from: &Storage, kind: RelationKind) -> Vec<StorageOperation> {
+        let mut r = Vec::new();
+        if kind == RelationKind::Table && self.unlogged != from.unlogged {
+            r.push(match self.unlogged {
+                true => StorageOperation::SetUnlogged,
+                false => StorageOperation::SetLogged,
+            });
+        }
+        let set: Vec<(String, String)> = self
+            .parameters
+            .iter()
+            .filter(|p| !from.parameters.contains(p))
+            .cloned()
+            .collect();
+        if !set.is_empty() {
+            r.push(StorageOperation::Set(set));
+        }
+        let reset: Vec<String> = from
+            .parameters
+            .iter()
+            .filter(|(name, _)| !self.parameters.iter().any(|(n, _)| n == name))
+            .map(|(name, _)| name.clone())
+            .collect();
+        if !reset.is_empty() {
+            r.push(StorageOperation::Reset(reset));
+        }
+        let tablespace = |s: &Storage| s.tablespace.as_ref().map(|t| t.value.to_lowercase());
+        if tablespace(self) != tablespace(from) {
+            r.push(StorageOperation::SetTablespace(
+                self.tablespace
+                    .clone()
+                    .unwrap_or_else(|| Ident::new("pg_default")),
+            ));
+        }
+        r
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StorageOperation {
+    /// `SET LOGGED`
+    SetLogged,
+    /// `SET UNLOGGED`
+    SetUnlogged,
+    /// `SET ( <parameter> = <value> [, ...] )`
+    Set(Vec<(String, String)>),
+    /// `RESET ( <parameter> [, ...] )`
+    Reset(Vec<String>),
+    /// `SET TABLESPACE <name>`
+    SetTablespace(Ident),
+}
+
+impl Display for StorageOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StorageOperation::SetLogged => write!(f, "SET LOGGED"),
+            StorageOperation::SetUnlogged => write!(f, "SET UNLOGGED"),
+            StorageOperation::Set(parameters) => {
+                write!(f, "SET ({})", parameter_list(parameters))
+            }
+            StorageOperation::Reset(names) => write!(f, "RESET ({})", names.join(", ")),
+            StorageOperation::SetTablespace(tablespace) => {
+                write!(f, "SET TABLESPACE {tablespace}")
+            }
+        }
+    }
+}
+
+/// Like `Parser::parse_keyword`, for words sqlparser doesn't have a keyword for
+pub(crate) fn parse_word(parser: &mut Parser, word: &str) -> bool {
+    match parser.peek_token().token {
+        Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(word) => {
+            parser.next_token();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// `<parameter> = <value> [, ...]`
+fn parameter_list(parameters: &[(String, String)]) -> String {
+    let parameters: Vec<String> = parameters
+        .iter()
+        .map(|(name, value)| {
+            // Numbers and words can be written bare, anything else is a string
+            let bare = value.trim_start_matches('-');
+            if !bare.is_empty()
+                && bare
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+            {
+                format!("{name} = {value}")
+            } else {
+                format!("{name} = {}", Value::SingleQuotedString(value.clone()))
+            }
+        })
+        .collect();
+    parameters.join(", ")
+}
+
+/// `CREATE PUBLICATION <name> [ FOR ALL TABLES | FOR TABLE <table> [, ...] ]
+/// [ WITH ( <parameter> = <value> [, ...] ) ]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CreatePublication {
+    pub name: Ident,
+    pub all_tables: bool,
+    pub tables: Vec<ObjectName>,
+    pub parameters: Vec<(String, String)>,
+}
+
+impl CreatePublication {
+    pub fn parse(parser: &mut Parser) -> Result<CreatePublication, ParserError> {
+        parser.expect_keyword_is(Keyword::CREATE)?;
+        if !parse_word(parser, "PUBLICATION") {
+            return parser.expected("PUBLICATION", parser.peek_token());
+        }
+        let name = parser.parse_identifier()?;
+        let mut all_tables = false;
+        let mut tables = vec![];
+        if parser.parse_keyword(Keyword::FOR) {
+            if parser.parse_keywords(&[Keyword::ALL, Keyword::TABLES]) {
+                all_tables = true;
+            } else {
+                parser.expect_keyword_is(Keyword::TABLE)?;
+                tables = parser.parse_comma_separated(CreatePublication::parse_table)?;
+            }
+        }
+        let parameters = if parser.parse_keyword(Keyword::WITH) {
+            Storage::parse_parameters(parser)?
+        } else {
+            vec![]
+        };
+
+        Ok(CreatePublication {
+            name,
+            all_tables,
+            tables,
+            parameters,
+        })
+    }
+
+    /// `[ ONLY ] <table>`, column lists and row filters aren't supported
+    pub fn parse_table(parser: &mut Parser) -> Result<ObjectName, ParserError> {
+        let _ = parser.parse_keyword(Keyword::ONLY);
+        parser.parse_object_name(false)
+    }
+
+    /// Parameters are only compared when they're specified. `publish` is a list of operations
+    /// in any order.
+    pub fn changed_parameters(&self, from: &CreatePublication) -> Vec<(String, String)> {
+        let normalize = |name: &str, value: &str| {
+            let mut parts: Vec<String> =
+                value.split(',').map(|p| p.trim().to_lowercase()).collect();
+            if name == "publish" {
+                parts.sort();
+            }
+            parts
+        };
+        self.parameters
+            .iter()
+            .filter(|(name, value)| {
+                !from
+                    .parameters
+                    .iter()
+                    .any(|(n, v)| n == name && normalize(n, v) == normalize(name, value))
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+impl Display for CreatePublication {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "CREATE PUBLICATION {}", self.name)?;
+        if self.all_tables {
+            write!(f, " FOR ALL TABLES")?;
+        } else if !self.tables.is_empty() {
+            let tables: Vec<String> = self.tables.iter().map(|t| t.to_string()).collect();
+            write!(f, " FOR TABLE {}", tables.join(", "))?;
+        }
+        if !self.parameters.is_empty() {
+            write!(f, " WITH ({})", parameter_list(&self.parameters))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AlterPublicationOperation {
+    /// `ADD TABLE <table> [, ...]`
+    AddTables(Vec<ObjectName>),
+    /// `DROP TABLE <table> [, ...]`
+    DropTables(Vec<ObjectName>),
+    /// `SET ( <parameter> = <value> [, ...] )`
+    Set(Vec<(String, String)>),
+}
+
+impl Display for AlterPublicationOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let tables = |tables: &[ObjectName]| {
+            let tables: Vec<String> = tables.iter().map(|t| t.to_string()).collect();
+            tables.join(", ")
+        };
+        match self {
+            AlterPublicationOperation::AddTables(t) => write!(f, "ADD TABLE {}", tables(t)),
+            AlterPublicationOperation::DropTables(t) => write!(f, "DROP TABLE {}", tables(t)),
+            AlterPublicationOperation::Set(parameters) => {
+                write!(f, "SET ({})", parameter_list(parameters))
+            }
+        }
+    }
+}
+
+/// `[ CONSTRAINT <name> ] EXCLUDE [ USING <method> ] ( <element> WITH <operator> [, ...] )
+/// [ INCLUDE ( <column> [, ...] ) ] [ WHERE ( <predicate> ) ] [ DEFERRABLE ... ]`
+#[derive(Clone, Debug)]
+pub struct ExclusionConstraint {
+    pub name: Option<Ident>,
+    pub using: Option<Ident>,
+    pub elements: Vec<ExclusionElement>,
+    pub include: Vec<Ident>,
+    pub predicate: Option<Expr>,
+    pub characteristics: Option<ConstraintCharacteristics>,
+}
+
+/// A single `<expr> [ <opclass> ] [ ASC | DESC ] [ NULLS { FIRST | LAST } ] WITH <operator>`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExclusionElement {
+    pub expr: Expr,
+    pub opclass: Option<ObjectName>,
+    pub asc: Option<bool>,
+    pub nulls_first: Option<bool>,
+    pub operator: String,
+}
+
+impl ExclusionConstraint {
+    /// Parse an exclusion constraint, including an optional leading `CONSTRAINT <name>`
+    pub fn parse(parser: &mut Parser) -> Result<ExclusionConstraint, ParserError> {
+        let name = if parser.parse_keyword(Keyword::CONSTRAINT) {
+            Some(parser.parse_identifier()?)
+        } else {
+            None
+        };
+        parser.expect_keyword_is(Keyword::EXCLUDE)?;
+        let using = if parser.parse_keyword(Keyword::USING) {
+            Some(parser.parse_identifier()?)
+        } else {
+            None
+        };
+        parser.expect_token(&Token::LParen)?;
+        let elements = parser.parse_comma_separated(ExclusionElement::parse)?;
+        parser.expect_token(&Token::RParen)?;
+        let include = if parser.parse_keyword(Keyword::INCLUDE) {
+            parser.expect_token(&Token::LParen)?;
+            let columns = parser.parse_comma_separated(|p| p.parse_identifier())?;
+            parser.expect_token(&Token::RParen)?;
+            columns
+        } else {
+            vec![]
+        };
+        let predicate = if parser.parse_keyword(Keyword::WHERE) {
+            parser.expect_token(&Token::LParen)?;
+            let expr = parser.parse_expr()?;
+            parser.expect_token(&Token::RParen)?;
+            Some(expr)
+        } else {
+            None
+        };
+        let characteristics = parser.parse_constraint_characteristics()?;
+
+        Ok(ExclusionConstraint {
+            name,
+            using,
+            elements,
+            include,
+            predicate,
+            characteristics,
+        })
+    }
+
+    fn method(&self) -> String {
+        self.using
+            .as_ref()
+            .map(|m| m.value.to_lowercase())
+            .unwrap_or("btree".to_string())
+    }
+}
+
+/// Constraints are compared by how Postgres would store them, so an omitted `USING` matches
+/// `USING btree` and an omitted `NOT DEFERRABLE` matches an explicit one.
+impl PartialEq for ExclusionConstraint {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.method() == other.method()
+            && self.elements == other.elements
+            && self.include == other.include
+            && self.predicate == other.predicate
+            && characteristics_equal(&self.characteristics, &other.characteristics)
+    }
+}
+
+impl Display for ExclusionConstraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "CONSTRAINT {name} ")?;
+        }
+        write!(f, "EXCLUDE")?;
+        if let Some(using) = &self.using {
+            write!(f, " USING {using}")?;
+        }
+        let elements: Vec<String> = self.elements.iter().map(|e| e.to_string()).collect();
+        write!(f, " ({})", elements.join(", "))?;
+        if !self.include.is_empty() {
+            let include: Vec<String> = self.include.iter().map(|i| i.to_string()).collect();
+            write!(f, " INCLUDE ({})", include.join(", "))?;
+        }
+        if let Some(predicate) = &self.predicate {
+            write!(f, " WHERE ({predicate})")?;
+        }
+        if let Some(characteristics) = &self.characteristics {
+            write!(f, " {characteristics}")?;
+        }
+        Ok(())
+    }
+}
+
+impl ExclusionElement {
+    fn parse(parser: &mut Parser) -> Result<ExclusionElement, ParserError> {
+        let expr = parser.parse_expr()?;
+        let opclass = match parser.peek_token().token {
+            Token::Word(w)
+                if !matches!(
+                    w.keyword,
+                    Keyword::WITH | Keyword::ASC | Keyword::DESC | Keyword::NULLS
+                ) =>
+            {
+                Some(parser.parse_object_name(false)?)
+            }
+            _ => None,
+        };
+        let asc = if parser.parse_keyword(Keyword::ASC) {
+            Some(true)
+        } else if parser.parse_keyword(Keyword::DESC) {
+            Some(false)
+        } else {
+            None
+        };
+        let nulls_first = if parser.parse_keywords(&[Keyword::NULLS, Keyword::FIRST]) {
+            Some(true)
+        } else if parser.parse_keywords(&[Keyword::NULLS, Keyword::LAST]) {
+            Some(false)
+        } else {
+            None
+        };
+        parser.expect_keyword_is(Keyword::WITH)?;
+
+        // Operators are kept as written, they may be a single token such as `&&` or a
+        // qualified `OPERATOR(pg_catalog.=)`
+        let mut operator = String::new();
+        let mut depth = 0;
+        loop {
+            match parser.peek_token().token {
+                Token::EOF => break,
+                Token::Comma | Token::RParen if depth == 0 => break,
+                Token::LParen => depth += 1,
+                Token::RParen => depth -= 1,
+                _ => (),
+            }
+            operator.push_str(&parser.next_token().token.to_string());
+        }
+        if operator.is_empty() {
+            return parser.expected("an operator", parser.peek_token());
+        }
+
+        Ok(ExclusionElement {
+            expr,
+            opclass,
+            asc,
+            nulls_first,
+            operator,
+        })
+    }
+}
+
+impl Display for ExclusionElement {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.expr)?;
+        if let Some(opclass) = &self.opclass {
+            write!(f, " {opclass}")?;
+        }
+        match self.asc {
+            Some(true) => write!(f, " ASC")?,
+            Some(false) => write!(f, " DESC")?,
+            None => (),
+        }
+        match self.nulls_first {
+            Some(true) => write!(f, " NULLS FIRST")?,
+            Some(false) => write!(f, " NULLS LAST")?,
+            None => (),
+        }
+        write!(f, " WITH {}", self.operator)
+    }
+}
+
+/// `CREATE STATISTICS <name> [ ( <kind> [, ...] ) ] ON <expr> [, ...] FROM <table>`
+#[derive(Clone, Debug)]
+pub struct CreateStatistics {
+    pub name: ObjectName,
+    pub kinds: Vec<Ident>,
+    pub expressions: Vec<Expr>,
+    pub table: ObjectName,
+}
+
+impl CreateStatistics {
+    pub fn parse(parser: &mut Parser) -> Result<CreateStatistics, ParserError> {
+        parser.expect_keywords(&[Keyword::CREATE, Keyword::STATISTICS])?;
+        let _ = parser.parse_keywords(&[Keyword::IF, Keyword::NOT, Keyword::EXISTS]);
+        let name = parser.parse_object_name(false)?;
+        let kinds = if parser.consume_token(&Token::LParen) {
+            let kinds = parser.parse_comma_separated(|p| p.parse_identifier())?;
+            parser.expect_token(&Token::RParen)?;
+            kinds
+        } else {
+            vec![]
+        };
+        parser.expect_keyword_is(Keyword::ON)?;
+        let expressions = parser.parse_comma_separated(|p| p.parse_expr())?;
+        parser.expect_keyword_is(Keyword::FROM)?;
+        let table = parser.parse_object_name(false)?;
+
+        Ok(CreateStatistics {
+            name,
+            kinds,
+            expressions,
+            table,
+        })
+    }
+
+    /// The kinds Postgres builds. Without any given that's all of them, except for statistics on
+    /// a single expression which don't have a kind.
+    fn built_kinds(&self) -> Vec<String> {
+        let mut kinds: Vec<String> = self.kinds.iter().map(|k| k.value.to_lowercase()).collect();
+        if kinds.is_empty() && self.expressions.len() > 1 {
+            kinds = vec!["ndistinct".into(), "dependencies".into(), "mcv".into()];
+        }
+        kinds.sort();
+        kinds
+    }
+}
+
+/// Statistics are compared by how Postgres would store them. Names qualified with the migrated
+/// schema are unqualified when they're read, so names in other schemas stay distinct.
+impl PartialEq for CreateStatistics {
+    fn eq(&self, other: &Self) -> bool {
+        let expressions =
+            |s: &Self| -> Vec<Expr> { s.expressions.iter().map(crate::normalize::expr).collect() };
+        crate::normalize::object_name(&self.name) == crate::normalize::object_name(&other.name)
+            && self.built_kinds() == other.built_kinds()
+            && expressions(self) == expressions(other)
+            && crate::normalize::object_name(&self.table)
+                == crate::normalize::object_name(&other.table)
+    }
+}
+
+impl Display for CreateStatistics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "CREATE STATISTICS {}", self.name)?;
+        if !self.kinds.is_empty() {
+            let kinds: Vec<String> = self.kinds.iter().map(|k| k.to_string()).collect();
+            write!(f, " ({})", kinds.join(", "))?;
+        }
+        let expressions: Vec<String> = self.expressions.iter().map(|e| e.to_string()).collect();
+        write!(f, " ON {} FROM {}", expressions.join(", "), self.table)
+    }
+}
+
+/// Compare constraint characteristics treating unset values as the Postgres defaults
+pub(crate) fn characteristics_equal(
+    a: &Option<ConstraintCharacteristics>,
+    b: &Option<ConstraintCharacteristics>,
+) -> bool {
+    let normalize = |c: &Option<ConstraintCharacteristics>| {
+        let c = c.unwrap_or_default();
+        (
+            c.deferrable.unwrap_or(false),
+            c.initially.unwrap_or(DeferrableInitial::Immediate),
+            c.enforced.unwrap_or(true),
+        )
+    };
+    normalize(a) == normalize(b)
+}
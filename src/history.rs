@@ -0,0 +1,67 @@
+use crate::altertable::Step;
+use crate::{MigrationError, MigrationOptions};
+use std::time::Duration;
+
+/// The table applied plans are recorded in, in the migrated schema. It's left out when reading
+/// the schema, so it's never part of a plan.
+pub const HISTORY_TABLE: &str = "declare_schema_history";
+
+/// Record a plan applied on `c` in `HISTORY_TABLE`, creating it in the current schema when it
+/// doesn't exist. `durations` are of the steps that ran, and `result` is the outcome of applying
+/// them.
+pub async fn record(
+    c: &mut sqlx::PgConnection,
+    to_src: &str,
+    steps: &[Step],
+    durations: &[Duration],
+    result: &Result<(), MigrationError>,
+    options: &MigrationOptions,
+) -> Result<(), MigrationError> {
+    let q = format!(
+        "CREATE TABLE IF NOT EXISTS {HISTORY_TABLE} (
+            id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+            applied_at timestamptz NOT NULL DEFAULT now(),
+            schema_hash text NOT NULL,
+            application_version text,
+            statements text[] NOT NULL,
+            durations_ms bigint[] NOT NULL,
+            outcome text NOT NULL,
+            error text
+        )"
+    );
+    sqlx::query(sqlx::AssertSqlSafe(q)).execute(&mut *c).await?;
+
+    let statements: Vec<String> = steps.iter().map(|s| s.to_string()).collect();
+    let durations: Vec<i64> = durations.iter().map(|d| d.as_millis() as i64).collect();
+    let (outcome, error) = match result {
+        Ok(()) => ("applied", None),
+        Err(e) => match database_message(e) {
+            Some(message) => ("failed", Some(format!("{e}: {message}"))),
+            None => ("failed", Some(e.to_string())),
+        },
+    };
+    let q = format!(
+        "INSERT INTO {HISTORY_TABLE}
+            (schema_hash, application_version, statements, durations_ms, outcome, error)
+        VALUES (encode(sha256(convert_to($1, 'UTF8')), 'hex'), $2, $3, $4, $5, $6)"
+    );
+    sqlx::query(sqlx::AssertSqlSafe(q))
+        .bind(to_src)
+        .bind(&options.application_version)
+        .bind(statements)
+        .bind(durations)
+        .bind(outcome)
+        .bind(error)
+        .execute(&mut *c)
+        .await?;
+    Ok(())
+}
+
+/// The message Postgres returned for the statement that failed, which `e` doesn't display
+fn database_message(e: &MigrationError) -> Option<String> {
+    match e {
+        MigrationError::ExecSqlError(sqlx::Error::Database(e)) => Some(e.message().to_string()),
+        MigrationError::LockRetriesExhausted { error, .. } => database_message(error),
+        _ => None,
+    }
+}
@@ -20,13 +20,25 @@
 
 /// Diff'ing of ASTs and statement generation
 pub mod altertable;
+/// Execution of planned steps in transactions
+pub mod apply;
+/// Postgres DDL that sqlparser doesn't represent
+pub mod ddl;
+/// Record of applied migrations
+pub mod history;
+/// Names for unnamed objects, matching the names Postgres generates
+mod naming;
+/// Normalization of ASTs to compare them with how Postgres stores them
+mod normalize;
+/// Classification of planned steps by what they do to existing data
+pub mod safety;
 /// str parsing to generate sqlparser ASTs
 pub mod schema;
 /// Postgres Server reading to generate sqlparser ASTs
 pub mod source_postgres;
 
-use altertable::Wrapped;
 use sqlparser::ast::CreateTable;
+use sqlparser::ast::ObjectName;
 use sqlparser::ast::TableConstraint;
 use sqlx::PgPool;
 use thiserror::Error;
@@ -43,6 +55,10 @@ pub enum MigrationError {
         "The table constraint cannot be modified yet: From: `{0}` To: {1}. Try adding a new constraint then dropping the old one"
     )]
     CannotModifyTableConstraint(TableConstraint, TableConstraint),
+    #[error(
+        "The exclusion constraint cannot be modified yet: From: `{0}` To: {1}. Try adding a new constraint then dropping the old one"
+    )]
+    CannotModifyExclusionConstraint(ddl::ExclusionConstraint, ddl::ExclusionConstraint),
     #[error("These are not the same tables {0} {1}")]
     TablesNotMatching(CreateTable, CreateTable),
     #[error("No name was found for the view")]
@@ -57,17 +73,118 @@ pub enum MigrationError {
     UnsupportedStatementType(sqlparser::ast::Statement),
     #[error("Unsupported statement {0}")]
     UnnamedObject(altertable::Wrapped),
+    #[error(
+        "The migration would lose data, set allow_data_loss to run it anyway: {}",
+        .0.join(", ")
+    )]
+    DataLoss(Vec<String>),
+    #[error(
+        "The migration would lock tables larger than max_locked_table_size while it processes their rows: {}",
+        .0.join(", ")
+    )]
+    LockedTablesTooLarge(Vec<String>),
+    #[error(
+        "Schemas that aren't empty can't be dropped, set drop_non_empty_schemas to drop them with everything in them: {}",
+        .0.join(", ")
+    )]
+    NonEmptySchemas(Vec<String>),
+    #[error(
+        "The database doesn't match the schema after migrating, these steps are still planned: {}",
+        .0.join("; ")
+    )]
+    NotConverged(Box<Vec<String>>),
+    #[error("{error}, after retrying {retries} times")]
+    LockRetriesExhausted {
+        retries: u32,
+        error: Box<MigrationError>,
+    },
+    #[error("{migration}, and it couldn't be recorded in the history: {history}")]
+    NotRecorded {
+        migration: Box<MigrationError>,
+        history: Box<MigrationError>,
+    },
 }
+/// Policies for changes that aren't made by default
+#[derive(Clone, Debug, Default)]
+pub struct MigrationOptions {
+    /// Run steps that lose data, such as `DROP TABLE` and `DROP COLUMN`. Without it migrating
+    /// fails with `MigrationError::DataLoss` listing what would be lost, see `safety`.
+    pub allow_data_loss: bool,
+    /// The largest table, in bytes including its indexes and TOAST, that a step may block writes
+    /// to while it scans or rewrites the rows. Larger tables fail the migration with
+    /// `MigrationError::LockedTablesTooLarge`, see `Step::locking`. `None` allows any size.
+    pub max_locked_table_size: Option<u64>,
+    /// Change existing tables without scanning them while writes are blocked. Foreign keys and
+    /// `CHECK` constraints are added `NOT VALID` and validated separately, and `SET NOT NULL` is
+    /// preceded by a `CHECK (<column> IS NOT NULL)` constraint added the same way, which is
+    /// dropped again afterwards.
+    pub online: bool,
+    /// Create and drop indexes with `CONCURRENTLY`, which doesn't block writes. These steps can't
+    /// run in a transaction, and an index left invalid by a failed build is dropped, see
+    /// `apply::execute_step`.
+    pub concurrent_indexes: bool,
+    /// The key of the `pg_advisory_lock` held while migrating, so migrators started at the same
+    /// time wait for each other and then find nothing left to do. Defaults to
+    /// `apply::DEFAULT_ADVISORY_LOCK_KEY`.
+    pub advisory_lock_key: Option<i64>,
+    /// Migrate without taking the advisory lock
+    pub no_advisory_lock: bool,
+    /// How long a step waits for its lock before failing, while other sessions queue behind it.
+    /// Defaults to `apply::DEFAULT_LOCK_TIMEOUT`.
+    pub lock_timeout: Option<std::time::Duration>,
+    /// How long a step may run before it's cancelled, the server's `statement_timeout` when unset
+    pub statement_timeout: Option<std::time::Duration>,
+    /// How many times a phase that timed out waiting for a lock is rolled back and run again
+    pub lock_retries: u32,
+    /// The wait before retrying a phase, doubled for each retry after the first. Defaults to
+    /// `apply::DEFAULT_LOCK_RETRY_BACKOFF`.
+    pub lock_retry_backoff: Option<std::time::Duration>,
+    /// Record each applied plan, with its statements, how long they took and whether it
+    /// succeeded, in the `declare_schema_history` table of the migrated schema, see `history`
+    pub record_history: bool,
+    /// The version of the application migrating, recorded with the plan
+    pub application_version: Option<String>,
+    /// `DROP EXTENSION` for extensions that aren't in the target schema. Extensions are database
+    /// wide, so this includes extensions installed in other schemas.
+    pub drop_extensions: bool,
+    /// `DROP PUBLICATION` for publications that aren't in the target schema. Publications are
+    /// database wide, so this includes publications of tables in other schemas.
+    pub drop_publications: bool,
+    /// `DROP SCHEMA .. RESTRICT` for schemas that aren't in the target schema. Schemas that still
+    /// contain objects fail planning with `MigrationError::NonEmptySchemas`.
+    pub drop_schemas: bool,
+    /// Drop schemas with `CASCADE` instead, dropping everything they contain
+    pub drop_non_empty_schemas: bool,
+    /// Create the target tables, views and indexes in a scratch schema on the server and compare
+    /// the definitions Postgres stores for them, rather than the definitions as written. The
+    /// scratch schema is created in a transaction that's rolled back.
+    pub canonicalize_on_server: bool,
+    /// Tables that were renamed or moved to another schema, as `(old name, new name)`. They're
+    /// changed with `ALTER TABLE .. SET SCHEMA` and `RENAME TO`, keeping their rows, indexes,
+    /// constraints and owned sequences, instead of being dropped and created again. Qualify the
+    /// new name to move a table to another schema.
+    pub moved_tables: Vec<(ObjectName, ObjectName)>,
+}
+
 /// Diff a str with a DB and apply changes required to get the DB to match `str`
 /// Postgres schema is detected with current_schema()
 
 pub async fn migrate_from_string(to_schema: &str, pool: &PgPool) -> Result<(), MigrationError> {
+    migrate_from_string_with_options(to_schema, pool, &MigrationOptions::default()).await
+}
+
+/// Like `migrate_from_string` with `options` for changes that aren't made by default
+pub async fn migrate_from_string_with_options(
+    to_schema: &str,
+    pool: &PgPool,
+    options: &MigrationOptions,
+) -> Result<(), MigrationError> {
     let current_schema = sqlx::query!("SELECT current_schema();")
         .fetch_one(pool)
         .await?
         .current_schema
         .expect("Couldn't get current schema");
-    migrate_schema_from_string(&current_schema, to_schema, pool).await
+    migrate_schema_from_string_with_options(&current_schema, to_schema, pool, options).await
 }
 
 /// Diff a str with a DB and apply changes required to get the DB to match `str`. Used when you want
@@ -77,69 +194,255 @@ pub async fn migrate_schema_from_string(
     to_src: &str,
     pool: &PgPool,
 ) -> Result<(), MigrationError> {
-    let src_state = crate::source_postgres::from_pool_schema(&pool, schema_name).await?;
-    migrate_from_src(src_state, to_src, schema_name, &pool).await
+    migrate_schema_from_string_with_options(schema_name, to_src, pool, &MigrationOptions::default())
+        .await
 }
 
-async fn migrate_from_src(
-    src_state: Vec<Wrapped>,
-    to_schema: &str,
-    schema_name: &str, // ← add this
+/// Like `migrate_schema_from_string` with `options` for changes that aren't made by default
+pub async fn migrate_schema_from_string_with_options(
+    schema_name: &str,
+    to_src: &str,
     pool: &PgPool,
+    options: &MigrationOptions,
 ) -> Result<(), MigrationError> {
-    let end_statements = schema::app_schema(to_schema)?;
-    let end_state: Result<Vec<Wrapped>, MigrationError> = end_statements
-        .into_iter()
-        .map(|s| Wrapped::try_from(s))
-        .collect();
-    let end_state = end_state?;
-    let steps = crate::altertable::from_to(src_state, end_state)?;
+    // Boxed, as planning and applying make a future too large for small stacks
+    let migration = Box::pin(plan_migration(schema_name, to_src, pool, options)).await?;
+    Box::pin(migration.apply()).await
+}
 
+/// A plan made while holding the advisory lock, so no other migrator changes the database before
+/// it's applied. Dropping it without applying it releases the lock.
+pub struct Migration<'a> {
+    schema_name: String,
+    to_src: &'a str,
+    pool: &'a PgPool,
+    options: &'a MigrationOptions,
+    conn: sqlx::pool::PoolConnection<sqlx::Postgres>,
+    key: Option<i64>,
+    /// The steps `apply` runs
+    pub steps: Vec<altertable::Step>,
+}
+
+/// Take the advisory lock, unless `options.no_advisory_lock` is set, then read `schema_name` and
+/// plan migrating it to `to_src`
+pub async fn plan_migration<'a>(
+    schema_name: &str,
+    to_src: &'a str,
+    pool: &'a PgPool,
+    options: &'a MigrationOptions,
+) -> Result<Migration<'a>, MigrationError> {
     let mut conn = pool.acquire().await?;
+    // Ending the session releases the lock however the migration ends
+    conn.close_on_drop();
+    let key = match options.no_advisory_lock {
+        true => None,
+        false => Some(
+            options
+                .advisory_lock_key
+                .unwrap_or(apply::DEFAULT_ADVISORY_LOCK_KEY),
+        ),
+    };
+    if let Some(key) = key {
+        apply::advisory_lock(&mut conn, key).await?;
+    }
+    let steps = Box::pin(plan(schema_name, to_src, pool, options)).await?;
+    Ok(Migration {
+        schema_name: schema_name.to_string(),
+        to_src,
+        pool,
+        options,
+        conn,
+        key,
+        steps,
+    })
+}
+
+impl Migration<'_> {
+    /// Apply the planned steps, then release the advisory lock
+    pub async fn apply(mut self) -> Result<(), MigrationError> {
+        let r = Box::pin(migrate_schema(
+            &self.schema_name,
+            self.to_src,
+            self.pool,
+            &mut self.conn,
+            self.options,
+            self.steps,
+        ))
+        .await;
+        if let Some(key) = self.key {
+            apply::advisory_unlock(&mut self.conn, key).await?;
+        }
+        r
+    }
+}
+
+/// Apply `steps` on `conn`, which holds the advisory lock
+async fn migrate_schema(
+    schema_name: &str,
+    to_src: &str,
+    pool: &PgPool,
+    conn: &mut sqlx::PgConnection,
+    options: &MigrationOptions,
+    steps: Vec<altertable::Step>,
+) -> Result<(), MigrationError> {
+    safety::check_data_loss(&steps, options)?;
+
     let q = format!("SET search_path TO \"{}\"", schema_name);
     let safe = sqlx::AssertSqlSafe(q);
     sqlx::query(safe).execute(&mut *conn).await?;
-    sqlx::query("SET lock_timeout TO 5000")
-        .execute(&mut *conn)
-        .await?;
-    for s in steps {
-        #[cfg(test)]
-        println!("{:?}", s.to_string());
-        let safe = sqlx::AssertSqlSafe(s.to_string());
-        sqlx::query(safe).execute(&mut *conn).await?;
+    safety::check_lock_sizes(&steps, conn, options).await?;
+    apply::set_timeouts(conn, options).await?;
+    let mut durations = Vec::new();
+    let r = apply::apply(conn, steps.clone(), options, &mut durations).await;
+    if options.record_history && !steps.is_empty() {
+        let recorded = history::record(conn, to_src, &steps, &durations, &r, options).await;
+        match (r, recorded) {
+            (Ok(()), recorded) => recorded?,
+            (Err(e), Err(history)) => {
+                return Err(MigrationError::NotRecorded {
+                    migration: Box::new(e),
+                    history: Box::new(history),
+                });
+            }
+            (Err(e), Ok(())) => return Err(e),
+        }
+    } else {
+        r?;
+    }
+    if !steps.is_empty() {
+        check_convergence(schema_name, to_src, pool, options).await?;
     }
     Ok(())
 }
 
+/// Plan the migration again after applying it, which should find nothing left to do. Otherwise
+/// the database doesn't read back the way the schema is written, and every migration would apply
+/// the same steps again. Objects in other schemas aren't read back, unless they're on tables in
+/// `moved_tables`, so they're left out, and views are always replaced unless
+/// `canonicalize_on_server` is set, so they're not counted.
+async fn check_convergence(
+    schema_name: &str,
+    to_src: &str,
+    pool: &PgPool,
+    options: &MigrationOptions,
+) -> Result<(), MigrationError> {
+    let end_state = schema::app_objects(to_src)?
+        .into_iter()
+        .map(|o| source_postgres::unqualify_wrapped(o, schema_name))
+        .filter(|o| match source_postgres::relation(o) {
+            Some(relation) if relation.0.len() > 1 => options
+                .moved_tables
+                .iter()
+                .any(|(_, new_name)| altertable::object_names_equal(new_name, relation)),
+            _ => true,
+        })
+        .collect();
+    let residual: Vec<String> = Box::pin(plan_objects(schema_name, end_state, pool, options))
+        .await?
+        .iter()
+        .filter(|s| {
+            !matches!(
+                s,
+                altertable::Step::Statement(sqlparser::ast::Statement::CreateView(cv))
+                    if cv.or_replace
+            )
+        })
+        .map(|s| s.to_string())
+        .collect();
+    if residual.is_empty() {
+        Ok(())
+    } else {
+        Err(MigrationError::NotConverged(Box::new(residual)))
+    }
+}
+
+/// Read `schema_name` and diff it with `to_src`
+async fn plan(
+    schema_name: &str,
+    to_src: &str,
+    pool: &PgPool,
+    options: &MigrationOptions,
+) -> Result<Vec<altertable::Step>, MigrationError> {
+    let end_state = schema::app_objects(to_src)?;
+    plan_objects(schema_name, end_state, pool, options).await
+}
+
+/// Read `schema_name` and diff it with `end_state`
+async fn plan_objects(
+    schema_name: &str,
+    end_state: Vec<altertable::Wrapped>,
+    pool: &PgPool,
+    options: &MigrationOptions,
+) -> Result<Vec<altertable::Step>, MigrationError> {
+    let mut declared_extensions =
+        source_postgres::declared_extensions(pool, schema_name, &end_state).await?;
+    let (src_state, end_state) = if options.canonicalize_on_server {
+        Box::pin(source_postgres::canonicalize(pool, schema_name, end_state)).await?
+    } else {
+        let src_state = source_postgres::from_pool_schema(pool, schema_name).await?;
+        // Objects are read back unqualified
+        let end_state = end_state
+            .into_iter()
+            .map(|o| source_postgres::unqualify_wrapped(o, schema_name))
+            .collect();
+        (src_state, end_state)
+    };
+    let mut src_state = src_state;
+    source_postgres::remove_schemas_in_use(&mut src_state, schema_name, &end_state);
+    src_state.append(&mut declared_extensions);
+    src_state.append(
+        &mut source_postgres::moved_tables(pool, schema_name, &options.moved_tables).await?,
+    );
+    let steps = altertable::from_to_with_options(src_state, end_state, options)?;
+    safety::check_dropped_schemas_empty(&steps, pool).await?;
+    Ok(steps)
+}
+
 /// Diff a str with a DB and return SQL changes required to get the DB to match `str`
 
 pub async fn generate_migrations_from_string(
     src: &str,
     pool: &PgPool,
 ) -> Result<Vec<String>, MigrationError> {
-    let src_state = crate::source_postgres::from_pool(&pool).await?;
-    generate_migrations_for_source(src_state, &src).await
+    generate_migrations_from_string_with_options(src, pool, &MigrationOptions::default()).await
+}
+
+/// Like `generate_migrations_from_string` with `options` for changes that aren't made by default
+pub async fn generate_migrations_from_string_with_options(
+    src: &str,
+    pool: &PgPool,
+    options: &MigrationOptions,
+) -> Result<Vec<String>, MigrationError> {
+    let current_schema = sqlx::query!("SELECT current_schema();")
+        .fetch_one(pool)
+        .await?
+        .current_schema
+        .expect("Couldn't get current schema");
+    generate_migrations_from_string_for_schema_with_options(&current_schema, src, pool, options)
+        .await
 }
 pub async fn generate_migrations_from_string_for_schema(
     schema: &str,
     to_src: &str,
     pool: &PgPool,
 ) -> Result<Vec<String>, MigrationError> {
-    let src_state = crate::source_postgres::from_pool_schema(&pool, &schema).await?;
-    generate_migrations_for_source(src_state, to_src).await
+    generate_migrations_from_string_for_schema_with_options(
+        schema,
+        to_src,
+        pool,
+        &MigrationOptions::default(),
+    )
+    .await
 }
 
-async fn generate_migrations_for_source(
-    src_state: Vec<Wrapped>,
-    to_schema: &str,
+/// Like `generate_migrations_from_string_for_schema` with `options` for changes that aren't
+/// made by default
+pub async fn generate_migrations_from_string_for_schema_with_options(
+    schema: &str,
+    to_src: &str,
+    pool: &PgPool,
+    options: &MigrationOptions,
 ) -> Result<Vec<String>, MigrationError> {
-    let end_statements = schema::app_schema(to_schema)?;
-    let end_state: Result<Vec<Wrapped>, MigrationError> = end_statements
-        .clone()
-        .into_iter()
-        .map(|s| Wrapped::try_from(s))
-        .collect();
-    let end_state = end_state?;
-    let steps = crate::altertable::from_to(src_state, end_state)?;
+    let steps = plan(schema, to_src, pool, options).await?;
     Ok(steps.into_iter().map(|f| f.to_string()).collect())
 }
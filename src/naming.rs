@@ -0,0 +1,414 @@
+use crate::altertable::Wrapped;
+use crate::normalize;
+use sqlparser::ast::{
+    CheckConstraint, ColumnOption, CreateTable, Expr, FunctionArg, FunctionArgExpr,
+    FunctionArguments, Ident, ObjectName, ObjectNamePart, PrimaryKeyConstraint, TableConstraint,
+    UniqueConstraint,
+};
+use std::collections::HashSet;
+
+/// Postgres' NAMEDATALEN, names are truncated to `NAMEDATALEN - 1` bytes
+const NAMEDATALEN: usize = 64;
+
+/// Name unnamed indexes and constraints the way Postgres would when creating them, so they
+/// match the objects read back from the server.
+///
+/// Names are only made unique against other names in `objects`, Postgres would also avoid names
+/// of other objects in the schema.
+pub(crate) fn name_objects(objects: Vec<Wrapped>) -> Vec<Wrapped> {
+    let mut taken: HashSet<String> = HashSet::new();
+    for o in &objects {
+        match o {
+            Wrapped::CreateTable(ct) => {
+                taken.insert(relation_name(&ct.name));
+                for def in ct.columns.iter().flat_map(|c| &c.options) {
+                    if let Some(name) = &def.name {
+                        taken.insert(normalize::ident(name).value);
+                    }
+                }
+                for c in &ct.constraints {
+                    if let Some(name) = constraint_name(c) {
+                        taken.insert(normalize::ident(name).value);
+                    }
+                }
+            }
+            Wrapped::CreateView(cv) => {
+                taken.insert(relation_name(&cv.name));
+            }
+            Wrapped::CreateIndex(ci) => {
+                if let Some(name) = &ci.name {
+                    taken.insert(relation_name(name));
+                }
+            }
+            Wrapped::ExclusionConstraint { constraint, .. } => {
+                if let Some(name) = &constraint.name {
+                    taken.insert(normalize::ident(name).value);
+                }
+            }
+            Wrapped::CreateExtension { .. }
+            | Wrapped::CreateSchema { .. }
+            | Wrapped::Storage { .. }
+            | Wrapped::Statistics { .. }
+            | Wrapped::Publication(_)
+            | Wrapped::ReplicaIdentity { .. } => (),
+        }
+    }
+
+    // Storage of an unnamed index follows the index
+    let mut last_index: Option<ObjectName> = None;
+    objects
+        .into_iter()
+        .map(|o| match o {
+            Wrapped::CreateTable(mut ct) => {
+                let table = relation_name(&ct.name);
+                let mut slots = constraint_slots(&mut ct);
+                // CHECK constraints are named as the table is created, before the indexes for
+                // PRIMARY KEY and UNIQUE constraints, and FOREIGN KEYs are added afterwards.
+                // Column constraints are named before table constraints of the same kind.
+                for slot in slots.iter_mut() {
+                    if let Slot::Check(name @ None, columns) = slot {
+                        let column = match columns.as_slice() {
+                            [column] => Some(column.as_str()),
+                            _ => None,
+                        };
+                        **name = Some(choose_name(&table, column, "check", &mut taken));
+                    }
+                }
+                for slot in slots.iter_mut() {
+                    match slot {
+                        // Primary keys are matched without their name, only reserve it
+                        Slot::PrimaryKey(None) => {
+                            choose_name(&table, None, "pkey", &mut taken);
+                        }
+                        Slot::Unique(name @ None, columns) => {
+                            let addition = name_addition(columns);
+                            **name =
+                                Some(choose_name(&table, addition.as_deref(), "key", &mut taken));
+                        }
+                        _ => (),
+                    }
+                }
+                for slot in slots.iter_mut() {
+                    if let Slot::ForeignKey(name @ None, columns) = slot {
+                        let addition = name_addition(columns);
+                        **name = Some(choose_name(&table, addition.as_deref(), "fkey", &mut taken));
+                    }
+                }
+                Wrapped::CreateTable(ct)
+            }
+            Wrapped::CreateIndex(mut ci) if ci.name.is_none() => {
+                let table = relation_name(&ci.table_name);
+                let exprs: Vec<Expr> = ci
+                    .columns
+                    .iter()
+                    .map(|c| c.column.expr.clone())
+                    .chain(ci.include.iter().cloned().map(Expr::Identifier))
+                    .collect();
+                let addition = name_addition(&index_column_names(exprs.iter()));
+                // Index names can't be schema qualified, they're always in the table's schema
+                let name = choose_name(&table, addition.as_deref(), "idx", &mut taken);
+                ci.name = Some(ObjectName(vec![ObjectNamePart::Identifier(name)]));
+                last_index = ci.name.clone();
+                Wrapped::CreateIndex(ci)
+            }
+            Wrapped::CreateIndex(ci) => {
+                last_index = ci.name.clone();
+                Wrapped::CreateIndex(ci)
+            }
+            Wrapped::Storage {
+                kind,
+                relation,
+                storage,
+            } if relation.0.is_empty() => Wrapped::Storage {
+                kind,
+                relation: last_index.clone().unwrap_or(relation),
+                storage,
+            },
+            Wrapped::ExclusionConstraint {
+                table,
+                mut constraint,
+            } if constraint.name.is_none() => {
+                let columns = index_column_names(constraint.elements.iter().map(|e| &e.expr));
+                let addition = name_addition(&columns);
+                constraint.name = Some(choose_name(
+                    &relation_name(&table),
+                    addition.as_deref(),
+                    "excl",
+                    &mut taken,
+                ));
+                Wrapped::ExclusionConstraint { table, constraint }
+            }
+            o => o,
+        })
+        .collect()
+}
+
+/// The name of a constraint with the columns used to generate it
+enum Slot<'a> {
+    PrimaryKey(&'a mut Option<Ident>),
+    Unique(&'a mut Option<Ident>, Vec<String>),
+    ForeignKey(&'a mut Option<Ident>, Vec<String>),
+    /// Columns referenced by the CHECK expression
+    Check(&'a mut Option<Ident>, Vec<String>),
+}
+
+/// Constraints of a table in the order Postgres names them, column constraints first
+fn constraint_slots(ct: &mut CreateTable) -> Vec<Slot<'_>> {
+    let mut r = Vec::new();
+    for column in ct.columns.iter_mut() {
+        let column_name = vec![normalize::ident(&column.name).value];
+        for def in column.options.iter_mut() {
+            match &def.option {
+                ColumnOption::PrimaryKey(_) => r.push(Slot::PrimaryKey(&mut def.name)),
+                ColumnOption::Unique(_) => r.push(Slot::Unique(&mut def.name, column_name.clone())),
+                ColumnOption::ForeignKey(_) => {
+                    r.push(Slot::ForeignKey(&mut def.name, column_name.clone()))
+                }
+                ColumnOption::Check(c) => {
+                    let mut columns = Vec::new();
+                    column_refs(&c.expr, &mut columns);
+                    r.push(Slot::Check(&mut def.name, columns))
+                }
+                _ => (),
+            }
+        }
+    }
+    for c in ct.constraints.iter_mut() {
+        match c {
+            TableConstraint::PrimaryKey(PrimaryKeyConstraint { name, .. }) => {
+                r.push(Slot::PrimaryKey(name))
+            }
+            TableConstraint::Unique(UniqueConstraint { name, columns, .. }) => {
+                let columns = index_column_names(columns.iter().map(|c| &c.column.expr));
+                r.push(Slot::Unique(name, columns))
+            }
+            TableConstraint::ForeignKey(fk) => {
+                let columns = fk
+                    .columns
+                    .iter()
+                    .map(|c| normalize::ident(c).value)
+                    .collect();
+                r.push(Slot::ForeignKey(&mut fk.name, columns))
+            }
+            TableConstraint::Check(CheckConstraint { name, expr, .. }) => {
+                let mut columns = Vec::new();
+                column_refs(expr, &mut columns);
+                r.push(Slot::Check(name, columns))
+            }
+            _ => (),
+        }
+    }
+    r
+}
+
+fn constraint_name(c: &TableConstraint) -> Option<&Ident> {
+    match c {
+        TableConstraint::PrimaryKey(PrimaryKeyConstraint { name, .. })
+        | TableConstraint::Unique(UniqueConstraint { name, .. })
+        | TableConstraint::Check(CheckConstraint { name, .. }) => name.as_ref(),
+        TableConstraint::ForeignKey(fk) => fk.name.as_ref(),
+        _ => None,
+    }
+}
+
+/// The name of the `CHECK (<column> IS NOT NULL)` constraint added while setting a column
+/// `NOT NULL` online
+pub(crate) fn not_null_check_name(table: &ObjectName, column: &Ident) -> Ident {
+    Ident::new(make_object_name(
+        &relation_name(table),
+        Some(&normalize::ident(column).value),
+        "not_null",
+    ))
+}
+
+/// The unqualified name of a relation as Postgres stores it
+fn relation_name(name: &ObjectName) -> String {
+    match name.0.last() {
+        Some(ObjectNamePart::Identifier(i)) => normalize::ident(i).value,
+        Some(part) => part.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Pick the first of `<table>_<addition>_<label>`, `<table>_<addition>_<label>1`, .. that isn't
+/// taken, like Postgres' `ChooseRelationName`
+fn choose_name(
+    table: &str,
+    addition: Option<&str>,
+    label: &str,
+    taken: &mut HashSet<String>,
+) -> Ident {
+    let mut pass = 0;
+    loop {
+        let label = match pass {
+            0 => label.to_string(),
+            n => format!("{label}{n}"),
+        };
+        let name = make_object_name(table, addition, &label);
+        if taken.insert(name.clone()) {
+            return Ident::new(name);
+        }
+        pass += 1;
+    }
+}
+
+/// Join `name1`, `name2` and `label` with `_`, truncating `name1` and `name2` so the result fits
+/// in `NAMEDATALEN - 1` bytes. Like Postgres' `makeObjectName` the longer of the two is shortened
+/// first and multibyte characters are never split.
+fn make_object_name(name1: &str, name2: Option<&str>, label: &str) -> String {
+    let mut overhead = label.len() + 1;
+    if name2.is_some() {
+        overhead += 1;
+    }
+    let available = NAMEDATALEN - 1 - overhead;
+
+    let mut name1_len = name1.len();
+    let mut name2_len = name2.map(|n| n.len()).unwrap_or(0);
+    while name1_len + name2_len > available {
+        if name1_len > name2_len {
+            name1_len -= 1;
+        } else {
+            name2_len -= 1;
+        }
+    }
+
+    let mut r = clip(name1, name1_len).to_string();
+    if let Some(name2) = name2 {
+        r.push('_');
+        r.push_str(clip(name2, name2_len));
+    }
+    r.push('_');
+    r.push_str(label);
+    r
+}
+
+/// The longest prefix of `s` that is at most `len` bytes and doesn't split a character
+fn clip(s: &str, len: usize) -> &str {
+    let mut end = len.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Column names joined with `_`, like Postgres' `ChooseIndexNameAddition`
+fn name_addition(columns: &[String]) -> Option<String> {
+    if columns.is_empty() {
+        return None;
+    }
+    let mut r = String::new();
+    for column in columns {
+        if !r.is_empty() {
+            r.push('_');
+        }
+        r.push_str(clip(column, NAMEDATALEN - 1));
+        if r.len() >= NAMEDATALEN {
+            break;
+        }
+    }
+    Some(r)
+}
+
+/// Names for each index column like Postgres' `ChooseIndexColumnNames`. Columns use their
+/// name, function calls the function name and other expressions `expr`. Repeated names get a
+/// number appended.
+fn index_column_names<'a>(exprs: impl Iterator<Item = &'a Expr>) -> Vec<String> {
+    let mut r: Vec<String> = Vec::new();
+    for e in exprs {
+        let original = expr_column_name(e).unwrap_or("expr".to_string());
+        let mut name = original.clone();
+        let mut i = 1;
+        while r.contains(&name) {
+            let suffix = i.to_string();
+            name = format!(
+                "{}{suffix}",
+                clip(&original, NAMEDATALEN - 1 - suffix.len())
+            );
+            i += 1;
+        }
+        r.push(name);
+    }
+    r
+}
+
+/// The name Postgres would give an index column for `e`, like `FigureIndexColname`
+fn expr_column_name(e: &Expr) -> Option<String> {
+    match e {
+        Expr::Identifier(i) => Some(normalize::ident(i).value),
+        Expr::CompoundIdentifier(is) => is.last().map(|i| normalize::ident(i).value),
+        Expr::Function(f) => match f.name.0.last() {
+            Some(ObjectNamePart::Identifier(i)) => Some(normalize::ident(i).value),
+            _ => None,
+        },
+        Expr::Nested(inner) => expr_column_name(inner),
+        Expr::Cast { expr, .. } => expr_column_name(expr),
+        Expr::Case { .. } => Some("case".to_string()),
+        _ => None,
+    }
+}
+
+/// Distinct columns referenced by `e`, in the order they're first used
+fn column_refs(e: &Expr, r: &mut Vec<String>) {
+    let mut push = |i: &Ident| {
+        let name = normalize::ident(i).value;
+        if !r.contains(&name) {
+            r.push(name);
+        }
+    };
+    match e {
+        Expr::Identifier(i) => push(i),
+        Expr::CompoundIdentifier(is) => {
+            if let Some(i) = is.last() {
+                push(i)
+            }
+        }
+        Expr::Nested(inner)
+        | Expr::UnaryOp { expr: inner, .. }
+        | Expr::IsNull(inner)
+        | Expr::IsNotNull(inner)
+        | Expr::IsTrue(inner)
+        | Expr::IsNotTrue(inner)
+        | Expr::IsFalse(inner)
+        | Expr::IsNotFalse(inner)
+        | Expr::Cast { expr: inner, .. } => column_refs(inner, r),
+        Expr::BinaryOp { left, right, .. }
+        | Expr::IsDistinctFrom(left, right)
+        | Expr::IsNotDistinctFrom(left, right)
+        | Expr::Like {
+            expr: left,
+            pattern: right,
+            ..
+        }
+        | Expr::ILike {
+            expr: left,
+            pattern: right,
+            ..
+        } => {
+            column_refs(left, r);
+            column_refs(right, r);
+        }
+        Expr::InList { expr, list, .. } => {
+            column_refs(expr, r);
+            for e in list {
+                column_refs(e, r);
+            }
+        }
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            column_refs(expr, r);
+            column_refs(low, r);
+            column_refs(high, r);
+        }
+        Expr::Function(f) => {
+            if let FunctionArguments::List(list) = &f.args {
+                for arg in &list.args {
+                    if let FunctionArg::Unnamed(FunctionArgExpr::Expr(e)) = arg {
+                        column_refs(e, r);
+                    }
+                }
+            }
+        }
+        _ => (),
+    }
+}
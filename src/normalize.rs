@@ -0,0 +1,553 @@
+use crate::altertable::Wrapped;
+use sqlparser::ast::{
+    Array, BinaryOperator, CheckConstraint, ColumnOption, ColumnOptionDef,
+    ConstraintCharacteristics, ConstraintReferenceMatchKind, CreateIndex, CreateTable, DataType,
+    DeferrableInitial, Expr, ForeignKeyConstraint, Function, FunctionArg, FunctionArgExpr,
+    FunctionArguments, Ident, IndexColumn, IndexType, ObjectName, ObjectNamePart, OrderByExpr,
+    OrderByOptions, ReferentialAction, TableConstraint, UnaryOperator, Value, ValueWithSpan,
+};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+
+/// Identifiers as Postgres stores them, unquoted identifiers are folded to lowercase
+pub(crate) fn ident(i: &Ident) -> Ident {
+    match i.quote_style {
+        Some(_) => Ident::new(i.value.clone()),
+        None => Ident::new(i.value.to_lowercase()),
+    }
+}
+
+pub(crate) fn object_name(name: &ObjectName) -> ObjectName {
+    ObjectName(
+        name.0
+            .iter()
+            .map(|part| match part {
+                ObjectNamePart::Identifier(i) => ObjectNamePart::Identifier(ident(i)),
+                part => part.clone(),
+            })
+            .collect(),
+    )
+}
+
+/// Normalize an expression so that the one written in a schema file compares equal to the one
+/// Postgres returns. Postgres adds parentheses around most expressions, casts constants and
+/// columns to the type they're used as, only quotes identifiers when needed and rewrites some
+/// operators such as `IN`, `BETWEEN` and `LIKE`. Expressions this doesn't understand are kept as
+/// they are.
+pub(crate) fn expr(e: &Expr) -> Expr {
+    let boxed = |e: &Expr| Box::new(expr(e));
+    match e {
+        Expr::Nested(inner) => expr(inner),
+        Expr::Value(v) => Expr::Value(value(&v.value).into()),
+        Expr::TypedString(ts) => Expr::Value(value(&ts.value.value).into()),
+        // Negative numbers are returned as strings, `-1` becomes `'-1'::integer`
+        Expr::UnaryOp {
+            op: UnaryOperator::Minus,
+            expr: inner,
+        } if matches!(
+            inner.as_ref(),
+            Expr::Value(ValueWithSpan {
+                value: Value::Number(_, _),
+                ..
+            })
+        ) =>
+        {
+            Expr::Value(Value::SingleQuotedString(format!("-{inner}")).into())
+        }
+        // Casts of constants and columns are usually ones Postgres added
+        Expr::Cast {
+            kind,
+            expr: inner,
+            data_type,
+            array,
+            format,
+        } => {
+            let inner = expr(inner);
+            if is_constant(&inner)
+                || matches!(inner, Expr::Identifier(_) | Expr::CompoundIdentifier(_))
+            {
+                inner
+            } else {
+                Expr::Cast {
+                    kind: kind.clone(),
+                    expr: Box::new(inner),
+                    data_type: data_type.clone(),
+                    array: *array,
+                    format: format.clone(),
+                }
+            }
+        }
+        Expr::Array(a) => Expr::Array(Array {
+            elem: a.elem.iter().map(expr).collect(),
+            named: true,
+        }),
+        // `x IN (a, b)` is returned as `x = ANY (ARRAY[a, b])`
+        Expr::AnyOp {
+            left,
+            compare_op: BinaryOperator::Eq,
+            right,
+            ..
+        } => match expr(right) {
+            Expr::Array(a) => Expr::InList {
+                expr: boxed(left),
+                list: a.elem,
+                negated: false,
+            },
+            right => Expr::AnyOp {
+                left: boxed(left),
+                compare_op: BinaryOperator::Eq,
+                right: Box::new(right),
+                is_some: false,
+            },
+        },
+        // `x NOT IN (a, b)` is returned as `x <> ALL (ARRAY[a, b])`
+        Expr::AllOp {
+            left,
+            compare_op: BinaryOperator::NotEq,
+            right,
+        } => match expr(right) {
+            Expr::Array(a) => Expr::InList {
+                expr: boxed(left),
+                list: a.elem,
+                negated: true,
+            },
+            right => Expr::AllOp {
+                left: boxed(left),
+                compare_op: BinaryOperator::NotEq,
+                right: Box::new(right),
+            },
+        },
+        // `x BETWEEN a AND b` is returned as `x >= a AND x <= b`
+        Expr::Between {
+            expr: inner,
+            negated,
+            low,
+            high,
+        } => {
+            let (low_op, high_op, join) = match negated {
+                false => (
+                    BinaryOperator::GtEq,
+                    BinaryOperator::LtEq,
+                    BinaryOperator::And,
+                ),
+                true => (BinaryOperator::Lt, BinaryOperator::Gt, BinaryOperator::Or),
+            };
+            Expr::BinaryOp {
+                left: Box::new(Expr::BinaryOp {
+                    left: boxed(inner),
+                    op: low_op,
+                    right: boxed(low),
+                }),
+                op: join,
+                right: Box::new(Expr::BinaryOp {
+                    left: boxed(inner),
+                    op: high_op,
+                    right: boxed(high),
+                }),
+            }
+        }
+        // `x LIKE y` is returned as `x ~~ y`
+        Expr::Like {
+            negated,
+            any: false,
+            expr: inner,
+            pattern,
+            escape_char: None,
+        } => Expr::BinaryOp {
+            left: boxed(inner),
+            op: match negated {
+                false => BinaryOperator::PGLikeMatch,
+                true => BinaryOperator::PGNotLikeMatch,
+            },
+            right: boxed(pattern),
+        },
+        Expr::ILike {
+            negated,
+            any: false,
+            expr: inner,
+            pattern,
+            escape_char: None,
+        } => Expr::BinaryOp {
+            left: boxed(inner),
+            op: match negated {
+                false => BinaryOperator::PGILikeMatch,
+                true => BinaryOperator::PGNotILikeMatch,
+            },
+            right: boxed(pattern),
+        },
+        Expr::Identifier(i) => Expr::Identifier(ident(i)),
+        Expr::CompoundIdentifier(is) => Expr::CompoundIdentifier(is.iter().map(ident).collect()),
+        Expr::BinaryOp { left, op, right } => Expr::BinaryOp {
+            left: boxed(left),
+            op: op.clone(),
+            right: boxed(right),
+        },
+        Expr::UnaryOp { op, expr: inner } => Expr::UnaryOp {
+            op: *op,
+            expr: boxed(inner),
+        },
+        Expr::IsNull(inner) => Expr::IsNull(boxed(inner)),
+        Expr::IsNotNull(inner) => Expr::IsNotNull(boxed(inner)),
+        Expr::IsTrue(inner) => Expr::IsTrue(boxed(inner)),
+        Expr::IsNotTrue(inner) => Expr::IsNotTrue(boxed(inner)),
+        Expr::IsFalse(inner) => Expr::IsFalse(boxed(inner)),
+        Expr::IsNotFalse(inner) => Expr::IsNotFalse(boxed(inner)),
+        Expr::IsDistinctFrom(a, b) => Expr::IsDistinctFrom(boxed(a), boxed(b)),
+        Expr::IsNotDistinctFrom(a, b) => Expr::IsNotDistinctFrom(boxed(a), boxed(b)),
+        // `x IN (a)` is returned as `x = a`
+        Expr::InList {
+            expr: inner,
+            list,
+            negated,
+        } if list.len() == 1 => Expr::BinaryOp {
+            left: boxed(inner),
+            op: match negated {
+                false => BinaryOperator::Eq,
+                true => BinaryOperator::NotEq,
+            },
+            right: boxed(&list[0]),
+        },
+        Expr::InList {
+            expr: inner,
+            list,
+            negated,
+        } => Expr::InList {
+            expr: boxed(inner),
+            list: list.iter().map(expr).collect(),
+            negated: *negated,
+        },
+        Expr::Function(f) => Expr::Function(function(f)),
+        e => e.clone(),
+    }
+}
+
+/// Compare table constraints with their expressions normalized
+pub(crate) fn constraints_equal(a: &TableConstraint, b: &TableConstraint) -> bool {
+    constraint(a) == constraint(b)
+}
+
+fn constraint(c: &TableConstraint) -> TableConstraint {
+    match c {
+        TableConstraint::ForeignKey(fk) => {
+            let mut fk = fk.clone();
+            fk.name = fk.name.as_ref().map(ident);
+            fk.columns = fk.columns.iter().map(ident).collect();
+            fk.foreign_table = object_name(&fk.foreign_table);
+            fk.referred_columns = fk.referred_columns.iter().map(ident).collect();
+            fk.on_delete = fk.on_delete.filter(|a| *a != ReferentialAction::NoAction);
+            fk.on_update = fk.on_update.filter(|a| *a != ReferentialAction::NoAction);
+            fk.match_kind = fk
+                .match_kind
+                .filter(|m| *m != ConstraintReferenceMatchKind::Simple);
+            fk.characteristics = Some(characteristics(&fk.characteristics));
+            TableConstraint::ForeignKey(fk)
+        }
+        TableConstraint::Unique(unique) => {
+            let mut unique = unique.clone();
+            unique.name = unique.name.as_ref().map(ident);
+            for column in unique.columns.iter_mut() {
+                column.column.expr = expr(&column.column.expr);
+            }
+            TableConstraint::Unique(unique)
+        }
+        TableConstraint::Check(check) => TableConstraint::Check(CheckConstraint {
+            name: check.name.as_ref().map(ident),
+            expr: Box::new(expr(&check.expr)),
+            enforced: Some(check.enforced.unwrap_or(true)),
+        }),
+        c => c.clone(),
+    }
+}
+
+/// Characteristics with the Postgres defaults filled in
+pub(crate) fn characteristics(c: &Option<ConstraintCharacteristics>) -> ConstraintCharacteristics {
+    let c = c.unwrap_or_default();
+    ConstraintCharacteristics {
+        deferrable: Some(c.deferrable.unwrap_or(false)),
+        initially: Some(c.initially.unwrap_or(DeferrableInitial::Immediate)),
+        enforced: Some(c.enforced.unwrap_or(true)),
+    }
+}
+
+/// Move constraints written on columns in to the table's constraints, the way Postgres reports
+/// them
+pub(crate) fn lift_column_constraints(ct: &CreateTable) -> CreateTable {
+    let mut ct = ct.clone();
+    let mut lifted = Vec::new();
+    for column in ct.columns.iter_mut() {
+        let mut options = Vec::new();
+        for def in column.options.drain(..) {
+            match def.option {
+                ColumnOption::PrimaryKey(mut pk) => {
+                    pk.name = def.name.or(pk.name);
+                    pk.columns = vec![IndexColumn::from(column.name.clone())];
+                    lifted.push(TableConstraint::PrimaryKey(pk));
+                }
+                ColumnOption::Unique(mut unique) => {
+                    unique.name = def.name.or(unique.name);
+                    unique.columns = vec![IndexColumn::from(column.name.clone())];
+                    lifted.push(TableConstraint::Unique(unique));
+                }
+                ColumnOption::ForeignKey(mut fk) => {
+                    fk.name = def.name.or(fk.name);
+                    fk.columns = vec![column.name.clone()];
+                    lifted.push(TableConstraint::ForeignKey(fk));
+                }
+                ColumnOption::Check(mut check) => {
+                    check.name = def.name.or(check.name);
+                    lifted.push(TableConstraint::Check(check));
+                }
+                option => options.push(ColumnOptionDef {
+                    name: def.name,
+                    option,
+                }),
+            }
+        }
+        column.options = options;
+    }
+    lifted.append(&mut ct.constraints);
+    ct.constraints = lifted;
+    ct
+}
+
+/// Foreign keys that don't list the columns they refer to refer to the primary key of the
+/// referenced table, which Postgres lists. Fill them in from the tables in `objects`, or from
+/// those in `existing` for tables that aren't in `objects`.
+pub(crate) fn referred_primary_keys(objects: Vec<Wrapped>, existing: &[Wrapped]) -> Vec<Wrapped> {
+    let primary_key = |table: &ObjectName| {
+        let table = object_name(table);
+        objects
+            .iter()
+            .chain(existing)
+            .find_map(|o| match o {
+                Wrapped::CreateTable(ct) if object_name(&ct.name) == table => {
+                    Some(primary_key_columns(ct))
+                }
+                _ => None,
+            })
+            .filter(|columns| !columns.is_empty())
+    };
+    let fill = |fk: &mut ForeignKeyConstraint| {
+        if fk.referred_columns.is_empty()
+            && let Some(columns) = primary_key(&fk.foreign_table)
+        {
+            fk.referred_columns = columns;
+        }
+    };
+    let mut filled = objects.clone();
+    for o in filled.iter_mut() {
+        if let Wrapped::CreateTable(ct) = o {
+            for column in ct.columns.iter_mut() {
+                for def in column.options.iter_mut() {
+                    if let ColumnOption::ForeignKey(fk) = &mut def.option {
+                        fill(fk);
+                    }
+                }
+            }
+            for c in ct.constraints.iter_mut() {
+                if let TableConstraint::ForeignKey(fk) = c {
+                    fill(fk);
+                }
+            }
+        }
+    }
+    filled
+}
+
+/// The columns of the primary key of `ct`, written on a column or the table
+fn primary_key_columns(ct: &CreateTable) -> Vec<Ident> {
+    let on_column = ct.columns.iter().find(|c| {
+        c.options
+            .iter()
+            .any(|o| matches!(o.option, ColumnOption::PrimaryKey(_)))
+    });
+    if let Some(column) = on_column {
+        return vec![column.name.clone()];
+    }
+    ct.constraints
+        .iter()
+        .filter_map(|c| match c {
+            TableConstraint::PrimaryKey(pk) => Some(&pk.columns),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|c| match &c.column.expr {
+            Expr::Identifier(i) => Some(i.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Postgres creates `serial`, `bigserial` and `smallserial` columns as integers that are
+/// `NOT NULL` and default to the next value of a sequence named after the table and column, so
+/// give them that default to compare them with the columns Postgres returns.
+pub(crate) fn serial_defaults(ct: &CreateTable) -> CreateTable {
+    let table = match ct.name.0.last() {
+        Some(ObjectNamePart::Identifier(i)) => ident(i).value,
+        _ => return ct.clone(),
+    };
+    let mut ct = ct.clone();
+    for column in ct.columns.iter_mut() {
+        let serial = match &column.data_type {
+            DataType::Custom(name, modifiers) if modifiers.is_empty() => {
+                let name = object_name(name).to_string();
+                ["serial", "bigserial", "smallserial"].contains(&name.as_str())
+            }
+            _ => false,
+        };
+        if !serial {
+            continue;
+        }
+        let sequence = format!("{table}_{}_seq", ident(&column.name).value);
+        let sequence = if sequence
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        {
+            sequence
+        } else {
+            format!("\"{}\"", sequence.replace('"', "\"\""))
+        };
+        let default = format!("nextval({}::regclass)", Value::SingleQuotedString(sequence));
+        let Ok(default) = Parser::new(&PostgreSqlDialect {})
+            .try_with_sql(&default)
+            .and_then(|mut p| p.parse_expr())
+        else {
+            continue;
+        };
+        if !column
+            .options
+            .iter()
+            .any(|o| matches!(o.option, ColumnOption::NotNull))
+        {
+            column.options.push(ColumnOptionDef {
+                name: None,
+                option: ColumnOption::NotNull,
+            });
+        }
+        column.options.push(ColumnOptionDef {
+            name: None,
+            option: ColumnOption::Default(default),
+        });
+    }
+    ct
+}
+
+/// Columns in the primary key of `to` are `NOT NULL` when they already are in `from`. Otherwise
+/// adding the primary key makes them `NOT NULL`.
+pub(crate) fn primary_key_not_null(from: &CreateTable, to: &CreateTable) -> CreateTable {
+    let not_null = |options: &[ColumnOptionDef]| {
+        options
+            .iter()
+            .any(|o| matches!(o.option, ColumnOption::NotNull))
+    };
+    let primary_key: Vec<Ident> = to
+        .constraints
+        .iter()
+        .filter_map(|c| match c {
+            TableConstraint::PrimaryKey(pk) => Some(&pk.columns),
+            _ => None,
+        })
+        .flatten()
+        .filter_map(|c| match &c.column.expr {
+            Expr::Identifier(i) => Some(ident(i)),
+            _ => None,
+        })
+        .collect();
+
+    let mut to = to.clone();
+    for column in to.columns.iter_mut() {
+        let name = ident(&column.name);
+        let from_not_null = from
+            .columns
+            .iter()
+            .any(|c| ident(&c.name) == name && not_null(&c.options));
+        if primary_key.contains(&name) && from_not_null && !not_null(&column.options) {
+            column.options.push(ColumnOptionDef {
+                name: None,
+                option: ColumnOption::NotNull,
+            });
+        }
+    }
+    to
+}
+
+/// Constants are compared by their text, Postgres returns numbers used as text and text used as
+/// numbers as strings with a cast
+fn value(v: &Value) -> Value {
+    match v {
+        Value::Number(n, _) => Value::SingleQuotedString(n.to_string()),
+        Value::DollarQuotedString(s) => Value::SingleQuotedString(s.value.clone()),
+        v => v.clone(),
+    }
+}
+
+fn is_constant(e: &Expr) -> bool {
+    match e {
+        Expr::Value(_) => true,
+        Expr::Array(a) => a.elem.iter().all(is_constant),
+        _ => false,
+    }
+}
+
+fn function(f: &Function) -> Function {
+    let mut f = f.clone();
+    f.name = object_name(&f.name);
+    if let FunctionArguments::List(list) = &mut f.args {
+        for arg in list.args.iter_mut() {
+            if let FunctionArg::Unnamed(FunctionArgExpr::Expr(e)) = arg {
+                *e = expr(e);
+            }
+        }
+    }
+    f
+}
+
+/// An index as Postgres would store it. The method defaults to btree, columns default to `ASC`
+/// with `NULLS LAST` for ascending and `NULLS FIRST` for descending columns.
+pub(crate) fn index(ci: &CreateIndex) -> CreateIndex {
+    let mut ci = ci.clone();
+    ci.name = ci.name.as_ref().map(object_name);
+    ci.table_name = object_name(&ci.table_name);
+    ci.using = Some(match ci.using {
+        None | Some(IndexType::BTree) => IndexType::BTree,
+        Some(IndexType::Custom(method)) => IndexType::Custom(ident(&method)),
+        Some(method) => method,
+    });
+    ci.columns = ci.columns.iter().map(index_column).collect();
+    ci.include = ci.include.iter().map(ident).collect();
+    ci.nulls_distinct = Some(ci.nulls_distinct.unwrap_or(true));
+    ci.predicate = ci.predicate.as_ref().map(expr);
+    ci.concurrently = false;
+    ci.if_not_exists = false;
+    ci
+}
+
+fn index_column(c: &IndexColumn) -> IndexColumn {
+    let asc = c.column.options.asc.unwrap_or(true);
+    IndexColumn {
+        column: OrderByExpr {
+            expr: expr(&c.column.expr),
+            options: OrderByOptions {
+                asc: Some(asc),
+                nulls_first: Some(c.column.options.nulls_first.unwrap_or(!asc)),
+            },
+            with_fill: c.column.with_fill.clone(),
+        },
+        operator_class: c.operator_class.as_ref().map(object_name),
+    }
+}
+
+/// Compare indexes by their normalized form. Postgres always qualifies the table with its schema
+/// so an unqualified table only compares the table name.
+pub(crate) fn indexes_equal(a: &CreateIndex, b: &CreateIndex) -> bool {
+    let mut a = index(a);
+    let mut b = index(b);
+    let parts = a.table_name.0.len().min(b.table_name.0.len());
+    a.table_name.0.drain(..a.table_name.0.len() - parts);
+    b.table_name.0.drain(..b.table_name.0.len() - parts);
+    // Index names are never schema qualified by Postgres
+    for name in [&mut a.name, &mut b.name].into_iter().flatten() {
+        name.0.drain(..name.0.len() - 1);
+    }
+    a == b
+}
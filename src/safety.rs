@@ -0,0 +1,490 @@
+use crate::altertable::{Step, object_names_equal};
+use crate::ddl::{AlterPublicationOperation, ColumnSetting, PostgresStatement, StorageOperation};
+use crate::{MigrationError, MigrationOptions};
+use sqlparser::ast::{
+    AlterColumnOperation, AlterTableOperation, Expr, ObjectName, ObjectType, Statement,
+    TableConstraint,
+};
+use std::fmt::Display;
+
+/// What a step does to the data already in the database
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// Keeps the data and doesn't need to check it
+    Safe,
+    /// Drops data that can't be recreated from the schema, such as a table or column
+    DataLoss,
+    /// Checks or rewrites the existing rows, so it can fail on them or block writes while it runs
+    Blocking,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Change::Safe => write!(f, "safe"),
+            Change::DataLoss => write!(f, "data loss"),
+            Change::Blocking => write!(f, "blocking"),
+        }
+    }
+}
+
+impl Step {
+    pub fn change(&self) -> Change {
+        if !self.data_lost().is_empty() {
+            return Change::DataLoss;
+        }
+        if self.created_index().is_some_and(|ci| !ci.concurrently) {
+            return Change::Blocking;
+        }
+        match self {
+            Step::Statement(Statement::AlterTable(at)) => {
+                let blocking = at.operations.iter().any(|o| match o {
+                    AlterTableOperation::AddConstraint { not_valid, .. } => !not_valid,
+                    AlterTableOperation::AlterColumn { op, .. } => {
+                        matches!(op, AlterColumnOperation::SetNotNull)
+                    }
+                    _ => false,
+                });
+                match blocking {
+                    true => Change::Blocking,
+                    false => Change::Safe,
+                }
+            }
+            Step::Postgres(PostgresStatement::AddExclusionConstraint { .. }) => Change::Blocking,
+            // Changing how a table is logged or where it's stored rewrites it
+            Step::Postgres(PostgresStatement::AlterStorage { operation, .. }) => match operation {
+                StorageOperation::SetLogged
+                | StorageOperation::SetUnlogged
+                | StorageOperation::SetTablespace(_) => Change::Blocking,
+                StorageOperation::Set(_) | StorageOperation::Reset(_) => Change::Safe,
+            },
+            _ => Change::Safe,
+        }
+    }
+
+    /// What the step drops that can't be recreated from the schema
+    pub fn data_lost(&self) -> Vec<String> {
+        match self {
+            Step::Statement(Statement::Drop {
+                object_type: ObjectType::Table,
+                names,
+                ..
+            }) => names.iter().map(|n| format!("table {n}")).collect(),
+            // Schemas dropped with `RESTRICT` must already be empty
+            Step::Statement(Statement::Drop {
+                object_type: ObjectType::Schema,
+                names,
+                cascade: true,
+                ..
+            }) => names
+                .iter()
+                .map(|n| format!("schema {n} and everything in it"))
+                .collect(),
+            Step::Statement(Statement::AlterTable(at)) => at
+                .operations
+                .iter()
+                .flat_map(|o| match o {
+                    AlterTableOperation::DropColumn { column_names, .. } => column_names
+                        .iter()
+                        .map(|c| format!("column {}.{c}", at.name))
+                        .collect(),
+                    _ => vec![],
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// Refuse steps that lose data unless `options.allow_data_loss` is set
+pub fn check_data_loss(steps: &[Step], options: &MigrationOptions) -> Result<(), MigrationError> {
+    if options.allow_data_loss {
+        return Ok(());
+    }
+    let lost: Vec<String> = steps.iter().flat_map(|s| s.data_lost()).collect();
+    if lost.is_empty() {
+        Ok(())
+    } else {
+        Err(MigrationError::DataLoss(lost))
+    }
+}
+
+/// Refuse to plan `DROP SCHEMA .. RESTRICT` for schemas that still contain objects, which
+/// Postgres would refuse to run
+pub async fn check_dropped_schemas_empty(
+    steps: &[Step],
+    pool: &sqlx::PgPool,
+) -> Result<(), MigrationError> {
+    let mut not_empty = Vec::new();
+    for step in steps {
+        let Step::Statement(Statement::Drop {
+            object_type: ObjectType::Schema,
+            names,
+            cascade: false,
+            ..
+        }) = step
+        else {
+            continue;
+        };
+        for name in names {
+            let objects = sqlx::query_scalar!(
+                r#"
+                SELECT pg_catalog.pg_describe_object(classid, objid, objsubid) as "object!"
+                FROM pg_catalog.pg_depend
+                WHERE refclassid = 'pg_catalog.pg_namespace'::regclass
+                AND refobjid = pg_catalog.to_regnamespace($1)
+                AND deptype = 'n'
+                ORDER BY 1
+                "#,
+                name.to_string()
+            )
+            .fetch_all(pool)
+            .await?;
+            if !objects.is_empty() {
+                not_empty.push(format!("schema {name} ({})", objects.join(", ")));
+            }
+        }
+    }
+    if not_empty.is_empty() {
+        Ok(())
+    } else {
+        Err(MigrationError::NonEmptySchemas(not_empty))
+    }
+}
+
+/// A table level lock, weakest first. Only the locks migrations take are included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Lock {
+    ShareUpdateExclusive,
+    Share,
+    ShareRowExclusive,
+    AccessExclusive,
+}
+
+impl Lock {
+    /// Whether the lock blocks writes to the table
+    pub fn blocks_writes(&self) -> bool {
+        *self >= Lock::Share
+    }
+}
+
+impl Display for Lock {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Lock::ShareUpdateExclusive => write!(f, "SHARE UPDATE EXCLUSIVE"),
+            Lock::Share => write!(f, "SHARE"),
+            Lock::ShareRowExclusive => write!(f, "SHARE ROW EXCLUSIVE"),
+            Lock::AccessExclusive => write!(f, "ACCESS EXCLUSIVE"),
+        }
+    }
+}
+
+/// What a step reads or writes of the existing rows while it holds its lock
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Work {
+    /// Only the catalog is changed
+    Catalog,
+    /// Every row is read, such as to check a constraint or build an index
+    Scan,
+    /// The table is written again
+    Rewrite,
+}
+
+impl Display for Work {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Work::Catalog => write!(f, "catalog only"),
+            Work::Scan => write!(f, "scans the table"),
+            Work::Rewrite => write!(f, "rewrites the table"),
+        }
+    }
+}
+
+/// The lock a step takes on existing relations and the work it does while holding it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Locking {
+    pub lock: Lock,
+    pub relations: Vec<ObjectName>,
+    pub work: Work,
+}
+
+impl Locking {
+    fn new(lock: Lock, relation: &ObjectName, work: Work) -> Self {
+        Locking {
+            lock,
+            relations: vec![relation.clone()],
+            work,
+        }
+    }
+
+    /// Whether other sessions can't write to the relations for as long as the rows take to
+    /// process
+    pub fn is_long(&self) -> bool {
+        self.lock.blocks_writes() && self.work != Work::Catalog
+    }
+}
+
+impl Display for Locking {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let relations: Vec<String> = self.relations.iter().map(|r| r.to_string()).collect();
+        write!(
+            f,
+            "{} on {}, {}",
+            self.lock,
+            relations.join(", "),
+            self.work
+        )
+    }
+}
+
+impl Step {
+    /// The lock the step takes on relations that already exist, following the Postgres
+    /// documentation for each statement. Steps that only lock new objects, or objects that
+    /// aren't tables or indexes, return `None`.
+    pub fn locking(&self) -> Option<Locking> {
+        if let Some(ci) = self.created_index() {
+            return Some(Locking::new(
+                match ci.concurrently {
+                    true => Lock::ShareUpdateExclusive,
+                    false => Lock::Share,
+                },
+                &ci.table_name,
+                Work::Scan,
+            ));
+        }
+        match self {
+            Step::Statement(Statement::Drop {
+                object_type: ObjectType::Table | ObjectType::Index,
+                names,
+                ..
+            }) => Some(Locking {
+                lock: Lock::AccessExclusive,
+                relations: names.clone(),
+                work: Work::Catalog,
+            }),
+            Step::Statement(Statement::AlterIndex { name, .. }) => Some(Locking::new(
+                Lock::ShareUpdateExclusive,
+                name,
+                Work::Catalog,
+            )),
+            Step::Statement(Statement::AlterTable(at)) => {
+                let (lock, work) = at
+                    .operations
+                    .iter()
+                    .map(table_operation_locking)
+                    .max()
+                    .unwrap_or((Lock::AccessExclusive, Work::Catalog));
+                let mut locking = Locking::new(lock, &at.name, work);
+                // Foreign keys also lock the table they refer to
+                for o in &at.operations {
+                    if let AlterTableOperation::AddConstraint {
+                        constraint: TableConstraint::ForeignKey(fk),
+                        ..
+                    } = o
+                    {
+                        locking.relations.push(fk.foreign_table.clone());
+                    }
+                }
+                Some(locking)
+            }
+            Step::Postgres(p) => match p {
+                PostgresStatement::AddExclusionConstraint { table, .. } => {
+                    Some(Locking::new(Lock::AccessExclusive, table, Work::Scan))
+                }
+                PostgresStatement::DropIndexConcurrently { name } => Some(Locking::new(
+                    Lock::ShareUpdateExclusive,
+                    name,
+                    Work::Catalog,
+                )),
+                PostgresStatement::AlterConstraint { table, .. }
+                | PostgresStatement::AlterTableSetSchema { table, .. } => {
+                    Some(Locking::new(Lock::AccessExclusive, table, Work::Catalog))
+                }
+                PostgresStatement::AlterColumnSet { table, setting, .. } => Some(Locking::new(
+                    match setting {
+                        ColumnSetting::Statistics(_) => Lock::ShareUpdateExclusive,
+                        _ => Lock::AccessExclusive,
+                    },
+                    table,
+                    Work::Catalog,
+                )),
+                PostgresStatement::AlterStorage {
+                    name, operation, ..
+                } => Some(match operation {
+                    StorageOperation::SetLogged
+                    | StorageOperation::SetUnlogged
+                    | StorageOperation::SetTablespace(_) => {
+                        Locking::new(Lock::AccessExclusive, name, Work::Rewrite)
+                    }
+                    StorageOperation::Set(_) | StorageOperation::Reset(_) => {
+                        Locking::new(Lock::ShareUpdateExclusive, name, Work::Catalog)
+                    }
+                }),
+                PostgresStatement::CreateStatistics(statistics) => Some(Locking::new(
+                    Lock::ShareUpdateExclusive,
+                    &statistics.table,
+                    Work::Catalog,
+                )),
+                PostgresStatement::CreatePublication(publication) => {
+                    (!publication.tables.is_empty()).then(|| Locking {
+                        lock: Lock::ShareUpdateExclusive,
+                        relations: publication.tables.clone(),
+                        work: Work::Catalog,
+                    })
+                }
+                PostgresStatement::AlterPublication {
+                    operation:
+                        AlterPublicationOperation::AddTables(tables)
+                        | AlterPublicationOperation::DropTables(tables),
+                    ..
+                } => Some(Locking {
+                    lock: Lock::ShareUpdateExclusive,
+                    relations: tables.clone(),
+                    work: Work::Catalog,
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+fn table_operation_locking(o: &AlterTableOperation) -> (Lock, Work) {
+    match o {
+        AlterTableOperation::AddConstraint {
+            constraint,
+            not_valid,
+            ..
+        } => {
+            let lock = match constraint {
+                TableConstraint::ForeignKey(_) => Lock::ShareRowExclusive,
+                _ => Lock::AccessExclusive,
+            };
+            let work = match (constraint, not_valid) {
+                // The index is built even when the constraint isn't validated
+                (TableConstraint::PrimaryKey(_) | TableConstraint::Unique(_), _) => Work::Scan,
+                (_, true) => Work::Catalog,
+                (_, false) => Work::Scan,
+            };
+            (lock, work)
+        }
+        AlterTableOperation::ValidateConstraint { .. } => (Lock::ShareUpdateExclusive, Work::Scan),
+        AlterTableOperation::AlterColumn {
+            op: AlterColumnOperation::SetNotNull,
+            ..
+        } => (Lock::AccessExclusive, Work::Scan),
+        _ => (Lock::AccessExclusive, Work::Catalog),
+    }
+}
+
+/// `Step::locking` for each step of a plan. `SET NOT NULL` doesn't scan the table when an earlier
+/// step validated a `CHECK (<column> IS NOT NULL)` constraint on it.
+pub fn plan_locking(steps: &[Step]) -> Vec<Option<Locking>> {
+    steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| {
+            let mut locking = step.locking();
+            if let Some(locking) = &mut locking
+                && proven_not_null(&steps[..i], step)
+            {
+                locking.work = Work::Catalog;
+            }
+            locking
+        })
+        .collect()
+}
+
+/// `Step::change` for each step of a plan, with `SET NOT NULL` safe like in `plan_locking`
+pub fn plan_changes(steps: &[Step]) -> Vec<Change> {
+    steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| match proven_not_null(&steps[..i], step) {
+            true => Change::Safe,
+            false => step.change(),
+        })
+        .collect()
+}
+
+/// Whether `step` sets a column `NOT NULL` that one of the `earlier` steps proved with a
+/// validated `CHECK (<column> IS NOT NULL)` constraint
+fn proven_not_null(earlier: &[Step], step: &Step) -> bool {
+    let Step::Statement(Statement::AlterTable(at)) = step else {
+        return false;
+    };
+    let [
+        AlterTableOperation::AlterColumn {
+            column_name,
+            op: AlterColumnOperation::SetNotNull,
+        },
+    ] = at.operations.as_slice()
+    else {
+        return false;
+    };
+    let table_operations = || {
+        earlier.iter().flat_map(|s| match s {
+            Step::Statement(Statement::AlterTable(e)) if object_names_equal(&e.name, &at.name) => {
+                e.operations.iter().collect()
+            }
+            _ => vec![],
+        })
+    };
+    table_operations().any(|o| {
+        let AlterTableOperation::AddConstraint {
+            constraint: TableConstraint::Check(check),
+            not_valid,
+        } = o
+        else {
+            return false;
+        };
+        let Expr::IsNotNull(e) = check.expr.as_ref() else {
+            return false;
+        };
+        let Expr::Identifier(column) = e.as_ref() else {
+            return false;
+        };
+        let validated = || {
+            table_operations().any(|v| {
+                matches!(v, AlterTableOperation::ValidateConstraint { name } if Some(name) == check.name.as_ref())
+            })
+        };
+        column.value == column_name.value && (!not_valid || validated())
+    })
+}
+
+/// Refuse steps that hold a lock blocking writes while they process the rows of a relation
+/// larger than `options.max_locked_table_size`. Relations that don't exist yet are skipped.
+pub async fn check_lock_sizes(
+    steps: &[Step],
+    c: &mut sqlx::PgConnection,
+    options: &MigrationOptions,
+) -> Result<(), MigrationError> {
+    let Some(max_size) = options.max_locked_table_size else {
+        return Ok(());
+    };
+    let mut too_large = Vec::new();
+    for (step, locking) in steps.iter().zip(plan_locking(steps)) {
+        let Some(locking) = locking.filter(|l| l.is_long()) else {
+            continue;
+        };
+        for relation in &locking.relations {
+            let size = sqlx::query_scalar!(
+                "SELECT pg_catalog.pg_total_relation_size(pg_catalog.to_regclass($1))",
+                relation.to_string()
+            )
+            .fetch_one(&mut *c)
+            .await?;
+            if let Some(size) = size
+                && size as u64 > max_size
+            {
+                too_large.push(format!("{step} ({locking}, {relation} is {size} bytes)"));
+            }
+        }
+    }
+    if too_large.is_empty() {
+        Ok(())
+    } else {
+        Err(MigrationError::LockedTablesTooLarge(too_large))
+    }
+}
@@ -1,5 +1,14 @@
 use crate::MigrationError;
-use sqlparser::ast::Statement;
+use crate::altertable::Wrapped;
+use crate::ddl::{
+    ColumnSetting, CreatePublication, CreateStatistics, ExclusionConstraint, RelationKind, Storage,
+};
+use sqlparser::ast::{ColumnOptionDef, CreateExtension, Ident, ObjectName, Statement};
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::keywords::Keyword;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Token, TokenWithSpan, Tokenizer};
+use std::ops::Range;
 
 pub fn app_schema(src: &str) -> Result<Vec<Statement>, MigrationError> {
     let dialect = sqlparser::dialect::PostgreSqlDialect {};
@@ -9,3 +18,616 @@ pub fn app_schema(src: &str) -> Result<Vec<Statement>, MigrationError> {
 
     Ok(ast)
 }
+
+/// Parse `src` in to the objects to migrate to. Unlike `app_schema` this handles Postgres syntax
+/// that sqlparser can't parse on its own, such as `EXCLUDE` table constraints and
+/// `CREATE STATISTICS`.
+pub fn app_objects(src: &str) -> Result<Vec<Wrapped>, MigrationError> {
+    let dialect = PostgreSqlDialect {};
+    let tokens = Tokenizer::new(&dialect, src)
+        .tokenize_with_location()
+        .map_err(sqlparser::parser::ParserError::from)?;
+
+    let mut r = Vec::new();
+    for statement_tokens in split_statements(tokens) {
+        if let Some((table, column, setting)) = parse_alter_column_setting(&statement_tokens) {
+            set_column_setting(&mut r, &table, &column, setting)?;
+            continue;
+        }
+        if is_create_statistics(&statement_tokens) {
+            let mut parser = Parser::new(&dialect).with_tokens_with_locations(statement_tokens);
+            let statistics = CreateStatistics::parse(&mut parser)?;
+            parser.expect_token(&Token::EOF)?;
+            r.push(Wrapped::Statistics {
+                statistics,
+                target: None,
+            });
+            continue;
+        }
+        if let Some((name, target)) = parse_alter_statistics(&statement_tokens) {
+            set_statistics_target(&mut r, &name, target)?;
+            continue;
+        }
+        if starts_with_words(&statement_tokens, &["CREATE", "PUBLICATION"]) {
+            let mut parser = Parser::new(&dialect).with_tokens_with_locations(statement_tokens);
+            let publication = CreatePublication::parse(&mut parser)?;
+            parser.expect_token(&Token::EOF)?;
+            r.push(Wrapped::Publication(publication));
+            continue;
+        }
+        if starts_with_words(&statement_tokens, &["CREATE", "EXTENSION"]) {
+            let mut parser = Parser::new(&dialect).with_tokens_with_locations(statement_tokens);
+            let extension = parse_create_extension(&mut parser)?;
+            parser.expect_token(&Token::EOF)?;
+            r.push(Wrapped::try_from(Statement::CreateExtension(extension))?);
+            continue;
+        }
+        if let Some((name, tables)) = parse_alter_publication_add(&statement_tokens) {
+            add_publication_tables(&mut r, &name, tables)?;
+            continue;
+        }
+        let (statement_tokens, exclusion_constraints) =
+            extract_exclusion_constraints(statement_tokens)?;
+        let (statement_tokens, storage) = extract_storage(statement_tokens)?;
+        let (statement_tokens, column_settings) = extract_column_settings(statement_tokens)?;
+        let mut parser = Parser::new(&dialect).with_tokens_with_locations(statement_tokens);
+        let mut statement = parser.parse_statement()?;
+        parser.expect_token(&Token::EOF)?;
+        if let Statement::CreateTable(ct) = &mut statement {
+            for (column, setting) in column_settings {
+                if let Some(c) = ct.columns.iter_mut().find(|c| c.name == column) {
+                    c.options.push(ColumnOptionDef {
+                        name: None,
+                        option: setting.option(),
+                    });
+                }
+            }
+        }
+
+        let table_name = match &statement {
+            Statement::CreateTable(ct) => Some(ct.name.clone()),
+            _ => None,
+        };
+        // Unnamed indexes are named later, see `naming::name_objects`
+        let relation = match &statement {
+            Statement::CreateTable(ct) => ct.name.clone(),
+            Statement::CreateIndex(ci) => ci.name.clone().unwrap_or(ObjectName(vec![])),
+            _ => ObjectName(vec![]),
+        };
+        r.push(Wrapped::try_from(statement)?);
+        if let Some(table) = table_name {
+            for constraint in exclusion_constraints {
+                r.push(Wrapped::ExclusionConstraint {
+                    table: table.clone(),
+                    constraint,
+                });
+            }
+        }
+        if let Some((kind, storage)) = storage {
+            r.push(Wrapped::Storage {
+                kind,
+                relation,
+                storage,
+            });
+        }
+    }
+    Ok(r)
+}
+
+/// Parse `ALTER TABLE [ ONLY ] <table> ALTER [ COLUMN ] <column> SET <setting>`, as written by
+/// `pg_dump` for settings `CREATE TABLE` doesn't support
+fn parse_alter_column_setting(
+    tokens: &[TokenWithSpan],
+) -> Option<(ObjectName, Ident, ColumnSetting)> {
+    let dialect = PostgreSqlDialect {};
+    let mut parser = Parser::new(&dialect).with_tokens_with_locations(tokens.to_vec());
+    if !parser.parse_keywords(&[Keyword::ALTER, Keyword::TABLE]) {
+        return None;
+    }
+    let _ = parser.parse_keyword(Keyword::ONLY);
+    let table = parser.parse_object_name(false).ok()?;
+    if !parser.parse_keyword(Keyword::ALTER) {
+        return None;
+    }
+    let _ = parser.parse_keyword(Keyword::COLUMN);
+    let column = parser.parse_identifier().ok()?;
+    if !parser.parse_keyword(Keyword::SET) {
+        return None;
+    }
+    let setting = ColumnSetting::parse(&mut parser).ok()?;
+    if parser.peek_token().token != Token::EOF {
+        return None;
+    }
+    Some((table, column, setting))
+}
+
+/// sqlparser doesn't support `CREATE STATISTICS`, see `CreateStatistics::parse`
+fn is_create_statistics(tokens: &[TokenWithSpan]) -> bool {
+    starts_with_words(tokens, &["CREATE", "STATISTICS"])
+}
+
+/// Parse `CREATE EXTENSION [ IF NOT EXISTS ] <name> [ WITH ] [ SCHEMA <schema> ]
+/// [ VERSION <version> ] [ CASCADE ]`. sqlparser only accepts the options after `WITH`.
+fn parse_create_extension(parser: &mut Parser) -> Result<CreateExtension, ParserError> {
+    parser.expect_keywords(&[Keyword::CREATE, Keyword::EXTENSION])?;
+    let if_not_exists = parser.parse_keywords(&[Keyword::IF, Keyword::NOT, Keyword::EXISTS]);
+    let name = parser.parse_identifier()?;
+    let _ = parser.parse_keyword(Keyword::WITH);
+    let schema = match parser.parse_keyword(Keyword::SCHEMA) {
+        true => Some(parser.parse_identifier()?),
+        false => None,
+    };
+    let version = match parser.parse_keyword(Keyword::VERSION) {
+        true => Some(parser.parse_identifier()?),
+        false => None,
+    };
+    let cascade = parser.parse_keyword(Keyword::CASCADE);
+    Ok(CreateExtension {
+        name,
+        if_not_exists,
+        schema,
+        version,
+        cascade,
+    })
+}
+
+fn starts_with_words(tokens: &[TokenWithSpan], words: &[&str]) -> bool {
+    let significant: Vec<&Token> = tokens
+        .iter()
+        .filter(|t| is_significant(t))
+        .map(|t| &t.token)
+        .take(words.len())
+        .collect();
+    significant.len() == words.len()
+        && significant.iter().zip(words).all(|(t, word)| {
+            matches!(t, Token::Word(w) if w.quote_style.is_none() && w.value.eq_ignore_ascii_case(word))
+        })
+}
+
+/// Parse `ALTER PUBLICATION <name> ADD TABLE <table> [, ...]`, as written by `pg_dump`
+fn parse_alter_publication_add(tokens: &[TokenWithSpan]) -> Option<(Ident, Vec<ObjectName>)> {
+    if !starts_with_words(tokens, &["ALTER", "PUBLICATION"]) {
+        return None;
+    }
+    let dialect = PostgreSqlDialect {};
+    let mut parser = Parser::new(&dialect).with_tokens_with_locations(tokens.to_vec());
+    parser.next_token();
+    parser.next_token();
+    let name = parser.parse_identifier().ok()?;
+    if !parser.parse_keywords(&[Keyword::ADD, Keyword::TABLE]) {
+        return None;
+    }
+    let tables = parser
+        .parse_comma_separated(CreatePublication::parse_table)
+        .ok()?;
+    if parser.peek_token().token != Token::EOF {
+        return None;
+    }
+    Some((name, tables))
+}
+
+/// Add tables to a publication created earlier in the schema
+fn add_publication_tables(
+    objects: &mut [Wrapped],
+    name: &Ident,
+    mut tables: Vec<ObjectName>,
+) -> Result<(), MigrationError> {
+    let maybe_publication = objects.iter_mut().find_map(|o| match o {
+        Wrapped::Publication(p) if p.name.value.eq_ignore_ascii_case(&name.value) => Some(p),
+        _ => None,
+    });
+    let Some(publication) = maybe_publication else {
+        return Err(MigrationError::SqlParseTypeError(format!(
+            "ALTER PUBLICATION {name} must follow the CREATE PUBLICATION"
+        )));
+    };
+    publication.tables.append(&mut tables);
+    Ok(())
+}
+
+/// Parse `ALTER STATISTICS <name> SET STATISTICS <target>`
+fn parse_alter_statistics(tokens: &[TokenWithSpan]) -> Option<(ObjectName, u64)> {
+    let dialect = PostgreSqlDialect {};
+    let mut parser = Parser::new(&dialect).with_tokens_with_locations(tokens.to_vec());
+    if !parser.parse_keywords(&[Keyword::ALTER, Keyword::STATISTICS]) {
+        return None;
+    }
+    let name = parser.parse_object_name(false).ok()?;
+    if !parser.parse_keywords(&[Keyword::SET, Keyword::STATISTICS]) {
+        return None;
+    }
+    let target = parser.parse_literal_uint().ok()?;
+    if parser.peek_token().token != Token::EOF {
+        return None;
+    }
+    Some((name, target))
+}
+
+/// Set the target of statistics created earlier in the schema
+fn set_statistics_target(
+    objects: &mut [Wrapped],
+    name: &ObjectName,
+    target: u64,
+) -> Result<(), MigrationError> {
+    let maybe_target = objects.iter_mut().find_map(|o| match o {
+        Wrapped::Statistics { statistics, target }
+            if crate::altertable::object_names_equal(&statistics.name, name) =>
+        {
+            Some(target)
+        }
+        _ => None,
+    });
+    let Some(t) = maybe_target else {
+        return Err(MigrationError::SqlParseTypeError(format!(
+            "ALTER STATISTICS {name} SET STATISTICS {target} must follow the CREATE STATISTICS"
+        )));
+    };
+    *t = Some(target);
+    Ok(())
+}
+
+/// Set `setting` on a column of a table created earlier in the schema
+fn set_column_setting(
+    objects: &mut [Wrapped],
+    table: &ObjectName,
+    column: &Ident,
+    setting: ColumnSetting,
+) -> Result<(), MigrationError> {
+    let maybe_column = objects.iter_mut().find_map(|o| match o {
+        Wrapped::CreateTable(ct) if crate::altertable::object_names_equal(&ct.name, table) => {
+            ct.columns.iter_mut().find(|c| c.name == *column)
+        }
+        _ => None,
+    });
+    let Some(c) = maybe_column else {
+        return Err(MigrationError::SqlParseTypeError(format!(
+            "ALTER TABLE {table} ALTER COLUMN {column} SET {setting} must follow the CREATE TABLE"
+        )));
+    };
+    c.options
+        .retain(|o| !ColumnSetting::from_option(&o.option).is_some_and(|s| s.same_kind(&setting)));
+    c.options.push(ColumnOptionDef {
+        name: None,
+        option: setting.option(),
+    });
+    Ok(())
+}
+
+fn is_significant(t: &TokenWithSpan) -> bool {
+    !matches!(t.token, Token::Whitespace(_))
+}
+
+/// Split tokens on `;`, dropping statements that are only whitespace or comments
+fn split_statements(tokens: Vec<TokenWithSpan>) -> Vec<Vec<TokenWithSpan>> {
+    let mut r = Vec::new();
+    let mut current = Vec::new();
+    for t in tokens {
+        match t.token {
+            Token::SemiColon | Token::EOF => {
+                if current.iter().any(is_significant) {
+                    r.push(std::mem::take(&mut current));
+                } else {
+                    current.clear();
+                }
+            }
+            _ => current.push(t),
+        }
+    }
+    if current.iter().any(is_significant) {
+        r.push(current);
+    }
+    r
+}
+
+fn is_create_table(tokens: &[TokenWithSpan]) -> bool {
+    let mut words = tokens
+        .iter()
+        .filter(|t| is_significant(t))
+        .map(|t| &t.token);
+    if !matches!(words.next(), Some(Token::Word(w)) if w.keyword == Keyword::CREATE) {
+        return false;
+    }
+    for t in words {
+        match t {
+            Token::Word(w) if w.keyword == Keyword::TABLE => return true,
+            Token::Word(w)
+                if matches!(
+                    w.keyword,
+                    Keyword::UNLOGGED
+                        | Keyword::TEMP
+                        | Keyword::TEMPORARY
+                        | Keyword::GLOBAL
+                        | Keyword::LOCAL
+                ) => {}
+            _ => return false,
+        }
+    }
+    false
+}
+
+fn is_create_index(tokens: &[TokenWithSpan]) -> bool {
+    let words: Vec<&Token> = tokens
+        .iter()
+        .filter(|t| is_significant(t))
+        .map(|t| &t.token)
+        .take(3)
+        .collect();
+    match words.as_slice() {
+        [Token::Word(c), Token::Word(i), ..] if c.keyword == Keyword::CREATE => {
+            i.keyword == Keyword::INDEX
+                || (i.keyword == Keyword::UNIQUE
+                    && matches!(words.get(2), Some(Token::Word(w)) if w.keyword == Keyword::INDEX))
+        }
+        _ => false,
+    }
+}
+
+type RelationStorage = (RelationKind, Storage);
+
+/// sqlparser doesn't support `UNLOGGED` or `TABLESPACE`, and only keeps `WITH` on some
+/// statements. Remove them from `CREATE TABLE` and `CREATE INDEX` and parse them separately.
+fn extract_storage(
+    tokens: Vec<TokenWithSpan>,
+) -> Result<(Vec<TokenWithSpan>, Option<RelationStorage>), MigrationError> {
+    let kind = if is_create_table(&tokens) {
+        RelationKind::Table
+    } else if is_create_index(&tokens) {
+        RelationKind::Index
+    } else {
+        return Ok((tokens, None));
+    };
+
+    let mut storage = Storage::default();
+    let mut removed = vec![false; tokens.len()];
+    let next_significant = |from: usize| (from..tokens.len()).find(|&i| is_significant(&tokens[i]));
+
+    // `CREATE UNLOGGED TABLE`
+    for (i, t) in tokens.iter().enumerate() {
+        match &t.token {
+            Token::Word(w) if w.keyword == Keyword::UNLOGGED => {
+                storage.unlogged = true;
+                removed[i] = true;
+            }
+            Token::Word(w) if w.keyword == Keyword::TABLE || w.keyword == Keyword::INDEX => break,
+            _ => (),
+        }
+    }
+
+    // Storage follows the column list
+    let mut depth = 0;
+    let mut body_end = None;
+    for (i, t) in tokens.iter().enumerate() {
+        match t.token {
+            Token::LParen => depth += 1,
+            Token::RParen => {
+                depth -= 1;
+                if depth == 0 {
+                    body_end = Some(i);
+                    break;
+                }
+            }
+            _ => (),
+        }
+    }
+    let dialect = PostgreSqlDialect {};
+    let mut i = body_end.map(|e| e + 1).unwrap_or(tokens.len());
+    let mut depth = 0;
+    while i < tokens.len() {
+        match &tokens[i].token {
+            Token::LParen => depth += 1,
+            Token::RParen => depth -= 1,
+            Token::Word(w) if depth == 0 && w.keyword == Keyword::WHERE => break,
+            Token::Word(w) if depth == 0 && w.keyword == Keyword::WITH => {
+                if let Some(open) = next_significant(i + 1)
+                    && tokens[open].token == Token::LParen
+                {
+                    let mut close = open;
+                    let mut inner = 0;
+                    for (j, t) in tokens.iter().enumerate().skip(open) {
+                        match t.token {
+                            Token::LParen => inner += 1,
+                            Token::RParen => inner -= 1,
+                            _ => (),
+                        }
+                        if inner == 0 {
+                            close = j;
+                            break;
+                        }
+                    }
+                    let mut parser = Parser::new(&dialect)
+                        .with_tokens_with_locations(tokens[open..=close].to_vec());
+                    storage.parameters = Storage::parse_parameters(&mut parser)?;
+                    for r in removed.iter_mut().take(close + 1).skip(i) {
+                        *r = true;
+                    }
+                    i = close;
+                }
+            }
+            Token::Word(w) if depth == 0 && w.keyword == Keyword::TABLESPACE => {
+                if let Some(name) = next_significant(i + 1) {
+                    let mut parser = Parser::new(&dialect)
+                        .with_tokens_with_locations(vec![tokens[name].clone()]);
+                    storage.tablespace = Some(parser.parse_identifier()?);
+                    for r in removed.iter_mut().take(name + 1).skip(i) {
+                        *r = true;
+                    }
+                    i = name;
+                }
+            }
+            _ => (),
+        }
+        i += 1;
+    }
+
+    let remaining = tokens
+        .into_iter()
+        .zip(removed)
+        .filter(|(_, removed)| !removed)
+        .map(|(t, _)| t)
+        .collect();
+    let storage = (storage != Storage::default()).then_some((kind, storage));
+    Ok((remaining, storage))
+}
+
+/// sqlparser doesn't support `EXCLUDE` constraints, remove them from a `CREATE TABLE` body and
+/// parse them separately.
+fn extract_exclusion_constraints(
+    tokens: Vec<TokenWithSpan>,
+) -> Result<(Vec<TokenWithSpan>, Vec<ExclusionConstraint>), MigrationError> {
+    if !is_create_table(&tokens) {
+        return Ok((tokens, vec![]));
+    }
+
+    let elements = table_elements(&tokens);
+    let dialect = PostgreSqlDialect {};
+    let mut constraints = Vec::new();
+    let mut removed = vec![false; tokens.len()];
+    let mut kept_element = false;
+    for (n, element) in elements.iter().enumerate() {
+        let words: Vec<&Token> = tokens[element.clone()]
+            .iter()
+            .filter(|t| is_significant(t))
+            .map(|t| &t.token)
+            .take(3)
+            .collect();
+        let is_exclusion = match words.as_slice() {
+            [Token::Word(w), ..] if w.keyword == Keyword::EXCLUDE => true,
+            [Token::Word(c), _, Token::Word(w)] => {
+                c.keyword == Keyword::CONSTRAINT && w.keyword == Keyword::EXCLUDE
+            }
+            _ => false,
+        };
+        if !is_exclusion {
+            kept_element = true;
+            continue;
+        }
+
+        let mut parser =
+            Parser::new(&dialect).with_tokens_with_locations(tokens[element.clone()].to_vec());
+        constraints.push(ExclusionConstraint::parse(&mut parser)?);
+
+        // Remove the element along with the comma separating it from its neighbour
+        let (start, end) = if kept_element {
+            (element.start - 1, element.end)
+        } else if n + 1 < elements.len() {
+            (element.start, element.end + 1)
+        } else {
+            (element.start, element.end)
+        };
+        for r in removed.iter_mut().take(end).skip(start) {
+            *r = true;
+        }
+    }
+
+    let remaining = tokens
+        .into_iter()
+        .zip(removed)
+        .filter(|(_, removed)| !removed)
+        .map(|(t, _)| t)
+        .collect();
+    Ok((remaining, constraints))
+}
+
+/// Each element of a `CREATE TABLE` body as a range of token indexes
+fn table_elements(tokens: &[TokenWithSpan]) -> Vec<Range<usize>> {
+    let mut elements = Vec::new();
+    let mut depth = 0;
+    let mut element_start = 0;
+    for (i, t) in tokens.iter().enumerate() {
+        match t.token {
+            Token::LParen => {
+                depth += 1;
+                if depth == 1 {
+                    element_start = i + 1;
+                }
+            }
+            Token::RParen => {
+                depth -= 1;
+                if depth == 0 {
+                    elements.push(element_start..i);
+                    break;
+                }
+            }
+            Token::Comma if depth == 1 => {
+                elements.push(element_start..i);
+                element_start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    elements
+}
+
+type NamedColumnSetting = (Ident, ColumnSetting);
+
+/// sqlparser doesn't support `STORAGE` or `COMPRESSION` on columns, remove them from a
+/// `CREATE TABLE` body and parse them separately. `STATISTICS` is accepted the same way.
+fn extract_column_settings(
+    tokens: Vec<TokenWithSpan>,
+) -> Result<(Vec<TokenWithSpan>, Vec<NamedColumnSetting>), MigrationError> {
+    if !is_create_table(&tokens) {
+        return Ok((tokens, vec![]));
+    }
+
+    let dialect = PostgreSqlDialect {};
+    let mut settings = Vec::new();
+    let mut removed = vec![false; tokens.len()];
+    for element in table_elements(&tokens) {
+        let mut significant = element.clone().filter(|&i| is_significant(&tokens[i]));
+        let Some(first) = significant.next() else {
+            continue;
+        };
+        let column = match &tokens[first].token {
+            Token::Word(w)
+                if !matches!(
+                    w.keyword,
+                    Keyword::CONSTRAINT
+                        | Keyword::PRIMARY
+                        | Keyword::UNIQUE
+                        | Keyword::CHECK
+                        | Keyword::FOREIGN
+                        | Keyword::EXCLUDE
+                        | Keyword::LIKE
+                ) =>
+            {
+                w.clone().into_ident(tokens[first].span)
+            }
+            _ => continue,
+        };
+
+        let mut depth = 0;
+        while let Some(i) = significant.next() {
+            match &tokens[i].token {
+                Token::LParen => depth += 1,
+                Token::RParen => depth -= 1,
+                Token::Word(w)
+                    if depth == 0
+                        && w.quote_style.is_none()
+                        && matches!(
+                            w.keyword,
+                            Keyword::STORAGE | Keyword::COMPRESSION | Keyword::STATISTICS
+                        ) =>
+                {
+                    let Some(value) = significant.next() else {
+                        break;
+                    };
+                    let mut parser = Parser::new(&dialect)
+                        .with_tokens_with_locations(vec![tokens[i].clone(), tokens[value].clone()]);
+                    settings.push((column.clone(), ColumnSetting::parse(&mut parser)?));
+                    for r in removed.iter_mut().take(value + 1).skip(i) {
+                        *r = true;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let remaining = tokens
+        .into_iter()
+        .zip(removed)
+        .filter(|(_, removed)| !removed)
+        .map(|(t, _)| t)
+        .collect();
+    Ok((remaining, settings))
+}
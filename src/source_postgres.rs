@@ -1,7 +1,11 @@
 use crate::MigrationError;
 use crate::altertable::Wrapped;
+use crate::ddl::{ColumnSetting, CreatePublication, CreateStatistics, RelationKind, Storage};
 use sqlparser::ast::helpers::stmt_create_table::CreateTableBuilder;
-use sqlparser::ast::{ColumnDef, CreateExtension, CreateView, Ident, ObjectName};
+use sqlparser::ast::{
+    ColumnDef, ColumnOption, ColumnOptionDef, CreateExtension, CreateView, Ident, ObjectName,
+    ObjectNamePart, ReplicaIdentity, TableConstraint,
+};
 use std::collections::HashMap;
 
 #[derive(Clone, Debug)]
@@ -12,7 +16,10 @@ struct PGIndex {
     indexdef: Option<String>,
 }
 
-async fn pg_indexes(pool: &sqlx::PgPool, schema: String) -> Result<Vec<Wrapped>, MigrationError> {
+async fn pg_indexes(
+    c: &mut sqlx::PgConnection,
+    schema: String,
+) -> Result<Vec<Wrapped>, MigrationError> {
     let mut r = Vec::new();
     let db_indexes = sqlx::query_as!(
         PGIndex,
@@ -25,17 +32,32 @@ async fn pg_indexes(pool: &sqlx::PgPool, schema: String) -> Result<Vec<Wrapped>,
         LEFT JOIN information_schema.table_constraints as tc
         ON pgi.indexname = tc.constraint_name
         WHERE schemaname = $1
-        AND constraint_name IS NULL ",
+        AND constraint_name IS NULL
+        AND NOT EXISTS (
+            SELECT 1 FROM pg_catalog.pg_constraint con
+            WHERE con.contype = 'x'
+            AND con.conindid = format('%I.%I', pgi.schemaname, pgi.indexname)::regclass
+        )
+        -- Left by a failed concurrent build, so it's created again
+        AND NOT EXISTS (
+            SELECT 1 FROM pg_catalog.pg_index i
+            WHERE i.indexrelid = format('%I.%I', pgi.schemaname, pgi.indexname)::regclass
+            AND NOT i.indisvalid
+        )",
         schema
     )
-    .fetch_all(pool)
+    .fetch_all(&mut *c)
     .await?;
 
     for dbi in db_indexes {
         if let Some(def) = dbi.indexdef.clone() {
             eprintln!("Index {def}");
         }
-        let c = string_to_create_index(dbi.indexdef)?;
+        let mut c = string_to_create_index(dbi.indexdef)?;
+        // Storage parameters are read separately, see `pg_storage`
+        if let sqlparser::ast::Statement::CreateIndex(ci) = &mut c {
+            ci.with.clear();
+        }
         let w = Wrapped::try_from(c)?;
         r.push(w)
     }
@@ -43,7 +65,7 @@ async fn pg_indexes(pool: &sqlx::PgPool, schema: String) -> Result<Vec<Wrapped>,
 }
 
 async fn table_constraints(
-    c: &sqlx::PgPool,
+    c: &mut sqlx::PgConnection,
     schema: String,
     table_name: String,
 ) -> Result<Vec<sqlparser::ast::TableConstraint>, MigrationError> {
@@ -58,10 +80,11 @@ async fn table_constraints(
             pg_catalog.pg_get_constraintdef(r.oid, true) as definition
         FROM pg_catalog.pg_constraint r
         WHERE r.conrelid = $1::regclass
+        AND r.contype <> 'x'
         ",
         name as _
     )
-    .fetch_all(c)
+    .fetch_all(&mut *c)
     .await?;
 
     for dbtc in db_table_constraints {
@@ -80,34 +103,323 @@ async fn table_constraints(
     Ok(r)
 }
 
+#[derive(Clone, Debug)]
+struct PGStorage {
+    relname: Option<String>,
+    is_index: Option<bool>,
+    unlogged: Option<bool>,
+    reloptions: Option<Vec<String>>,
+    tablespace: Option<String>,
+}
+
+/// Storage of tables and indexes that isn't the default. Indexes of constraints are left out,
+/// like the indexes themselves.
+async fn pg_storage(
+    c: &mut sqlx::PgConnection,
+    schema: &str,
+) -> Result<Vec<Wrapped>, MigrationError> {
+    let db_storage = sqlx::query_as!(
+        PGStorage,
+        "
+        SELECT
+            cls.relname::text,
+            cls.relkind = 'i' as is_index,
+            cls.relpersistence = 'u' as unlogged,
+            cls.reloptions::text[],
+            spc.spcname::text as tablespace
+        FROM pg_catalog.pg_class cls
+        JOIN pg_catalog.pg_namespace nsp ON nsp.oid = cls.relnamespace
+        LEFT JOIN pg_catalog.pg_tablespace spc ON spc.oid = cls.reltablespace
+        WHERE nsp.nspname = $1
+        AND cls.relkind IN ('r', 'p', 'i')
+        AND NOT EXISTS (
+            SELECT 1 FROM pg_catalog.pg_constraint con WHERE con.conindid = cls.oid
+        )
+        AND (
+            (cls.relkind <> 'i' AND cls.relpersistence = 'u')
+            OR cls.reloptions IS NOT NULL
+            OR cls.reltablespace <> 0
+        )
+        ",
+        schema
+    )
+    .fetch_all(&mut *c)
+    .await?;
+
+    let mut r = Vec::new();
+    for dbs in db_storage {
+        let kind = match dbs.is_index {
+            Some(true) => RelationKind::Index,
+            _ => RelationKind::Table,
+        };
+        let parameters = dbs
+            .reloptions
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|o| o.split_once('='))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        r.push(Wrapped::Storage {
+            kind,
+            relation: ObjectName(vec![ObjectNamePart::Identifier(catalog_ident(
+                dbs.relname,
+            )?)]),
+            storage: Storage {
+                unlogged: kind == RelationKind::Table && dbs.unlogged.unwrap_or(false),
+                parameters,
+                tablespace: dbs.tablespace.map(|t| catalog_ident(Some(t))).transpose()?,
+            },
+        });
+    }
+    Ok(r)
+}
+
+#[derive(Clone, Debug)]
+struct PGExclusionConstraint {
+    table_name: Option<String>,
+    conname: Option<String>,
+    definition: Option<String>,
+}
+
+async fn pg_exclusion_constraints(
+    c: &mut sqlx::PgConnection,
+    schema: &str,
+) -> Result<Vec<Wrapped>, MigrationError> {
+    let mut r = Vec::new();
+
+    let db_constraints = sqlx::query_as!(
+        PGExclusionConstraint,
+        "
+        SELECT
+            cls.relname::text as table_name,
+            con.conname::text,
+            pg_catalog.pg_get_constraintdef(con.oid, true) as definition
+        FROM pg_catalog.pg_constraint con
+        JOIN pg_catalog.pg_class cls ON cls.oid = con.conrelid
+        JOIN pg_catalog.pg_namespace nsp ON nsp.oid = cls.relnamespace
+        WHERE nsp.nspname = $1
+        AND con.contype = 'x'
+        ",
+        schema
+    )
+    .fetch_all(&mut *c)
+    .await?;
+
+    for dbc in db_constraints {
+        let s = format!(
+            "CONSTRAINT {} {}",
+            dbc.conname.unwrap(),
+            dbc.definition.unwrap()
+        );
+        r.push(Wrapped::ExclusionConstraint {
+            table: string_to_object_name(dbc.table_name)?,
+            constraint: string_to_exclusion_constraint(Some(s))?,
+        });
+    }
+    Ok(r)
+}
+
+async fn pg_statistics(
+    c: &mut sqlx::PgConnection,
+    schema: &str,
+) -> Result<Vec<Wrapped>, MigrationError> {
+    let mut r = Vec::new();
+
+    let db_statistics = sqlx::query!(
+        r#"
+        SELECT
+            s.stxname::text AS "name!",
+            pg_catalog.pg_get_statisticsobjdef(s.oid) AS "definition!",
+            CASE WHEN s.stxstattarget >= 0 THEN s.stxstattarget::int END AS target
+        FROM pg_catalog.pg_statistic_ext s
+        JOIN pg_catalog.pg_namespace nsp ON nsp.oid = s.stxnamespace
+        WHERE nsp.nspname = $1
+        "#,
+        schema
+    )
+    .fetch_all(&mut *c)
+    .await?;
+
+    for dbs in db_statistics {
+        let dialect = sqlparser::dialect::PostgreSqlDialect {};
+        let mut parser = sqlparser::parser::Parser::new(&dialect).try_with_sql(&dbs.definition)?;
+        let mut statistics = CreateStatistics::parse(&mut parser)?;
+        // The definition qualifies the name with its schema, and the table when it isn't in the
+        // search path
+        statistics.name = ObjectName(vec![ObjectNamePart::Identifier(catalog_ident(Some(
+            dbs.name,
+        ))?)]);
+        statistics.table = unqualify(&statistics.table, schema);
+        r.push(Wrapped::Statistics {
+            statistics,
+            target: dbs.target.map(|t| t as u64),
+        });
+    }
+    Ok(r)
+}
+
+async fn pg_replica_identities(
+    c: &mut sqlx::PgConnection,
+    schema: &str,
+) -> Result<Vec<Wrapped>, MigrationError> {
+    let mut r = Vec::new();
+
+    let db_identities = sqlx::query!(
+        r#"
+        SELECT
+            cls.relname::text AS "table_name!",
+            cls.relreplident::text AS "identity!",
+            idx.relname::text AS index_name
+        FROM pg_catalog.pg_class cls
+        JOIN pg_catalog.pg_namespace nsp ON nsp.oid = cls.relnamespace
+        LEFT JOIN pg_catalog.pg_index i ON i.indrelid = cls.oid AND i.indisreplident
+        LEFT JOIN pg_catalog.pg_class idx ON idx.oid = i.indexrelid
+        WHERE nsp.nspname = $1
+        AND cls.relkind IN ('r', 'p')
+        AND cls.relreplident <> 'd'
+        "#,
+        schema
+    )
+    .fetch_all(&mut *c)
+    .await?;
+
+    for dbi in db_identities {
+        let identity = match (dbi.identity.as_str(), dbi.index_name) {
+            ("f", _) => ReplicaIdentity::Full,
+            ("n", _) => ReplicaIdentity::Nothing,
+            ("i", Some(index)) => ReplicaIdentity::Index(catalog_ident(Some(index))?),
+            _ => continue,
+        };
+        r.push(Wrapped::ReplicaIdentity {
+            table: string_to_object_name(Some(dbi.table_name))?,
+            identity,
+        });
+    }
+    Ok(r)
+}
+
+/// Publications are database wide. Tables in `schema` are unqualified, like the rest of the
+/// objects read from it.
+async fn pg_publications(
+    c: &mut sqlx::PgConnection,
+    schema: &str,
+) -> Result<Vec<Wrapped>, MigrationError> {
+    let mut r = Vec::new();
+
+    let db_publications = sqlx::query!(
+        r#"
+        SELECT
+            p.oid AS "oid!",
+            p.pubname::text AS "name!",
+            p.puballtables AS "all_tables!",
+            p.pubinsert AS "insert!",
+            p.pubupdate AS "update!",
+            p.pubdelete AS "delete!",
+            p.pubtruncate AS "truncate!",
+            p.pubviaroot AS "via_root!"
+        FROM pg_catalog.pg_publication p
+        ORDER BY p.pubname
+        "#
+    )
+    .fetch_all(&mut *c)
+    .await?;
+
+    for dbp in db_publications {
+        let db_tables = sqlx::query!(
+            r#"
+            SELECT
+                nsp.nspname::text AS "schema_name!",
+                cls.relname::text AS "table_name!"
+            FROM pg_catalog.pg_publication_rel pr
+            JOIN pg_catalog.pg_class cls ON cls.oid = pr.prrelid
+            JOIN pg_catalog.pg_namespace nsp ON nsp.oid = cls.relnamespace
+            WHERE pr.prpubid = $1
+            ORDER BY nsp.nspname, cls.relname
+            "#,
+            dbp.oid
+        )
+        .fetch_all(&mut *c)
+        .await?;
+        let mut tables = Vec::new();
+        for dbt in db_tables {
+            let table = catalog_ident(Some(dbt.table_name))?;
+            tables.push(if dbt.schema_name == schema {
+                ObjectName(vec![ObjectNamePart::Identifier(table)])
+            } else {
+                ObjectName(vec![
+                    ObjectNamePart::Identifier(catalog_ident(Some(dbt.schema_name))?),
+                    ObjectNamePart::Identifier(table),
+                ])
+            });
+        }
+
+        let publish: Vec<&str> = [
+            (dbp.insert, "insert"),
+            (dbp.update, "update"),
+            (dbp.delete, "delete"),
+            (dbp.truncate, "truncate"),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, operation)| enabled.then_some(operation))
+        .collect();
+        r.push(Wrapped::Publication(CreatePublication {
+            name: catalog_ident(Some(dbp.name))?,
+            all_tables: dbp.all_tables,
+            tables,
+            parameters: vec![
+                ("publish".to_string(), publish.join(", ")),
+                (
+                    "publish_via_partition_root".to_string(),
+                    dbp.via_root.to_string(),
+                ),
+            ],
+        }));
+    }
+    Ok(r)
+}
+
 #[derive(Clone, Debug)]
 struct PGExtension {
     extname: Option<String>,
+    extversion: Option<String>,
+    extschema: Option<String>,
 }
 
-async fn pg_extensions(c: &sqlx::PgPool) -> Result<Vec<Wrapped>, MigrationError> {
+async fn pg_extensions(
+    c: &mut sqlx::PgConnection,
+    schema: &str,
+    declared: &[String],
+) -> Result<Vec<Wrapped>, MigrationError> {
     let mut r = Vec::new();
 
+    // Extensions in other schemas, such as plpgsql in pg_catalog, aren't managed by this schema
+    // unless they're declared
     let db_extensions = sqlx::query_as!(
         PGExtension,
         "
         SELECT
-            extname
-
-        FROM pg_extension pge
-        "
+            extname::text,
+            extversion,
+            nspname::text as extschema
+        FROM pg_catalog.pg_extension pge
+        JOIN pg_catalog.pg_namespace nsp ON nsp.oid = pge.extnamespace
+        WHERE nsp.nspname = $1
+        OR pge.extname = ANY($2)
+        ",
+        schema,
+        declared
     )
-    .fetch_all(c)
+    .fetch_all(&mut *c)
     .await?;
 
     for ext in db_extensions {
-        let name_ident = string_to_ident(ext.extname)?;
+        let name_ident = catalog_ident(ext.extname)?;
         let statement = sqlparser::ast::Statement::CreateExtension(CreateExtension {
             name: name_ident,
             cascade: false,
             if_not_exists: false,
-            schema: None,
-            version: None,
+            schema: Some(catalog_ident(ext.extschema)?),
+            version: ext.extversion.map(|v| Ident::with_quote('\'', v)),
         });
         let wrapped = Wrapped::try_from(statement)?;
         r.push(wrapped);
@@ -115,6 +427,74 @@ async fn pg_extensions(c: &sqlx::PgPool) -> Result<Vec<Wrapped>, MigrationError>
     Ok(r)
 }
 
+/// Read the extensions declared in `to` that are installed outside `schema`, so they're compared
+/// with the declarations rather than created again
+pub async fn declared_extensions(
+    pool: &sqlx::PgPool,
+    schema: &str,
+    to: &[Wrapped],
+) -> Result<Vec<Wrapped>, MigrationError> {
+    let names: Vec<String> = to
+        .iter()
+        .filter_map(|o| match o {
+            Wrapped::CreateExtension { name, .. } => Some(crate::normalize::ident(name).value),
+            _ => None,
+        })
+        .collect();
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut conn = pool.acquire().await?;
+    let mut r = pg_extensions(&mut conn, schema, &names).await?;
+    // Those in `schema` are read with the rest of it
+    r.retain(
+        |o| !matches!(o, Wrapped::CreateExtension { schema: Some(s), .. } if s.value == schema),
+    );
+    Ok(r)
+}
+
+#[derive(Clone, Debug)]
+struct PGSchema {
+    schema_name: Option<String>,
+    owner: Option<String>,
+}
+
+async fn pg_schemas(c: &mut sqlx::PgConnection) -> Result<Vec<Wrapped>, MigrationError> {
+    let mut r = Vec::new();
+
+    let db_schemas = sqlx::query_as!(
+        PGSchema,
+        r#"
+        SELECT
+            nspname::text as schema_name,
+            pg_catalog.pg_get_userbyid(nspowner)::text as owner
+        FROM pg_catalog.pg_namespace
+        WHERE nspname NOT LIKE 'pg\_%'
+        AND nspname <> 'information_schema'
+        "#
+    )
+    .fetch_all(&mut *c)
+    .await?;
+
+    for dbs in db_schemas {
+        let statement = sqlparser::ast::Statement::CreateSchema {
+            schema_name: sqlparser::ast::SchemaName::NamedAuthorization(
+                ObjectName(vec![ObjectNamePart::Identifier(catalog_ident(
+                    dbs.schema_name,
+                )?)]),
+                catalog_ident(dbs.owner)?,
+            ),
+            if_not_exists: false,
+            with: None,
+            options: None,
+            default_collate_spec: None,
+            clone: None,
+        };
+        r.push(Wrapped::try_from(statement)?);
+    }
+    Ok(r)
+}
+
 struct PGTable {
     table_schema: Option<String>,
     table_name: Option<String>,
@@ -151,7 +531,7 @@ struct PGView {
 }
 
 async fn pg_view(
-    c: &sqlx::PgPool,
+    c: &mut sqlx::PgConnection,
     schema: &str,
     view_name: Option<String>,
 ) -> Result<Wrapped, MigrationError> {
@@ -166,7 +546,7 @@ async fn pg_view(
             schema,
             name
         )
-        .fetch_one(c)
+        .fetch_one(&mut *c)
         .await?;
         #[cfg(test)]
         println!("view def source {:?}", db_view.view_definition);
@@ -198,7 +578,7 @@ async fn pg_view(
     }
 }
 async fn table_columns(
-    c: &sqlx::PgPool,
+    c: &mut sqlx::PgConnection,
     schema: String,
     table_name: String,
 ) -> Result<Vec<ColumnDef>, MigrationError> {
@@ -221,8 +601,9 @@ async fn table_columns(
         schema,
         table_name
     )
-    .fetch_all(c)
+    .fetch_all(&mut *c)
     .await?;
+    let settings = column_settings(c, &schema, &table_name).await?;
     for dbtc in db_table_columns {
         let mut column_options = Vec::new();
         match dbtc.is_nullable {
@@ -253,6 +634,12 @@ async fn table_columns(
                 }
             }
         }
+        if let Some(column_settings) = dbtc.column_name.as_ref().and_then(|n| settings.get(n)) {
+            column_options.extend(column_settings.iter().map(|s| ColumnOptionDef {
+                name: None,
+                option: s.option(),
+            }));
+        }
         r.push(ColumnDef {
             name: string_to_ident(dbtc.column_name)?,
             data_type: string_to_datatype(dbtc.data_type)?,
@@ -262,9 +649,65 @@ async fn table_columns(
     Ok(r)
 }
 
+/// The storage of every column, with the compression and statistics target of the columns that
+/// have them set
+async fn column_settings(
+    c: &mut sqlx::PgConnection,
+    schema: &str,
+    table_name: &str,
+) -> Result<HashMap<String, Vec<ColumnSetting>>, MigrationError> {
+    let rows = sqlx::query!(
+        r#"SELECT
+            a.attname::text AS "column_name!",
+            a.attstorage::text AS "storage!",
+            NULLIF(a.attcompression::text, '') AS compression,
+            CASE WHEN a.attstattarget >= 0 THEN a.attstattarget::int END AS statistics
+        FROM pg_attribute a
+        JOIN pg_class c ON c.oid = a.attrelid
+        JOIN pg_namespace n ON n.oid = c.relnamespace
+        WHERE n.nspname = $1
+        AND c.relname = $2
+        AND a.attnum > 0
+        AND NOT a.attisdropped"#,
+        schema,
+        table_name
+    )
+    .fetch_all(&mut *c)
+    .await?;
+
+    let mut r = HashMap::new();
+    for row in rows {
+        let mut settings = Vec::new();
+        let storage = match row.storage.as_str() {
+            "p" => "PLAIN",
+            "e" => "EXTERNAL",
+            "m" => "MAIN",
+            _ => "EXTENDED",
+        };
+        settings.push(ColumnSetting::Storage(storage.to_string()));
+        match row.compression.as_deref() {
+            Some("p") => settings.push(ColumnSetting::Compression("pglz".to_string())),
+            Some("l") => settings.push(ColumnSetting::Compression("lz4".to_string())),
+            _ => (),
+        }
+        if let Some(target) = row.statistics {
+            settings.push(ColumnSetting::Statistics(target as u64));
+        }
+        r.insert(row.column_name, settings);
+    }
+    Ok(r)
+}
+
 pub async fn from_pool(pool: &sqlx::PgPool) -> Result<Vec<Wrapped>, MigrationError> {
+    let mut conn = pool.acquire().await?;
+    from_connection(&mut conn).await
+}
+
+/// Like `from_pool` using the connection's current schema. Objects created in an open
+/// transaction on the connection are included.
+pub async fn from_connection(c: &mut sqlx::PgConnection) -> Result<Vec<Wrapped>, MigrationError> {
     let current_schema = sqlx::query!("SELECT current_schema();")
-        .fetch_one(pool)
+        .fetch_one(&mut *c)
         .await?
         .current_schema
         .expect("Couldn't get current schema");
@@ -272,26 +715,330 @@ pub async fn from_pool(pool: &sqlx::PgPool) -> Result<Vec<Wrapped>, MigrationErr
         PGTable,
         "select table_schema, table_name, table_type as \"table_type: PGTableType\" from information_schema.tables where table_schema = current_schema()",
     )
-    .fetch_all(pool)
+    .fetch_all(&mut *c)
     .await?;
-    tables_to_wrapped(pool, db_tables, current_schema.as_str()).await
+    tables_to_wrapped(c, db_tables, current_schema.as_str()).await
 }
 pub async fn from_pool_schema(
     pool: &sqlx::PgPool,
     schema: &str,
+) -> Result<Vec<Wrapped>, MigrationError> {
+    let mut conn = pool.acquire().await?;
+    from_connection_schema(&mut conn, schema).await
+}
+
+/// Like `from_pool_schema` on a single connection
+pub async fn from_connection_schema(
+    c: &mut sqlx::PgConnection,
+    schema: &str,
 ) -> Result<Vec<Wrapped>, MigrationError> {
     let db_tables = sqlx::query_as!(
         PGTable,
-        "select table_schema, table_name, table_type as \"table_type: PGTableType\" from information_schema.tables where table_schema = $1",
-        schema
+        "select table_schema, table_name, table_type as \"table_type: PGTableType\" from information_schema.tables where table_schema = $1 and table_name <> $2",
+        schema,
+        crate::history::HISTORY_TABLE
     )
-    .fetch_all(pool)
+    .fetch_all(&mut *c)
     .await?;
-    tables_to_wrapped(pool, db_tables, schema).await
+    tables_to_wrapped(c, db_tables, schema).await
 }
 
-async fn tables_to_wrapped(
+/// Read the tables that `moved_tables` moves out of `schema` from the schemas they're moved to,
+/// named as they're moved to, so they're compared once they've been moved
+pub async fn moved_tables(
+    pool: &sqlx::PgPool,
+    schema: &str,
+    moved_tables: &[(ObjectName, ObjectName)],
+) -> Result<Vec<Wrapped>, MigrationError> {
+    let mut r = Vec::new();
+    for (_, new_name) in moved_tables {
+        let [
+            ..,
+            ObjectNamePart::Identifier(new_schema),
+            ObjectNamePart::Identifier(table),
+        ] = new_name.0.as_slice()
+        else {
+            continue;
+        };
+        let new_schema = crate::normalize::ident(new_schema).value;
+        if new_schema == schema {
+            continue;
+        }
+        let table = crate::normalize::ident(table);
+        let last_is_table = |name: &ObjectName| matches!(name.0.last(), Some(ObjectNamePart::Identifier(i)) if crate::normalize::ident(i) == table);
+        for o in from_pool_schema(pool, &new_schema).await? {
+            match o {
+                Wrapped::CreateTable(mut ct) if last_is_table(&ct.name) => {
+                    ct.name = new_name.clone();
+                    r.push(Wrapped::CreateTable(ct));
+                }
+                Wrapped::CreateIndex(ci) if last_is_table(&ci.table_name) => {
+                    r.push(Wrapped::CreateIndex(ci));
+                }
+                Wrapped::Storage {
+                    kind: RelationKind::Table,
+                    relation,
+                    storage,
+                } if last_is_table(&relation) => {
+                    r.push(Wrapped::Storage {
+                        kind: RelationKind::Table,
+                        relation: new_name.clone(),
+                        storage,
+                    });
+                }
+                _ => (),
+            }
+        }
+    }
+    Ok(r)
+}
+
+/// Create the `to` objects in a scratch schema and read them back, so they compare with the
+/// objects read from `schema` in the form Postgres stores them. Returns the current objects in
+/// `schema` and the canonical `to` objects. Everything runs in a transaction that's rolled back.
+pub async fn canonicalize(
     pool: &sqlx::PgPool,
+    schema: &str,
+    to: Vec<Wrapped>,
+) -> Result<(Vec<Wrapped>, Vec<Wrapped>), MigrationError> {
+    let mut tx = pool.begin().await?;
+    let search_path = sqlx::query_scalar!("SELECT current_setting('search_path')")
+        .fetch_one(&mut *tx)
+        .await?
+        .unwrap_or_default();
+    // Both schemas are read with themselves first in the search_path, so names are only
+    // qualified when they're in other schemas
+    let set_search_path = |first: &str| {
+        let mut path = Ident::with_quote('"', first).to_string();
+        if !search_path.is_empty() {
+            path = format!("{path}, {search_path}");
+        }
+        sqlx::AssertSqlSafe(format!("SET LOCAL search_path TO {path}"))
+    };
+
+    sqlx::query(set_search_path(schema))
+        .execute(&mut *tx)
+        .await?;
+    let from = from_connection_schema(&mut tx, schema).await?;
+
+    let scratch = format!("declare_schema_scratch_{}", uuid::Uuid::new_v4().simple());
+    let create_scratch = format!("CREATE SCHEMA {}", Ident::with_quote('"', &scratch));
+    sqlx::query(sqlx::AssertSqlSafe(create_scratch))
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(set_search_path(&scratch))
+        .execute(&mut *tx)
+        .await?;
+
+    let mut written = Vec::new();
+    let mut database_wide = Vec::new();
+    for o in to {
+        match o {
+            Wrapped::CreateExtension {
+                ref name,
+                ref schema,
+                ref version,
+            } => {
+                // Types and functions from extensions may be used by the objects
+                let create = sqlparser::ast::Statement::CreateExtension(CreateExtension {
+                    name: name.clone(),
+                    if_not_exists: true,
+                    cascade: false,
+                    schema: schema.clone(),
+                    version: version.clone(),
+                });
+                sqlx::query(sqlx::AssertSqlSafe(create.to_string()))
+                    .execute(&mut *tx)
+                    .await?;
+                database_wide.push(o);
+            }
+            Wrapped::CreateSchema { .. } | Wrapped::Publication(_) => database_wide.push(o),
+            o => written.push(unqualify_wrapped(o, schema)),
+        }
+    }
+    for step in crate::altertable::from_to(vec![], written.clone())? {
+        sqlx::query(sqlx::AssertSqlSafe(step.to_string()))
+            .execute(&mut *tx)
+            .await?;
+    }
+    let created = from_connection_schema(&mut tx, &scratch).await?;
+    tx.rollback().await?;
+
+    let mut canonical = Vec::new();
+    for o in created {
+        match o {
+            Wrapped::CreateTable(ct) => {
+                // Column types read from information_schema lose details such as lengths, keep
+                // the columns as written with the defaults and NOT NULLs Postgres stored
+                let maybe_written = written.iter().find_map(|w| match w {
+                    Wrapped::CreateTable(w)
+                        if crate::normalize::object_name(&w.name)
+                            == crate::normalize::object_name(&ct.name) =>
+                    {
+                        Some(w)
+                    }
+                    _ => None,
+                });
+                let mut table = match maybe_written {
+                    Some(w) => w.clone(),
+                    None => ct.clone(),
+                };
+                for column in table.columns.iter_mut() {
+                    let stored = ct.columns.iter().find(|c| {
+                        crate::normalize::ident(&c.name) == crate::normalize::ident(&column.name)
+                    });
+                    if let Some(stored) = stored {
+                        column.options.retain(|o| {
+                            !matches!(
+                                o.option,
+                                ColumnOption::Null
+                                    | ColumnOption::NotNull
+                                    | ColumnOption::Default(_)
+                                    | ColumnOption::PrimaryKey(_)
+                                    | ColumnOption::Unique(_)
+                                    | ColumnOption::ForeignKey(_)
+                                    | ColumnOption::Check(_)
+                            )
+                        });
+                        column.options.extend(
+                            stored
+                                .options
+                                .iter()
+                                .filter(|o| !matches!(o.option, ColumnOption::DialectSpecific(_)))
+                                .cloned(),
+                        );
+                    }
+                }
+                table.constraints = ct.constraints;
+                canonical.push(Wrapped::CreateTable(table));
+            }
+            // Indexes are read back qualified with the scratch schema
+            Wrapped::CreateIndex(mut ci) => {
+                if let Some(ObjectNamePart::Identifier(table)) = ci.table_name.0.last() {
+                    ci.table_name = ObjectName(vec![
+                        ObjectNamePart::Identifier(Ident::new(schema)),
+                        ObjectNamePart::Identifier(table.clone()),
+                    ]);
+                }
+                canonical.push(Wrapped::CreateIndex(ci));
+            }
+            o @ (Wrapped::CreateView(_)
+            | Wrapped::ExclusionConstraint { .. }
+            | Wrapped::Storage { .. }
+            | Wrapped::Statistics { .. }
+            | Wrapped::ReplicaIdentity { .. }) => canonical.push(o),
+            // Extensions, schemas and publications are database wide, so the scratch schema
+            // reads back all of them, use the ones written instead
+            Wrapped::CreateExtension { .. }
+            | Wrapped::CreateSchema { .. }
+            | Wrapped::Publication(_) => (),
+        }
+    }
+    canonical.append(&mut database_wide);
+    Ok((from, canonical))
+}
+
+/// Remove the schemas `to` uses without declaring them, the migrated `schema` and those `to`
+/// qualifies names with, so they're never dropped
+pub(crate) fn remove_schemas_in_use(from: &mut Vec<Wrapped>, schema: &str, to: &[Wrapped]) {
+    let schema_of = |name: &ObjectName| match name.0.as_slice() {
+        [ObjectNamePart::Identifier(s)] => Some(crate::normalize::ident(s).value),
+        _ => None,
+    };
+    let declared: Vec<String> = to
+        .iter()
+        .filter(|o| matches!(o, Wrapped::CreateSchema { .. }))
+        .filter_map(|o| o.name().as_ref().and_then(schema_of))
+        .collect();
+    let mut used: Vec<String> = to
+        .iter()
+        .filter_map(|o| match relation(o)?.0.as_slice() {
+            [ObjectNamePart::Identifier(s), _] => Some(crate::normalize::ident(s).value),
+            _ => None,
+        })
+        .collect();
+    used.push(schema.to_string());
+    from.retain(|o| match (o, o.name().as_ref().and_then(schema_of)) {
+        (Wrapped::CreateSchema { .. }, Some(name)) => {
+            declared.contains(&name) || !used.contains(&name)
+        }
+        _ => true,
+    });
+}
+
+/// Remove `schema` from names qualified with it, so they're created in the scratch schema
+pub(crate) fn unqualify_wrapped(o: Wrapped, schema: &str) -> Wrapped {
+    match o {
+        Wrapped::CreateTable(mut ct) => {
+            ct.name = unqualify(&ct.name, schema);
+            for c in ct.constraints.iter_mut() {
+                if let TableConstraint::ForeignKey(fk) = c {
+                    fk.foreign_table = unqualify(&fk.foreign_table, schema);
+                }
+            }
+            for column in ct.columns.iter_mut() {
+                for o in column.options.iter_mut() {
+                    if let ColumnOption::ForeignKey(fk) = &mut o.option {
+                        fk.foreign_table = unqualify(&fk.foreign_table, schema);
+                    }
+                }
+            }
+            Wrapped::CreateTable(ct)
+        }
+        Wrapped::CreateView(mut cv) => {
+            cv.name = unqualify(&cv.name, schema);
+            Wrapped::CreateView(cv)
+        }
+        Wrapped::CreateIndex(mut ci) => {
+            ci.table_name = unqualify(&ci.table_name, schema);
+            Wrapped::CreateIndex(ci)
+        }
+        Wrapped::ExclusionConstraint { table, constraint } => Wrapped::ExclusionConstraint {
+            table: unqualify(&table, schema),
+            constraint,
+        },
+        Wrapped::ReplicaIdentity { table, identity } => Wrapped::ReplicaIdentity {
+            table: unqualify(&table, schema),
+            identity,
+        },
+        Wrapped::Statistics {
+            mut statistics,
+            target,
+        } => {
+            statistics.name = unqualify(&statistics.name, schema);
+            statistics.table = unqualify(&statistics.table, schema);
+            Wrapped::Statistics { statistics, target }
+        }
+        o => o,
+    }
+}
+
+/// The table or view `o` is, or is on
+pub(crate) fn relation(o: &Wrapped) -> Option<&ObjectName> {
+    match o {
+        Wrapped::CreateTable(ct) => Some(&ct.name),
+        Wrapped::CreateView(cv) => Some(&cv.name),
+        Wrapped::CreateIndex(ci) => Some(&ci.table_name),
+        Wrapped::ExclusionConstraint { table, .. } | Wrapped::ReplicaIdentity { table, .. } => {
+            Some(table)
+        }
+        Wrapped::Storage { relation, .. } => Some(relation),
+        Wrapped::Statistics { statistics, .. } => Some(&statistics.table),
+        _ => None,
+    }
+}
+
+fn unqualify(name: &ObjectName, schema: &str) -> ObjectName {
+    match name.0.as_slice() {
+        [ObjectNamePart::Identifier(s), table] if crate::normalize::ident(s).value == schema => {
+            ObjectName(vec![table.clone()])
+        }
+        _ => name.clone(),
+    }
+}
+
+async fn tables_to_wrapped(
+    c: &mut sqlx::PgConnection,
     db_tables: Vec<PGTable>,
     schema: &str,
 ) -> Result<Vec<Wrapped>, MigrationError> {
@@ -306,9 +1053,10 @@ async fn tables_to_wrapped(
                 if let Some(table_name) = db_table.table_name {
                     let object_name = string_to_object_name(Some(table_name.clone()))?;
                     let columns =
-                        table_columns(pool, table_schema.to_string(), table_name.clone()).await?;
+                        table_columns(&mut *c, table_schema.to_string(), table_name.clone())
+                            .await?;
                     let constraints =
-                        table_constraints(pool, schema.to_string(), table_name.clone()).await?;
+                        table_constraints(&mut *c, schema.to_string(), table_name.clone()).await?;
                     let b = CreateTableBuilder::new(object_name.clone())
                         .columns(columns)
                         .constraints(constraints);
@@ -317,7 +1065,7 @@ async fn tables_to_wrapped(
                 }
             }
             Some(PGTableType::View) => {
-                let create_view = pg_view(pool, &schema, db_table.table_name).await?;
+                let create_view = pg_view(&mut *c, &schema, db_table.table_name).await?;
                 #[cfg(test)]
                 println!("create_view: {create_view}");
                 views.push(create_view);
@@ -335,14 +1083,41 @@ async fn tables_to_wrapped(
         .collect();
     let mut re = re?;
 
+    let mut exclusion_constraints = pg_exclusion_constraints(&mut *c, schema).await?;
+    re.append(&mut exclusion_constraints);
     re.append(&mut views);
-    let mut indexes = pg_indexes(&pool, schema.to_string()).await?;
+    let mut indexes = pg_indexes(&mut *c, schema.to_string()).await?;
     re.append(&mut indexes);
-    let mut extensions = pg_extensions(&pool).await?;
+    let mut storage = pg_storage(&mut *c, schema).await?;
+    re.append(&mut storage);
+    let mut statistics = pg_statistics(&mut *c, schema).await?;
+    re.append(&mut statistics);
+    let mut replica_identities = pg_replica_identities(&mut *c, schema).await?;
+    re.append(&mut replica_identities);
+    let mut publications = pg_publications(&mut *c, schema).await?;
+    re.append(&mut publications);
+    let mut extensions = pg_extensions(&mut *c, schema, &[]).await?;
     re.append(&mut extensions);
+    let mut schemas = pg_schemas(&mut *c).await?;
+    re.append(&mut schemas);
     Ok(re)
 }
 
+/// Names from the catalog, quoted when they aren't valid unquoted identifiers, such as
+/// `uuid-ossp`
+fn catalog_ident(os: Option<String>) -> Result<Ident, MigrationError> {
+    match os {
+        Some(s)
+            if !(s.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+                && s.chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')) =>
+        {
+            Ok(Ident::with_quote('"', s))
+        }
+        os => string_to_ident(os),
+    }
+}
+
 fn string_to_ident(os: Option<String>) -> Result<Ident, MigrationError> {
     if let Some(s) = os {
         let dialect = sqlparser::dialect::PostgreSqlDialect {};
@@ -444,3 +1219,18 @@ fn string_to_table_constraint(
         ));
     }
 }
+
+fn string_to_exclusion_constraint(
+    os: Option<String>,
+) -> Result<crate::ddl::ExclusionConstraint, MigrationError> {
+    if let Some(s) = os {
+        let dialect = sqlparser::dialect::PostgreSqlDialect {};
+        let parser = sqlparser::parser::Parser::new(&dialect);
+        let mut parser = parser.try_with_sql(&s)?;
+        Ok(crate::ddl::ExclusionConstraint::parse(&mut parser)?)
+    } else {
+        Err(MigrationError::SqlParseTypeError(
+            "No string value".to_string(),
+        ))
+    }
+}